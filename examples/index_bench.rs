@@ -0,0 +1,172 @@
+//! Times the spatial index backends on a synthetic coastline of about a million segments.
+//!
+//!     cargo run --example index_bench
+//!
+//! The repo ships no full-detail coastline, so wiggly closed loops of ~1.5 unit steps over
+//! 5000x3000 stand in for one. Indexes use the game's 5 unit cells.
+
+// The game is a binary crate, so its modules are compiled in here as they are in main.rs.
+// Only the index half of them gets called.
+#![allow(dead_code)]
+
+#[path = "../src/debug_tools/mod.rs"]
+mod debug_tools;
+#[path = "../src/init/mod.rs"]
+mod init;
+#[path = "../src/settings.rs"]
+mod settings;
+#[path = "../src/svg_creation/mod.rs"]
+mod svg_creation;
+use svg_creation::*;
+
+use std::f64::consts::TAU;
+use std::time::Instant;
+
+use bevy::math::DVec2;
+use geometry64::closest_point_on_segment;
+use spatial_grid::PathSegment;
+use spatial_index::{Slot, SpatialIndex, SpatialIndexKind, new_index};
+
+const CELL_SIZE: f32 = 5.0;
+const TARGET_SEGMENTS: usize = 1_000_000;
+const QUERIES: usize = 200_000;
+const RECT_SIZE: f64 = 20.0;
+const NEAREST_RADIUS: f64 = 15.0;
+const EDITS: usize = 20_000;
+
+/// xorshift, so every run sees the same coastline.
+fn next_random(seed: &mut u64) -> f64 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 7;
+    *seed ^= *seed << 17;
+    (*seed >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn synthetic_coastline(seed: &mut u64) -> Vec<(Slot, PathSegment)> {
+    let mut segments = Vec::new();
+    while segments.len() < TARGET_SEGMENTS {
+        let center = DVec2::new(
+            next_random(seed) * 4600.0 - 2300.0,
+            next_random(seed) * 2600.0 - 1300.0,
+        );
+        let radius = 20.0 + next_random(seed) * 200.0;
+        let n = (radius * TAU / 1.5) as usize;
+        let mut wobble = 0.0f64;
+        let points: Vec<DVec2> = (0..n)
+            .map(|i| {
+                wobble = (wobble + next_random(seed) * 0.2 - 0.1).clamp(-0.3, 0.3);
+                let angle = i as f64 / n as f64 * TAU;
+                center + DVec2::new(angle.cos(), angle.sin()) * radius * (1.0 + wobble)
+            })
+            .collect();
+        for i in 0..n {
+            let segment = PathSegment {
+                start: points[i],
+                end: points[(i + 1) % n],
+            };
+            segments.push((segments.len() as Slot, segment));
+        }
+    }
+    segments
+}
+
+fn millis(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+fn main() {
+    let mut seed = 99u64;
+    let segments = synthetic_coastline(&mut seed);
+    let queries: Vec<DVec2> = (0..QUERIES)
+        .map(|_| {
+            DVec2::new(
+                next_random(&mut seed) * 5000.0 - 2500.0,
+                next_random(&mut seed) * 3000.0 - 1500.0,
+            )
+        })
+        .collect();
+    let extra: Vec<PathSegment> = (0..EDITS)
+        .map(|_| {
+            let start = DVec2::new(
+                next_random(&mut seed) * 4000.0 - 2000.0,
+                next_random(&mut seed) * 2400.0 - 1200.0,
+            );
+            PathSegment {
+                start,
+                end: start + DVec2::new(1.0, 0.7),
+            }
+        })
+        .collect();
+    println!(
+        "{} segments, {} queries, {} edits",
+        segments.len(),
+        queries.len(),
+        extra.len()
+    );
+    println!(
+        "{:<10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "backend", "memory MB", "build ms", "rect ms", "nearest ms", "insert ms", "remove ms"
+    );
+
+    for kind in [
+        SpatialIndexKind::Grid,
+        SpatialIndexKind::Quadtree,
+        SpatialIndexKind::RTree,
+    ] {
+        let mut index: Box<dyn SpatialIndex> = new_index(kind, CELL_SIZE);
+
+        let start = Instant::now();
+        index.rebuild(&segments);
+        let build = millis(start);
+        let memory = index.memory_bytes() as f64 / 1.0e6;
+
+        let start = Instant::now();
+        let mut found = Vec::new();
+        let mut hits = 0;
+        for &pos in &queries {
+            found.clear();
+            index.query_rect(pos, pos + DVec2::splat(RECT_SIZE), &mut found);
+            hits += found.len();
+        }
+        let rect = millis(start);
+
+        let start = Instant::now();
+        let mut nearest_hits = 0;
+        for &pos in &queries {
+            let taken = index.nearest(pos, NEAREST_RADIUS, &mut |slot, best| {
+                let segment = &segments[slot as usize].1;
+                let distance =
+                    closest_point_on_segment(pos, segment.start, segment.end).distance(pos);
+                (distance <= NEAREST_RADIUS && distance < best).then_some(distance)
+            });
+            nearest_hits += taken.is_some() as usize;
+        }
+        let nearest = millis(start);
+
+        let first_extra = segments.len() as Slot;
+        let start = Instant::now();
+        for (i, segment) in extra.iter().enumerate() {
+            index.insert(first_extra + i as Slot, segment);
+        }
+        let insert = millis(start);
+
+        let start = Instant::now();
+        for (i, segment) in extra.iter().enumerate() {
+            index.remove(first_extra + i as Slot, segment);
+        }
+        let remove = millis(start);
+
+        println!(
+            "{:<10} {:>10.1} {:>10.0} {:>10.0} {:>10.0} {:>10.0} {:>10.0}   ({} rect hits, {} nearest hits)",
+            format!("{:?}", kind),
+            memory,
+            build,
+            rect,
+            nearest,
+            insert,
+            remove,
+            hits,
+            nearest_hits
+        );
+    }
+}
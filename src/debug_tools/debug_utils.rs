@@ -1,6 +1,11 @@
 use bevy::prelude::*;
 use bevy_prototype_lyon::prelude::*;
-use crate::svg_creation::spatial_grid::SpatialGrid;
+use crate::settings::world_mouse_pos;
+use crate::svg_creation::{
+    draw_logic::SNAP_RADIUS,
+    geometry64::{to_render, to_world},
+    spatial_grid::SpatialGrid,
+};
 
 #[derive(Resource, Default)]
 pub struct GridDebugOverlayState {
@@ -40,6 +45,8 @@ pub fn draw_spatial_grid_overlay(
     spatial_grid: Res<SpatialGrid>,
     query: Query<Entity, With<GridDebugDraw>>,
     camera_query: Query<(&Camera, &GlobalTransform)>,
+    mut windows: Query<&mut Window>,
+    cameras: Query<(&Camera, &Transform)>,
 ) {
     for entity in query.iter() {
         commands.entity(entity).despawn_recursive();
@@ -59,29 +66,12 @@ pub fn draw_spatial_grid_overlay(
     let cell_border = Srgba::rgb(0.0, 0.7, 0.2);
     let mut cell_path_builder = PathBuilder::new();
 
-    let cell_size = spatial_grid.cell_size;
-    let min = spatial_grid.bounds.min;
-    let cols = spatial_grid.cols;
-    let rows = spatial_grid.rows;
-
-    for col in 0..cols {
-        let x = min.x + col as f32 * cell_size;
-        for row in 0..rows {
-            let y = min.y + row as f32 * cell_size;
-            let rect_min = Vec2::new(x, y);
-            let rect_max = rect_min + Vec2::new(cell_size, cell_size);
-
-            if rect_max.x < view_min.x || rect_min.x > view_max.x ||
-               rect_max.y < view_min.y || rect_min.y > view_max.y {
-                continue;
-            }
-
-            cell_path_builder.move_to(rect_min);
-            cell_path_builder.line_to(Vec2::new(rect_max.x, rect_min.y));
-            cell_path_builder.line_to(rect_max);
-            cell_path_builder.line_to(Vec2::new(rect_min.x, rect_max.y));
-            cell_path_builder.close();
-        }
+    for (rect_min, rect_max) in spatial_grid.index().debug_rects(view_min, view_max) {
+        cell_path_builder.move_to(rect_min);
+        cell_path_builder.line_to(Vec2::new(rect_max.x, rect_min.y));
+        cell_path_builder.line_to(rect_max);
+        cell_path_builder.line_to(Vec2::new(rect_min.x, rect_max.y));
+        cell_path_builder.close();
     }
 
     let cell_path = cell_path_builder.build();
@@ -99,16 +89,13 @@ pub fn draw_spatial_grid_overlay(
     let seg_color = Srgba::rgb(0.8, 0.0, 0.8); // Purple
     let mut seg_path_builder = PathBuilder::new();
 
-    for seg in &spatial_grid.segments {
-        if seg.start == seg.end { continue; }
-        if (seg.start.x < view_min.x && seg.end.x < view_min.x) ||
-           (seg.start.x > view_max.x && seg.end.x > view_max.x) ||
-           (seg.start.y < view_min.y && seg.end.y < view_min.y) ||
-           (seg.start.y > view_max.y && seg.end.y > view_max.y) {
+    for seg_id in spatial_grid.query_rect(view_min, view_max) {
+        let Some(seg) = spatial_grid.get(seg_id) else {
             continue;
-        }
-        seg_path_builder.move_to(seg.start);
-        seg_path_builder.line_to(seg.end);
+        };
+        if seg.start == seg.end { continue; }
+        seg_path_builder.move_to(to_render(seg.start));
+        seg_path_builder.line_to(to_render(seg.end));
     }
 
     let seg_path = seg_path_builder.build();
@@ -121,4 +108,27 @@ pub fn draw_spatial_grid_overlay(
         Stroke::new(seg_color, 1.7),
         GridDebugDraw,
     ));
+
+    // What snapping sees: the segments within reach of the cursor, closest drawn thickest
+    let mouse_pos = world_mouse_pos(&mut windows.single_mut(), &cameras);
+    let snap_color = Srgba::rgb(1.0, 0.5, 0.0);
+    let snap_candidates = spatial_grid.query_radius(to_world(mouse_pos), SNAP_RADIUS as f64);
+    for (rank, seg_id) in snap_candidates.into_iter().enumerate() {
+        let Some(seg) = spatial_grid.get(seg_id) else {
+            continue;
+        };
+        let mut snap_path_builder = PathBuilder::new();
+        snap_path_builder.move_to(to_render(seg.start));
+        snap_path_builder.line_to(to_render(seg.end));
+        let snap_path = snap_path_builder.build();
+        commands.spawn((
+            ShapeBundle {
+                path: GeometryBuilder::build_as(&snap_path),
+                transform: Transform::from_xyz(0.0, 0.0, 13.0),
+                ..default()
+            },
+            Stroke::new(snap_color, if rank == 0 { 3.0 } else { 2.0 }),
+            GridDebugDraw,
+        ));
+    }
 }
@@ -8,7 +8,7 @@ pub struct FpsPlugin;
 
 impl Plugin for FpsPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(FrameTimeDiagnosticsPlugin::default())
+        app.add_plugins(FrameTimeDiagnosticsPlugin)
             .add_systems(Startup, spawn_text)
             .add_systems(Update, (update, toggle_fps_overlay))
             .init_resource::<FpsCounter>()
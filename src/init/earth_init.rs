@@ -1,12 +1,48 @@
+use bevy::math::DVec2;
 use bevy::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use regex::Regex;
 
+use crate::settings::SettingsConfig;
 use crate::{
+    clip_mask::ClipMask,
+    index_cache::{load_index_cache, load_topology_cache, save_index_cache, save_topology_cache, CacheKey},
+    intersections::node_segments,
+    math_utils::ramer_douglas_peucker_many,
+    simplify::{SimplifyPath, simplify_preserving_topology},
     spatial_grid::{PathSegment, SpatialGrid},
-    svg_utils::draw_svg
+    svg_utils::draw_svg,
+    topology::Topology,
 };
 
+/// One SVG of the base map. The layer without `mask` holds the borders, which are drawn and
+/// imported into the grid; `mask` layers are optional and their polygons become the clip
+/// mask strokes are kept inside, e.g. land.
+struct MapLayer {
+    file: &'static str,
+    mask: bool,
+}
+
+const MAP_LAYERS: &[MapLayer] = &[
+    MapLayer { file: "assets/earth/earthBorder.svg", mask: false },
+    MapLayer { file: "assets/earth/land.svg", mask: true },
+];
+/// Mask rings are simplified with this tolerance before they are used for clipping.
+const MASK_SIMPLIFY_TOLERANCE: f32 = 0.05;
+/// Parsed borders and their built index, reused while the map and import settings stay the same.
+const INDEX_CACHE_FILE: &str = "cache/world_index.bin";
+/// The base map's topology, built over the cached segments and stored under the same key.
+const TOPOLOGY_CACHE_FILE: &str = "cache/world_topology.bin";
+const DEFAULT_VIEWBOX: (f32, f32, f32, f32) = (0.0, 0.0, 5000.0, 3000.0);
+/// Bump whenever parsing, simplifying or noding imported borders changes what comes out,
+/// so caches made by the old importer are thrown away.
+const IMPORT_VERSION: u32 = 2;
+/// Corners of imported borders cutting off less than this area are simplified away.
+const IMPORT_SIMPLIFY_AREA: f64 = 0.05;
+/// Imported vertices, crossings included, are rounded to this grid so borders that meet
+/// share exact vertices.
+const IMPORT_SNAP_ROUNDING: f64 = 1.0e-6;
+
 pub struct WorldInitPlugin;
 
 impl Plugin for WorldInitPlugin {
@@ -31,84 +67,190 @@ pub fn world_setup(
     commands: Commands,
     asset_server: Res<AssetServer>,
     mut spatial_grid: ResMut<SpatialGrid>,
+    mut clip_mask: ResMut<ClipMask>,
+    mut topology: ResMut<Topology>,
+    settings: Res<SettingsConfig>,
 ) {
-    let filename = PathBuf::from("assets/earth/earthBorder.svg");
+    // Set while the grid is still empty, so nothing is indexed twice
+    if spatial_grid.index_kind() != settings.spatial_index {
+        spatial_grid.set_index_kind(settings.spatial_index);
+    }
+    for layer in MAP_LAYERS.iter().filter(|layer| layer.mask) {
+        load_clip_mask(&mut clip_mask, layer.file, spatial_grid.cell_size);
+    }
+
+    let Some(border_layer) = MAP_LAYERS.iter().find(|layer| !layer.mask) else {
+        return;
+    };
+    let filename = PathBuf::from(border_layer.file);
     let svg_position = Vec2::new(0.0, 0.0);
     let _entity = draw_svg(commands, asset_server, filename.clone(), svg_position);
 
-    if let Ok(svg_data) = std::fs::read_to_string(&filename) {
-        let viewbox = parse_viewbox(&svg_data).unwrap_or((0.0, 0.0, 5000.0, 3000.0));
-        let (_min_x, _min_y, vb_width, vb_height) = viewbox;
-
-        let mut segments = Vec::new();
+    let Ok(svg_data) = std::fs::read_to_string(&filename) else {
+        println!("Failed to load original.svg for grid integration.");
+        return;
+    };
 
-        let re_single = Regex::new(r"d\s*=\s*'([^']*)'").unwrap();
-        let re_double = Regex::new(r#"d\s*=\s*"([^"]*)""#).unwrap();
+    let mut key = CacheKey::new();
+    key.add(&IMPORT_VERSION.to_le_bytes())
+        .add(svg_data.as_bytes())
+        .add(&spatial_grid.cell_size.to_le_bytes())
+        .add(&IMPORT_SIMPLIFY_AREA.to_le_bytes())
+        .add(&IMPORT_SNAP_ROUNDING.to_le_bytes())
+        .add(&MASK_SIMPLIFY_TOLERANCE.to_le_bytes())
+        .add(format!("{:?}", spatial_grid.index_kind()).as_bytes());
+    // Mask layers count too, so any change to the files or settings the map loads with
+    // starts a fresh cache
+    for layer in MAP_LAYERS.iter().filter(|layer| layer.mask) {
+        key.add(layer.file.as_bytes())
+            .add(&std::fs::read(layer.file).unwrap_or_default());
+    }
+    let key = key.value();
+    let cache_path = Path::new(INDEX_CACHE_FILE);
 
-        for cap in re_single.captures_iter(&svg_data) {
-            let d_part = &cap[1];
-            parse_path_segments(d_part, vb_width, vb_height, &mut segments);
-        }
-        for cap in re_double.captures_iter(&svg_data) {
-            let d_part = &cap[1];
-            parse_path_segments(d_part, vb_width, vb_height, &mut segments);
+    if let Some((segments, index)) = load_index_cache(cache_path, key, spatial_grid.cell_size) {
+        println!("Loaded {} segments and the {:?} index from {:?}", segments.len(), index.kind(), cache_path);
+        spatial_grid.load_prebuilt(segments, index);
+        match load_topology_cache(Path::new(TOPOLOGY_CACHE_FILE), key) {
+            Some(cached) => *topology = cached,
+            None => build_topology(&mut topology, &spatial_grid, key),
         }
+        return;
+    }
 
-        for i in 0..10 {
-            if let Some(seg) = segments.get(i) {
-                println!("Segment {}: start {:?}, end {:?}", i, seg.start, seg.end);
-            }
+    let viewbox = parse_viewbox(&svg_data).unwrap_or(DEFAULT_VIEWBOX);
+    let (_min_x, _min_y, vb_width, vb_height) = viewbox;
+
+    let mut lines = Vec::new();
+
+    let re_single = Regex::new(r"d\s*=\s*'([^']*)'").unwrap();
+    let re_double = Regex::new(r#"d\s*=\s*"([^"]*)""#).unwrap();
+
+    for cap in re_single.captures_iter(&svg_data) {
+        let d_part = &cap[1];
+        lines.extend(parse_path_lines(d_part, vb_width, vb_height));
+    }
+    for cap in re_double.captures_iter(&svg_data) {
+        let d_part = &cap[1];
+        lines.extend(parse_path_lines(d_part, vb_width, vb_height));
+    }
+    let segments = node_segments(
+        &simplify_borders(&lines, spatial_grid.cell_size),
+        IMPORT_SNAP_ROUNDING,
+        spatial_grid.cell_size,
+    );
+
+    for i in 0..10 {
+        if let Some(seg) = segments.get(i) {
+            println!("Segment {}: start {:?}, end {:?}", i, seg.start, seg.end);
         }
-        spatial_grid.segments.extend(segments);
-        spatial_grid.rebuild_grid();
-
-        println!(
-            "Grid: min {:?} max {:?} cols {} rows {} segments {}",
-            spatial_grid.bounds.min,
-            spatial_grid.bounds.max,
-            spatial_grid.cols,
-            spatial_grid.rows,
-            spatial_grid.segments.len()
-        );
-        println!("Loaded original.svg with {} segments", spatial_grid.segments.len());
-    } else {
-        println!("Failed to load original.svg for grid integration.");
     }
+    spatial_grid.load_segments_then(segments, move |segments, index| {
+        save_index_cache(Path::new(INDEX_CACHE_FILE), key, segments, index);
+    });
+    build_topology(&mut topology, &spatial_grid, key);
+    println!(
+        "Loaded original.svg with {} segments, building the {:?} index in the background",
+        spatial_grid.len(),
+        spatial_grid.index_kind()
+    );
 }
 
-fn parse_path_segments(
-    d_part: &str,
-    vb_width: f32,
-    vb_height: f32,
-    segments: &mut Vec<PathSegment>,
-) {
-    let coords: Vec<&str> = d_part
-        .split(|c| c == 'M' || c == 'L')
-        .filter(|s| !s.trim().is_empty())
+/// Builds the topology of everything just loaded on the task pool and caches it.
+fn build_topology(topology: &mut Topology, spatial_grid: &SpatialGrid, key: u64) {
+    topology.build_in_background_then(spatial_grid.snapshot(), move |topology| {
+        save_topology_cache(Path::new(TOPOLOGY_CACHE_FILE), key, topology);
+    });
+}
+
+fn load_clip_mask(clip_mask: &mut ClipMask, filename: &str, cell_size: f32) {
+    let Ok(svg_data) = std::fs::read_to_string(filename) else {
+        return;
+    };
+    let (_min_x, _min_y, vb_width, vb_height) = parse_viewbox(&svg_data).unwrap_or(DEFAULT_VIEWBOX);
+    let re = Regex::new(r#"d\s*=\s*["']([^"']*)["']"#).unwrap();
+    let mut rings = Vec::new();
+    for cap in re.captures_iter(&svg_data) {
+        rings.extend(parse_path_rings(&cap[1], vb_width, vb_height));
+    }
+    let rings = ramer_douglas_peucker_many(&rings, MASK_SIMPLIFY_TOLERANCE, true)
+        .into_iter()
+        .filter(|ring| ring.len() >= 3)
         .collect();
+    clip_mask.add_layer(filename, rings, cell_size);
+}
 
-    let mut id = segments.len();
-    let mut last_point = None;
-
-    for part in coords {
-        let nums: Vec<f32> = part
-            .split_whitespace()
-            .filter_map(|n| n.parse::<f32>().ok())
-            .collect();
-        if nums.len() == 2 {
-            let current = Vec2::new(
-                nums[0] - vb_width / 2.0,
-                vb_height / 2.0 - nums[1]
-            );
-            if let Some(prev) = last_point {
-                segments.push(PathSegment {
-                    start: prev,
-                    end: current,
-                    id,
-                });
-                id += 1;
+/// Each `M` starts a new ring; `L` and `Z` are the only other commands expected.
+fn parse_path_rings(d_part: &str, vb_width: f32, vb_height: f32) -> Vec<Vec<Vec2>> {
+    d_part
+        .split('M')
+        .filter_map(|sub_path| {
+            let ring: Vec<Vec2> = sub_path
+                .split(['L', 'Z', 'z'])
+                .filter_map(|part| {
+                    let nums: Vec<f32> = part
+                        .split([' ', ','])
+                        .filter_map(|n| n.trim().parse::<f32>().ok())
+                        .collect();
+                    (nums.len() == 2).then(|| Vec2::new(
+                        nums[0] - vb_width / 2.0,
+                        vb_height / 2.0 - nums[1]
+                    ))
+                })
+                .collect();
+            (ring.len() >= 3).then_some(ring)
+        })
+        .collect()
+}
+
+/// Each `M` starts a new line and a `Z` closes it into a ring; `L` is the only other
+/// command expected.
+fn parse_path_lines(d_part: &str, vb_width: f32, vb_height: f32) -> Vec<SimplifyPath> {
+    d_part
+        .split('M')
+        .filter_map(|sub_path| {
+            let closed = sub_path.contains(['Z', 'z']);
+            let mut points: Vec<DVec2> = sub_path
+                .split(['L', 'Z', 'z'])
+                .filter_map(|part| {
+                    let nums: Vec<f64> = part
+                        .split_whitespace()
+                        .filter_map(|n| n.parse::<f64>().ok())
+                        .collect();
+                    (nums.len() == 2).then(|| DVec2::new(
+                        nums[0] - vb_width as f64 / 2.0,
+                        vb_height as f64 / 2.0 - nums[1]
+                    ))
+                })
+                .collect();
+            // A ring that repeats its start before the Z already has its closing edge
+            if closed && points.len() > 1 && points.first() == points.last() {
+                points.pop();
             }
-            last_point = Some(current);
+            let closed = closed && points.len() >= 3;
+            (points.len() >= 2).then_some(SimplifyPath { points, closed })
+        })
+        .collect()
+}
+
+/// Simplifies all imported borders together, so a border two countries share comes out the
+/// same for both and no shortcut crosses another border.
+fn simplify_borders(lines: &[SimplifyPath], cell_size: f32) -> Vec<PathSegment> {
+    let mut raw = SpatialGrid::new(cell_size);
+    raw.load_segments(line_segments(lines));
+    raw.wait_for_rebuild();
+    line_segments(&simplify_preserving_topology(lines, IMPORT_SIMPLIFY_AREA, &raw))
+}
+
+fn line_segments(lines: &[SimplifyPath]) -> Vec<PathSegment> {
+    let mut segments = Vec::new();
+    for line in lines {
+        for pair in line.points.windows(2) {
+            segments.push(PathSegment { start: pair[0], end: pair[1] });
+        }
+        if line.closed && let (Some(&last), Some(&first)) = (line.points.last(), line.points.first()) {
+            segments.push(PathSegment { start: last, end: first });
         }
     }
+    segments
 }
@@ -10,5 +10,5 @@ impl Plugin for ViewInitPlugin {
 }
 
 fn camera_setup(mut commands: Commands) {
-    commands.spawn(Camera2d::default());
+    commands.spawn(Camera2d);
 }
@@ -3,6 +3,8 @@ use bevy::prelude::*;
 use crate::svg_creation::draw::drawing::DrawPlugin;
 use crate::svg_creation::merge_svg::MergeSvgPlugin;
 use crate::svg_creation::spatial_grid::SpatialGridPlugin;
+use crate::svg_creation::topology::TopologyPlugin;
+use crate::svg_creation::region::RegionPlugin;
 use crate::debug_tools::fps_counter::FpsPlugin;
 use crate::debug_tools::debug_utils::DebugPlugin;
 use crate::init::earth_init::WorldInitPlugin;
@@ -21,6 +23,8 @@ impl Plugin for GamePlugins {
             .add_plugins(MergeSvgPlugin)
             .add_plugins(WorldInitPlugin)
             .add_plugins(ViewInitPlugin)
-            .add_plugins(SpatialGridPlugin);
+            .add_plugins(SpatialGridPlugin)
+            .add_plugins(TopologyPlugin)
+            .add_plugins(RegionPlugin);
     }
 }
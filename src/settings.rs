@@ -1,20 +1,31 @@
 use bevy::prelude::*;
 use bevy::input::mouse::MouseWheel;
 
-use crate::spatial_grid::SnapState;
+use crate::spatial_grid::{SnapState, SpatialGrid};
 use crate::draw_state::DrawingInfo;
+use crate::curve_fit::SmoothingConfig;
+use crate::self_intersection::SelfIntersectionConfig;
+use crate::clip_mask::ClipMask;
+use crate::spatial_index::SpatialIndexKind;
+
+/// Optional `key = value` overrides for `SettingsConfig`, read once at startup.
+const SETTINGS_FILE: &str = "settings.cfg";
 
 pub struct SettingsPlugin;
 
 impl Plugin for SettingsPlugin {
     fn build(&self, app: &mut App) {
         app
-            .add_systems(Startup, setup_settings_config)
+            .insert_resource(SettingsConfig::load())
             .add_systems(Update, (
                 camera_movement_system,
                 camera_zoom_system,
                 snap_toggle_system,
                 drawing_toggle_system,
+                smoothing_mode_toggle_system,
+                self_intersection_repair_toggle_system,
+                clip_mode_toggle_system,
+                spatial_index_toggle_system,
             ));
     }
 }
@@ -23,13 +34,41 @@ impl Plugin for SettingsPlugin {
 pub struct SettingsConfig {
     pub translation_speed: f32,
     pub zoom_speed: f32,
+    /// Backend the borders are indexed with from the start. I switches it while playing.
+    pub spatial_index: SpatialIndexKind,
 }
 
-fn setup_settings_config(mut commands: Commands) {
-    commands.insert_resource(SettingsConfig {
-        translation_speed: 250.0,
-        zoom_speed:0.1,
-    });
+impl SettingsConfig {
+    fn load() -> Self {
+        let mut config = SettingsConfig {
+            translation_speed: 250.0,
+            zoom_speed: 0.1,
+            spatial_index: SpatialIndexKind::default(),
+        };
+        let Ok(text) = std::fs::read_to_string(SETTINGS_FILE) else {
+            return config;
+        };
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line.split_once('=').and_then(|(key, value)| {
+                let value = value.trim();
+                match key.trim() {
+                    "translation_speed" => config.translation_speed = value.parse().ok()?,
+                    "zoom_speed" => config.zoom_speed = value.parse().ok()?,
+                    "spatial_index" => config.spatial_index = SpatialIndexKind::from_name(value)?,
+                    _ => return None,
+                }
+                Some(())
+            });
+            if parsed.is_none() {
+                println!("{}: ignoring '{}'", SETTINGS_FILE, line);
+            }
+        }
+        println!("Loaded {}, indexing borders with the {:?} index", SETTINGS_FILE, config.spatial_index);
+        config
+    }
 }
 
 fn camera_movement_system(
@@ -85,6 +124,47 @@ pub fn drawing_toggle_system(
     }
 }
 
+pub fn smoothing_mode_toggle_system(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut smoothing: ResMut<SmoothingConfig>,
+) {
+    if keys.just_pressed(KeyCode::KeyM) {
+        smoothing.mode = smoothing.mode.next();
+        println!("Smoothing mode is now {:?}", smoothing.mode);
+    }
+}
+
+pub fn self_intersection_repair_toggle_system(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut config: ResMut<SelfIntersectionConfig>,
+) {
+    if keys.just_pressed(KeyCode::KeyL) {
+        config.repair = config.repair.next();
+        println!("Self-intersecting strokes are now handled with {:?}", config.repair);
+    }
+}
+
+pub fn clip_mode_toggle_system(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut clip_mask: ResMut<ClipMask>,
+) {
+    if keys.just_pressed(KeyCode::KeyC) {
+        clip_mask.mode = clip_mask.mode.next();
+        println!("Land clipping is now {:?}", clip_mask.mode);
+    }
+}
+
+pub fn spatial_index_toggle_system(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut spatial_grid: ResMut<SpatialGrid>,
+) {
+    if keys.just_pressed(KeyCode::KeyI) {
+        let kind = spatial_grid.index_kind().next();
+        spatial_grid.rebuild_in_background(kind);
+        println!("Border lookups switching to the {:?} index", kind);
+    }
+}
+
 fn camera_zoom_system(
     mut query: Query<(&Camera, &mut Transform)>,
     mut scroll_evr: EventReader<MouseWheel>,
@@ -113,7 +193,7 @@ pub fn world_mouse_pos(
 ) -> Vec2 {
     let (_camera, camera_transform) = cameras.single();
     let pos = window.cursor_position().unwrap_or_default();
-    let size = Vec2::new(window.width() as f32, window.height() as f32);
+    let size = Vec2::new(window.width(), window.height());
     let adjusted_point = Vec2::new(pos.x, size.y - pos.y) - size / 2.0;
     let world_pos = camera_transform.compute_matrix() * adjusted_point.extend(0.0).extend(1.0);
     Vec2::new(world_pos.x, world_pos.y)
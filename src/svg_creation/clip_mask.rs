@@ -0,0 +1,237 @@
+use bevy::math::DVec2;
+use bevy::prelude::*;
+
+use crate::geometry64::{to_render, to_world};
+use crate::spatial_grid::{PathSegment, SpatialGrid};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ClipMode {
+    /// Strokes are left as drawn.
+    Off,
+    /// Only the part of the stroke over the mask is kept.
+    #[default]
+    Clip,
+    /// Clip, then carry loose ends on land on to the coastline.
+    ClipAndExtend,
+}
+
+impl ClipMode {
+    pub fn next(self) -> Self {
+        match self {
+            ClipMode::Off => ClipMode::Clip,
+            ClipMode::Clip => ClipMode::ClipAndExtend,
+            ClipMode::ClipAndExtend => ClipMode::Off,
+        }
+    }
+}
+
+/// A layer whose polygons mark where borders may be drawn, e.g. land from the base map.
+/// Rings are combined even-odd, so lakes and islands inside islands just work. Their edges
+/// are kept in a grid of their own, so lookups only visit the coast near the stroke.
+pub struct MaskLayer {
+    #[allow(dead_code)]
+    pub name: String,
+    pub rings: Vec<Vec<Vec2>>,
+    edges: SpatialGrid,
+    max_x: f32,
+}
+
+impl MaskLayer {
+    pub fn new(name: &str, rings: Vec<Vec<Vec2>>, cell_size: f32) -> Self {
+        let mut edges = SpatialGrid::new(cell_size);
+        edges.load_segments(rings.iter().flat_map(|ring| {
+            (0..ring.len()).map(move |i| PathSegment {
+                start: to_world(ring[i]),
+                end: to_world(ring[(i + 1) % ring.len()]),
+            })
+        }));
+        edges.wait_for_rebuild();
+        let max_x = rings
+            .iter()
+            .flatten()
+            .fold(f32::NEG_INFINITY, |max, p| max.max(p.x));
+        Self {
+            name: name.to_string(),
+            rings,
+            edges,
+            max_x,
+        }
+    }
+
+    /// Even-odd test along a ray to +x, counting crossings the same half-open way as
+    /// `point_in_polygon`.
+    fn contains(&self, point: Vec2) -> bool {
+        if point.x > self.max_x {
+            return false;
+        }
+        let p = to_world(point);
+        let pad = Vec2::new(0.0, RAY_PAD);
+        self.edges
+            .query_rect(point - pad, Vec2::new(self.max_x, point.y) + pad)
+            .into_iter()
+            .filter_map(|id| self.edges.get(id))
+            .filter(|seg| {
+                let (a, b) = (seg.start, seg.end);
+                (a.y > p.y) != (b.y > p.y) && p.x < (b.x - a.x) * (p.y - a.y) / (b.y - a.y) + a.x
+            })
+            .count()
+            % 2
+            == 1
+    }
+}
+
+/// Height of the box the containment ray looks for edges in, so edges it crosses are never
+/// lost to rounding in the box test.
+const RAY_PAD: f32 = 1.0e-3;
+
+#[derive(Resource, Default)]
+pub struct ClipMask {
+    pub layers: Vec<MaskLayer>,
+    pub mode: ClipMode,
+}
+
+/// The piece of a stroke kept after clipping. The flags say which ends were cut at the mask
+/// edge rather than being where the player put them.
+#[derive(Clone, Debug)]
+pub struct ClippedStroke {
+    pub points: Vec<Vec2>,
+    pub start_clipped: bool,
+    pub end_clipped: bool,
+    pub dropped_pieces: usize,
+}
+
+impl ClipMask {
+    pub fn add_layer(&mut self, name: &str, rings: Vec<Vec<Vec2>>, cell_size: f32) {
+        println!("Clip mask layer {} loaded with {} rings", name, rings.len());
+        self.layers.push(MaskLayer::new(name, rings, cell_size));
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.mode != ClipMode::Off && self.layers.iter().any(|layer| !layer.rings.is_empty())
+    }
+
+    pub fn contains(&self, point: Vec2) -> bool {
+        self.layers.iter().any(|layer| layer.contains(point))
+    }
+
+    /// Every mask edge `a`-`b` crosses, with the crossing point, in order going from `a`.
+    fn crossings(&self, a: Vec2, b: Vec2) -> Vec<Vec2> {
+        let (a, b) = (to_world(a), to_world(b));
+        let mut hits: Vec<DVec2> = self
+            .layers
+            .iter()
+            .flat_map(|layer| layer.edges.segments_crossing(a, b))
+            .map(|(_, point)| point)
+            .collect();
+        hits.sort_by(|x, y| x.distance_squared(a).total_cmp(&y.distance_squared(a)));
+        hits.into_iter().map(to_render).collect()
+    }
+
+    /// Cuts the stroke at every crossing of a mask edge and keeps the longest piece over the
+    /// mask. None if no part of it is over the mask.
+    pub fn clip_stroke(&self, stroke: &[Vec2]) -> Option<ClippedStroke> {
+        if stroke.len() < 2 {
+            return None;
+        }
+        let mut pieces: Vec<Vec<Vec2>> = Vec::new();
+        let mut current: Vec<Vec2> = Vec::new();
+        for w in stroke.windows(2) {
+            let (a, b) = (w[0], w[1]);
+            let mut cuts = self.crossings(a, b);
+            cuts.insert(0, a);
+            cuts.push(b);
+
+            // Classify each stretch between cuts by its midpoint, which is never on an edge
+            for span in cuts.windows(2) {
+                let (p, q) = (span[0], span[1]);
+                if p == q {
+                    continue;
+                }
+                if self.contains((p + q) / 2.0) {
+                    if current.is_empty() {
+                        current.push(p);
+                    }
+                    current.push(q);
+                } else if !current.is_empty() {
+                    pieces.push(std::mem::take(&mut current));
+                }
+            }
+        }
+        if !current.is_empty() {
+            pieces.push(current);
+        }
+
+        let piece_count = pieces.len();
+        let points = pieces
+            .into_iter()
+            .max_by(|a, b| path_length(a).total_cmp(&path_length(b)))?;
+        Some(ClippedStroke {
+            start_clipped: points[0] != stroke[0],
+            end_clipped: points[points.len() - 1] != stroke[stroke.len() - 1],
+            points,
+            dropped_pieces: piece_count - 1,
+        })
+    }
+
+    /// Nearest mask edge hit going from `from` along `direction`, within `max_distance`.
+    pub fn extend_to_coast(&self, from: Vec2, direction: Vec2, max_distance: f32) -> Option<Vec2> {
+        let direction = direction.normalize_or_zero();
+        if direction == Vec2::ZERO {
+            return None;
+        }
+        let reach = from + direction * max_distance;
+        self.crossings(from, reach)
+            .into_iter()
+            .find(|hit| *hit != from)
+    }
+}
+
+fn path_length(path: &[Vec2]) -> f32 {
+    path.windows(2).map(|w| w[0].distance(w[1])).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math_utils::{point_in_polygon, segment_intersection_point};
+
+    #[test]
+    fn indexed_lookups_match_brute_force() {
+        // A star-shaped island with a lake, so rays leave through varying edges
+        let island: Vec<Vec2> = (0..40)
+            .map(|i| {
+                let angle = i as f32 / 40.0 * std::f32::consts::TAU;
+                Vec2::from_angle(angle) * if i % 2 == 0 { 100.0 } else { 60.0 }
+            })
+            .collect();
+        let lake: Vec<Vec2> = (0..12)
+            .map(|i| {
+                Vec2::new(20.0, 5.0)
+                    + Vec2::from_angle(i as f32 / 12.0 * std::f32::consts::TAU) * 15.0
+            })
+            .collect();
+        let mut mask = ClipMask::default();
+        mask.add_layer("test", vec![island.clone(), lake.clone()], 5.0);
+
+        for i in 0..200 {
+            let from = Vec2::new((i * 37 % 160) as f32 - 80.0, (i * 53 % 160) as f32 - 80.0);
+            let expected = point_in_polygon(from, &island) != point_in_polygon(from, &lake);
+            assert_eq!(mask.contains(from), expected, "{from}");
+
+            let direction = Vec2::from_angle(i as f32 * 0.7);
+            let reach = from + direction * 150.0;
+            let brute = island
+                .iter()
+                .zip(island.iter().cycle().skip(1))
+                .chain(lake.iter().zip(lake.iter().cycle().skip(1)))
+                .filter_map(|(&p, &q)| segment_intersection_point(from, reach, p, q))
+                .filter(|hit| *hit != from)
+                .min_by(|x, y| x.distance(from).total_cmp(&y.distance(from)));
+            let coast = mask.extend_to_coast(from, direction, 150.0);
+            match (coast, brute) {
+                (Some(coast), Some(brute)) => assert!(coast.distance(brute) < 1.0e-3),
+                (coast, brute) => assert_eq!(coast, brute),
+            }
+        }
+    }
+}
@@ -0,0 +1,512 @@
+use bevy::prelude::*;
+
+use crate::math_utils::{closest_point_on_segment, ramer_douglas_peucker_mask, smooth_lines};
+
+const CHAIKIN_ITERATIONS: usize = 4;
+const NEWTON_ITERATIONS: usize = 4;
+const FLATTEN_MAX_DEPTH: u32 = 16;
+/// Flattening tolerance used to measure a span, as a fraction of `max_error`.
+const DEVIATION_SAMPLING: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SmoothingMode {
+    #[default]
+    MovingAverage,
+    Chaikin,
+    CatmullRom,
+    BezierFit,
+}
+
+impl SmoothingMode {
+    pub fn next(self) -> Self {
+        match self {
+            SmoothingMode::MovingAverage => SmoothingMode::Chaikin,
+            SmoothingMode::Chaikin => SmoothingMode::CatmullRom,
+            SmoothingMode::CatmullRom => SmoothingMode::BezierFit,
+            SmoothingMode::BezierFit => SmoothingMode::MovingAverage,
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct SmoothingConfig {
+    pub mode: SmoothingMode,
+    /// Max distance the smoothed stroke may stray from the drawn points.
+    pub max_error: f32,
+    /// Max distance between a curve and the polyline used for intersections and snapping.
+    pub flatten_tolerance: f32,
+}
+
+impl Default for SmoothingConfig {
+    fn default() -> Self {
+        Self {
+            mode: SmoothingMode::MovingAverage,
+            max_error: 1.5,
+            flatten_tolerance: 0.2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CubicBezier {
+    pub p0: Vec2,
+    pub p1: Vec2,
+    pub p2: Vec2,
+    pub p3: Vec2,
+}
+
+impl CubicBezier {
+    /// A straight segment as a curve, with the handles on the line.
+    pub fn line(a: Vec2, b: Vec2) -> Self {
+        Self {
+            p0: a,
+            p1: a.lerp(b, 1.0 / 3.0),
+            p2: a.lerp(b, 2.0 / 3.0),
+            p3: b,
+        }
+    }
+
+    pub fn eval(&self, t: f32) -> Vec2 {
+        let mt = 1.0 - t;
+        self.p0 * (mt * mt * mt)
+            + self.p1 * (3.0 * mt * mt * t)
+            + self.p2 * (3.0 * mt * t * t)
+            + self.p3 * (t * t * t)
+    }
+
+    fn derivative(&self, t: f32) -> Vec2 {
+        let mt = 1.0 - t;
+        (self.p1 - self.p0) * (3.0 * mt * mt)
+            + (self.p2 - self.p1) * (6.0 * mt * t)
+            + (self.p3 - self.p2) * (3.0 * t * t)
+    }
+
+    fn second_derivative(&self, t: f32) -> Vec2 {
+        (self.p2 - self.p1 * 2.0 + self.p0) * (6.0 * (1.0 - t))
+            + (self.p3 - self.p2 * 2.0 + self.p1) * (6.0 * t)
+    }
+
+    fn split(&self, t: f32) -> (CubicBezier, CubicBezier) {
+        let p01 = self.p0.lerp(self.p1, t);
+        let p12 = self.p1.lerp(self.p2, t);
+        let p23 = self.p2.lerp(self.p3, t);
+        let p012 = p01.lerp(p12, t);
+        let p123 = p12.lerp(p23, t);
+        let mid = p012.lerp(p123, t);
+        (
+            CubicBezier {
+                p0: self.p0,
+                p1: p01,
+                p2: p012,
+                p3: mid,
+            },
+            CubicBezier {
+                p0: mid,
+                p1: p123,
+                p2: p23,
+                p3: self.p3,
+            },
+        )
+    }
+
+    fn flatness(&self) -> f32 {
+        let chord = self.p3 - self.p0;
+        let d1 = closest_point_on_segment(self.p1, self.p0, self.p3).distance(self.p1);
+        let d2 = closest_point_on_segment(self.p2, self.p0, self.p3).distance(self.p2);
+        if chord.length_squared() == 0.0 {
+            return self.p1.distance(self.p0).max(self.p2.distance(self.p0));
+        }
+        d1.max(d2)
+    }
+}
+
+fn distance_to_polyline(point: Vec2, path: &[Vec2]) -> f32 {
+    if path.len() == 1 {
+        return point.distance(path[0]);
+    }
+    path.windows(2)
+        .map(|w| closest_point_on_segment(point, w[0], w[1]).distance(point))
+        .fold(f32::INFINITY, f32::min)
+}
+
+/// Chaikin corner cutting. Endpoints stay put, and an iteration is only kept while
+/// every point stays within `max_error` of the original path.
+pub fn chaikin_smooth(path: &[Vec2], iterations: usize, max_error: f32) -> Vec<Vec2> {
+    let mut current = path.to_vec();
+    for _ in 0..iterations {
+        if current.len() < 3 {
+            break;
+        }
+        let mut next = Vec::with_capacity(current.len() * 2);
+        next.push(current[0]);
+        for w in current.windows(2) {
+            next.push(w[0].lerp(w[1], 0.25));
+            next.push(w[0].lerp(w[1], 0.75));
+        }
+        next.push(current[current.len() - 1]);
+
+        if next
+            .iter()
+            .any(|p| distance_to_polyline(*p, path) > max_error)
+        {
+            break;
+        }
+        current = next;
+    }
+    current
+}
+
+/// Centripetal Catmull-Rom spline through `points`, as one Bezier per span.
+pub fn catmull_rom_beziers(points: &[Vec2]) -> Vec<CubicBezier> {
+    if points.len() < 2 {
+        return Vec::new();
+    }
+    let n = points.len();
+    let get = |i: isize| -> Vec2 {
+        if i < 0 {
+            points[0] * 2.0 - points[1]
+        } else if i as usize >= n {
+            points[n - 1] * 2.0 - points[n - 2]
+        } else {
+            points[i as usize]
+        }
+    };
+
+    let mut curves = Vec::with_capacity(n - 1);
+    for i in 0..n - 1 {
+        let i = i as isize;
+        let (p0, p1, p2, p3) = (get(i - 1), get(i), get(i + 1), get(i + 2));
+        let d1 = p1.distance(p0).sqrt();
+        let d2 = p2.distance(p1).sqrt();
+        let d3 = p3.distance(p2).sqrt();
+
+        let b1 = if d1 < f32::EPSILON || d2 < f32::EPSILON {
+            p1
+        } else {
+            (p2 * (d1 * d1) - p0 * (d2 * d2) + p1 * (2.0 * d1 * d1 + 3.0 * d1 * d2 + d2 * d2))
+                / (3.0 * d1 * (d1 + d2))
+        };
+        let b2 = if d3 < f32::EPSILON || d2 < f32::EPSILON {
+            p2
+        } else {
+            (p1 * (d3 * d3) - p3 * (d2 * d2) + p2 * (2.0 * d3 * d3 + 3.0 * d3 * d2 + d2 * d2))
+                / (3.0 * d3 * (d3 + d2))
+        };
+        curves.push(CubicBezier {
+            p0: p1,
+            p1: b1,
+            p2: b2,
+            p3: p2,
+        });
+    }
+    curves
+}
+
+/// Catmull-Rom through the RDP-simplified stroke. Spans that stray more than `max_error`
+/// from the drawn points take the worst drawn point as an extra knot; a span between two
+/// neighbouring drawn points that still strays is drawn straight.
+pub fn catmull_rom_smooth(path: &[Vec2], max_error: f32) -> Vec<CubicBezier> {
+    if path.len() < 2 {
+        return Vec::new();
+    }
+    let mut keep = Vec::new();
+    ramer_douglas_peucker_mask(path, max_error, &mut keep, &mut Vec::new());
+    let mut straight = vec![false; path.len()];
+    loop {
+        let knots: Vec<usize> = (0..path.len()).filter(|&i| keep[i]).collect();
+        let points: Vec<Vec2> = knots.iter().map(|&i| path[i]).collect();
+        let mut curves = catmull_rom_beziers(&points);
+        let mut refined = false;
+        for (curve, span) in curves.iter_mut().zip(knots.windows(2)) {
+            let (first, last) = (span[0], span[1]);
+            if straight[first] {
+                *curve = CubicBezier::line(curve.p0, curve.p3);
+                continue;
+            }
+            let drawn = &path[first..=last];
+            let flat = flatten_beziers(std::slice::from_ref(curve), max_error * DEVIATION_SAMPLING);
+            let curve_error = flat
+                .iter()
+                .map(|p| distance_to_polyline(*p, drawn))
+                .fold(0.0, f32::max);
+            let (worst, drawn_error) = (first + 1..last)
+                .map(|i| (i, distance_to_polyline(path[i], &flat)))
+                .fold(
+                    (first, 0.0),
+                    |best, next| if next.1 > best.1 { next } else { best },
+                );
+            if curve_error.max(drawn_error) <= max_error {
+                continue;
+            }
+            if last - first == 1 {
+                straight[first] = true;
+                *curve = CubicBezier::line(curve.p0, curve.p3);
+            } else {
+                keep[if worst == first {
+                    (first + last) / 2
+                } else {
+                    worst
+                }] = true;
+                refined = true;
+            }
+        }
+        if !refined {
+            return curves;
+        }
+    }
+}
+
+/// Schneider's least-squares cubic fitting ("An Algorithm for Automatically Fitting
+/// Digitized Curves", Graphics Gems). No point of `path` ends up further than `max_error`
+/// from the returned curves.
+pub fn fit_cubic_beziers(path: &[Vec2], max_error: f32) -> Vec<CubicBezier> {
+    let mut points: Vec<Vec2> = Vec::with_capacity(path.len());
+    for &p in path {
+        if points
+            .last()
+            .is_none_or(|last| last.distance(p) > f32::EPSILON)
+        {
+            points.push(p);
+        }
+    }
+    if points.len() < 2 {
+        return Vec::new();
+    }
+    let last = points.len() - 1;
+    let t_hat1 = (points[1] - points[0]).normalize_or_zero();
+    let t_hat2 = (points[last - 1] - points[last]).normalize_or_zero();
+
+    let mut curves = Vec::new();
+    fit_cubic(&points, 0, last, t_hat1, t_hat2, max_error, &mut curves);
+    curves
+}
+
+fn fit_cubic(
+    points: &[Vec2],
+    first: usize,
+    last: usize,
+    t_hat1: Vec2,
+    t_hat2: Vec2,
+    max_error: f32,
+    curves: &mut Vec<CubicBezier>,
+) {
+    if last - first == 1 {
+        let dist = points[first].distance(points[last]) / 3.0;
+        curves.push(CubicBezier {
+            p0: points[first],
+            p1: points[first] + t_hat1 * dist,
+            p2: points[last] + t_hat2 * dist,
+            p3: points[last],
+        });
+        return;
+    }
+
+    let mut u = chord_length_parameterize(points, first, last);
+    let mut bezier = generate_bezier(points, first, last, &u, t_hat1, t_hat2);
+    let (mut err, mut split) = compute_max_error(points, first, last, &bezier, &u);
+    if err < max_error {
+        curves.push(bezier);
+        return;
+    }
+
+    if err < max_error * 4.0 {
+        for _ in 0..NEWTON_ITERATIONS {
+            u = reparameterize(points, first, &bezier, &u);
+            bezier = generate_bezier(points, first, last, &u, t_hat1, t_hat2);
+            (err, split) = compute_max_error(points, first, last, &bezier, &u);
+            if err < max_error {
+                curves.push(bezier);
+                return;
+            }
+        }
+    }
+
+    let mut t_center = (points[split - 1] - points[split + 1]).normalize_or_zero();
+    if t_center == Vec2::ZERO {
+        t_center = (points[split] - points[split - 1])
+            .perp()
+            .normalize_or_zero();
+    }
+    fit_cubic(points, first, split, t_hat1, t_center, max_error, curves);
+    fit_cubic(points, split, last, -t_center, t_hat2, max_error, curves);
+}
+
+fn chord_length_parameterize(points: &[Vec2], first: usize, last: usize) -> Vec<f32> {
+    let mut u = Vec::with_capacity(last - first + 1);
+    u.push(0.0);
+    for i in first + 1..=last {
+        let prev = u[u.len() - 1];
+        u.push(prev + points[i].distance(points[i - 1]));
+    }
+    let total = u[u.len() - 1];
+    if total > 0.0 {
+        u.iter_mut().for_each(|v| *v /= total);
+    }
+    u
+}
+
+fn generate_bezier(
+    points: &[Vec2],
+    first: usize,
+    last: usize,
+    u: &[f32],
+    t_hat1: Vec2,
+    t_hat2: Vec2,
+) -> CubicBezier {
+    let start = points[first];
+    let end = points[last];
+    let mut c = [[0.0f32; 2]; 2];
+    let mut x = [0.0f32; 2];
+
+    for (i, &t) in u.iter().enumerate() {
+        let mt = 1.0 - t;
+        let b0 = mt * mt * mt;
+        let b1 = 3.0 * t * mt * mt;
+        let b2 = 3.0 * t * t * mt;
+        let b3 = t * t * t;
+        let a0 = t_hat1 * b1;
+        let a1 = t_hat2 * b2;
+        c[0][0] += a0.dot(a0);
+        c[0][1] += a0.dot(a1);
+        c[1][1] += a1.dot(a1);
+        let tmp = points[first + i] - (start * (b0 + b1) + end * (b2 + b3));
+        x[0] += a0.dot(tmp);
+        x[1] += a1.dot(tmp);
+    }
+    c[1][0] = c[0][1];
+
+    let det_c0_c1 = c[0][0] * c[1][1] - c[1][0] * c[0][1];
+    let det_c0_x = c[0][0] * x[1] - c[1][0] * x[0];
+    let det_x_c1 = x[0] * c[1][1] - x[1] * c[0][1];
+    let (alpha_l, alpha_r) = if det_c0_c1.abs() < f32::EPSILON {
+        (0.0, 0.0)
+    } else {
+        (det_x_c1 / det_c0_c1, det_c0_x / det_c0_c1)
+    };
+
+    // Degenerate or flipped handles fall back to the Wu/Barsky heuristic
+    let seg_length = start.distance(end);
+    let epsilon = 1.0e-6 * seg_length;
+    if alpha_l < epsilon || alpha_r < epsilon {
+        let dist = seg_length / 3.0;
+        return CubicBezier {
+            p0: start,
+            p1: start + t_hat1 * dist,
+            p2: end + t_hat2 * dist,
+            p3: end,
+        };
+    }
+    CubicBezier {
+        p0: start,
+        p1: start + t_hat1 * alpha_l,
+        p2: end + t_hat2 * alpha_r,
+        p3: end,
+    }
+}
+
+fn reparameterize(points: &[Vec2], first: usize, bezier: &CubicBezier, u: &[f32]) -> Vec<f32> {
+    u.iter()
+        .enumerate()
+        .map(|(i, &t)| {
+            let diff = bezier.eval(t) - points[first + i];
+            let d1 = bezier.derivative(t);
+            let d2 = bezier.second_derivative(t);
+            let denominator = d1.dot(d1) + diff.dot(d2);
+            if denominator.abs() < f32::EPSILON {
+                t
+            } else {
+                (t - diff.dot(d1) / denominator).clamp(0.0, 1.0)
+            }
+        })
+        .collect()
+}
+
+fn compute_max_error(
+    points: &[Vec2],
+    first: usize,
+    last: usize,
+    bezier: &CubicBezier,
+    u: &[f32],
+) -> (f32, usize) {
+    let mut max_dist = 0.0;
+    let mut split = (first + last).div_ceil(2);
+    for i in first + 1..last {
+        let dist = bezier.eval(u[i - first]).distance(points[i]);
+        if dist >= max_dist {
+            max_dist = dist;
+            split = i;
+        }
+    }
+    (max_dist, split.clamp(first + 1, last - 1))
+}
+
+/// Polyline through the curves, subdivided until it is within `tolerance` of them.
+pub fn flatten_beziers(curves: &[CubicBezier], tolerance: f32) -> Vec<Vec2> {
+    let mut out = Vec::new();
+    if let Some(first) = curves.first() {
+        out.push(first.p0);
+    }
+    for curve in curves {
+        flatten_into(curve, tolerance, 0, &mut out);
+    }
+    out
+}
+
+fn flatten_into(curve: &CubicBezier, tolerance: f32, depth: u32, out: &mut Vec<Vec2>) {
+    if depth >= FLATTEN_MAX_DEPTH || curve.flatness() <= tolerance {
+        out.push(curve.p3);
+        return;
+    }
+    let (left, right) = curve.split(0.5);
+    flatten_into(&left, tolerance, depth + 1, out);
+    flatten_into(&right, tolerance, depth + 1, out);
+}
+
+/// Runs the configured smoother. Returns the polyline used for geometry, plus the
+/// Bezier curves to write out when the mode produces them. Polylines from the first two
+/// modes keep every point; the merge simplifies them along with the borders they meet.
+pub fn smooth_stroke(points: &[Vec2], config: &SmoothingConfig) -> (Vec<Vec2>, Vec<CubicBezier>) {
+    let window_size = 3; // Try stronger smoothing, might look funny
+    match config.mode {
+        SmoothingMode::MovingAverage => (smooth_lines(points, window_size), Vec::new()),
+        SmoothingMode::Chaikin => (
+            chaikin_smooth(points, CHAIKIN_ITERATIONS, config.max_error),
+            Vec::new(),
+        ),
+        SmoothingMode::CatmullRom => {
+            let curves = catmull_rom_smooth(points, config.max_error);
+            (flatten_beziers(&curves, config.flatten_tolerance), curves)
+        }
+        SmoothingMode::BezierFit => {
+            let curves = fit_cubic_beziers(points, config.max_error);
+            (flatten_beziers(&curves, config.flatten_tolerance), curves)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catmull_rom_stays_within_max_error() {
+        let path: Vec<Vec2> = (0..200)
+            .map(|i| {
+                let x = i as f32 * 0.7;
+                Vec2::new(
+                    x,
+                    (x * 0.3).sin() * 12.0 + if i % 7 == 0 { 2.5 } else { 0.0 },
+                )
+            })
+            .collect();
+        let max_error = 1.0;
+        let flat = flatten_beziers(&catmull_rom_smooth(&path, max_error), 0.01);
+        let slack = 0.05;
+        for point in &flat {
+            assert!(distance_to_polyline(*point, &path) <= max_error + slack);
+        }
+        for point in &path {
+            assert!(distance_to_polyline(*point, &flat) <= max_error + slack);
+        }
+    }
+}
@@ -1,16 +1,22 @@
+use bevy::math::DVec2;
 use bevy::prelude::*;
 use crate::settings::world_mouse_pos;
 use crate::svg_creation::{
     spatial_grid::{SpatialGrid, SnapState, PathSegment},
     merge_svg::*,
-    math_utils::{segment_intersection_point},
+    geometry64::{to_render, to_world},
+    clip_mask::ClipMode,
+    self_intersection::{SelfIntersectionRepair, find_self_intersections, remove_loops, split_at_crossings},
 };
 use super::draw_state::*;
 
 const MIN_MOUSE_VELOCITY: f32 = 1.0; // px/sec
-const SNAP_RADIUS: f32 = 15.0;
+pub const SNAP_RADIUS: f32 = 15.0;
 const BLOCK_RADIUS: f32 = 7.0;
+const CLOSE_LOOP_MIN_POINTS: usize = 8;
+const COAST_EXTEND_DISTANCE: f32 = 50.0;
 
+#[allow(clippy::too_many_arguments)]
 pub fn drawing(
     commands: Commands,
     asset_server: Res<AssetServer>,
@@ -21,6 +27,7 @@ pub fn drawing(
     mut spatial_grid: ResMut<SpatialGrid>,
     mut pending_segments: ResMut<PendingSegments>,
     mut snap_state: ResMut<SnapState>,
+    settings: StrokeSettings,
     cameras: Query<(&Camera, &Transform)>,
     config: Res<FollowConfig>,
     mut drawing_timer: ResMut<DrawingTimer>,
@@ -28,7 +35,7 @@ pub fn drawing(
 ) {
     let mut window = windows.single_mut();
 
-    if !(drawing_info.is_drawing && !drawing_info.is_paused) {
+    if !drawing_info.is_drawing || drawing_info.is_paused {
         return;
     }
 
@@ -66,10 +73,10 @@ pub fn drawing(
         return;
     }
 
-    if let Some((seg, nearest_point)) = spatial_grid.query_nearest_segment(mouse_pos, SNAP_RADIUS) {
-        let near_start = nearest_point.distance(seg.start) < SNAP_RADIUS;
-        let near_end = nearest_point.distance(seg.end) < SNAP_RADIUS;
-        let near_segment = nearest_point.distance(mouse_pos) < BLOCK_RADIUS;
+    if let Some((_, seg, nearest_point)) = spatial_grid.query_nearest_segment(mouse_pos, SNAP_RADIUS) {
+        let near_start = nearest_point.distance(seg.start) < SNAP_RADIUS as f64;
+        let near_end = nearest_point.distance(seg.end) < SNAP_RADIUS as f64;
+        let near_segment = nearest_point.distance(to_world(mouse_pos)) < BLOCK_RADIUS as f64;
         if near_segment && !(near_start || near_end) {
             return;
         }
@@ -77,22 +84,27 @@ pub fn drawing(
 
     let new_pos = compute_new_pos(&drawing_info, mouse_pos, config.speed);
 
-    if let Some(last) = drawing_info.last_pos {
-        for seg in &spatial_grid.segments {
-            if let Some(hit) = segment_intersection_point(last, new_pos, seg.start, seg.end) {
-                let start_dist = hit.distance(seg.start);
-                let end_dist = hit.distance(seg.end);
-                let snapped_point = if start_dist < end_dist { seg.start } else { seg.end };
+    if let Some(&first) = drawing_points.points.first()
+        && drawing_points.points.len() >= CLOSE_LOOP_MIN_POINTS
+        && new_pos.distance(first) < SNAP_RADIUS
+    {
+        drawing_info.confirm_pending = true;
+        drawing_info.confirm_point = Some(to_world(first));
+        drawing_info.confirm_seg_id = None;
+        drawing_info.confirm_prompt_printed = false;
+        return;
+    }
 
-                if !drawing_info.confirm_pending {
-                    drawing_info.confirm_pending = true;
-                    drawing_info.confirm_point = Some(snapped_point);
-                    drawing_info.confirm_seg_id = Some(seg.id);
-                    drawing_info.confirm_prompt_printed = false;
-                }
-                return;
-            }
+    if let Some(last) = drawing_info.last_pos
+        && let Some(&(seg_id, hit)) = spatial_grid.segments_crossing(to_world(last), to_world(new_pos)).first()
+    {
+        if !drawing_info.confirm_pending {
+            drawing_info.confirm_pending = true;
+            drawing_info.confirm_point = Some(hit);
+            drawing_info.confirm_seg_id = Some(seg_id);
+            drawing_info.confirm_prompt_printed = false;
         }
+        return;
     }
 
     update_drawing_points(
@@ -104,17 +116,24 @@ pub fn drawing(
         now,
     );
 
-    let snapped_id = drawing_info.snapped_seg_id;
-    let initial_id = snap_state.initial_seg_id;
+    if !snap_state.is_enabled {
+        return;
+    }
+    let snapped = spatial_grid.query_nearest_point(new_pos, SNAP_RADIUS, snap_state.blocked_zone.as_ref());
     if drawing_points.points.len() < 2 {
+        // Snapping starts where the stroke leaves a border, and the border right around
+        // there can't end it
+        if let Some((point, seg_id)) = snapped {
+            snap_state.start_blocking(&spatial_grid, (seg_id, point));
+        }
         return;
     }
-    if let (Some(current_id), Some(start_id)) = (snapped_id, initial_id) {
-        let blocked = snap_state.blocked_range;
-        let outside_block =
-            current_id < start_id.saturating_sub(blocked) || current_id > start_id + blocked;
+    drawing_info.snapped_seg_id = snapped.map(|(_, seg_id)| seg_id);
+    if let (Some((point, current_id)), Some(zone)) = (snapped, snap_state.blocked_zone.as_ref()) {
+        let outside_block = !zone.contains(&spatial_grid, (current_id, point));
 
         if outside_block {
+            drawing_info.confirm_point = Some(point);
             finalize_svg_drawing(
                 commands,
                 asset_server,
@@ -123,11 +142,13 @@ pub fn drawing(
                 &mut svg_library,
                 &mut spatial_grid,
                 &mut snap_state,
+                &settings,
             );
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn drawing_confirmation_system(
     keys: Res<ButtonInput<KeyCode>>,
     mut drawing_info: ResMut<DrawingInfo>,
@@ -135,15 +156,16 @@ pub fn drawing_confirmation_system(
     mut svg_library: ResMut<SvgLibrary>,
     mut spatial_grid: ResMut<SpatialGrid>,
     mut snap_state: ResMut<SnapState>,
+    settings: StrokeSettings,
     asset_server: Res<AssetServer>,
     commands: Commands,
 ) {
     if drawing_info.confirm_pending {
         if keys.just_pressed(KeyCode::KeyY) {
-            if let Some(confirm_point) = drawing_info.confirm_point {
-                if let Some(last) = drawing_points.points.last_mut() {
-                    *last = confirm_point;
-                }
+            if let Some(confirm_point) = drawing_info.confirm_point
+                && let Some(last) = drawing_points.points.last_mut()
+            {
+                *last = to_render(confirm_point);
             }
             finalize_svg_drawing(
                 commands,
@@ -153,6 +175,7 @@ pub fn drawing_confirmation_system(
                 &mut svg_library,
                 &mut spatial_grid,
                 &mut snap_state,
+                &settings,
             );
             drawing_info.confirm_pending = false;
             drawing_info.confirm_point = None;
@@ -185,6 +208,7 @@ pub fn drawing_cooldown_system(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn finalize_svg_drawing(
     commands: Commands,
     asset_server: Res<AssetServer>,
@@ -193,53 +217,131 @@ pub fn finalize_svg_drawing(
     svg_library: &mut ResMut<SvgLibrary>,
     spatial_grid: &mut ResMut<SpatialGrid>,
     snap_state: &mut ResMut<SnapState>,
+    settings: &StrokeSettings,
 ) {
-    let mut new_segments = Vec::new();
+    let closes_loop = match (drawing_info.confirm_point, drawing_points.points.first()) {
+        (Some(confirm_point), Some(first)) => confirm_point == to_world(*first),
+        _ => false,
+    };
 
-    if let Some(first) = drawing_points.points.first_mut() {
-        if let Some((closest_pt, _seg_id)) = spatial_grid.query_nearest_point(
-            *first,
-            15.0,
-            None,
-            0,
-        ) {
-            if first.distance(closest_pt) < 15.0 {
-                *first = closest_pt;
-            }
+    let (mut start_clipped, mut end_clipped) = (false, false);
+    if settings.clip_mask.is_active() {
+        let Some(clipped) = settings.clip_mask.clip_stroke(&drawing_points.points) else {
+            println!("Stroke lies entirely off the land mask, undo (right click) and redraw it over land");
+            drawing_info.is_paused = true;
+            return;
+        };
+        if clipped.dropped_pieces > 0 {
+            println!("Stroke crossed the coast, kept the longest of {} pieces over land", clipped.dropped_pieces + 1);
         }
+        start_clipped = clipped.start_clipped;
+        end_clipped = clipped.end_clipped;
+        drawing_points.points = clipped.points;
+    }
+    let closes_loop = closes_loop && !start_clipped && !end_clipped;
+
+    // Exact f64 positions of the stroke's ends on existing borders. The f32 drawing points
+    // only approximate these, so the stored segments take their ends from here
+    let mut start_anchor = None;
+    if !start_clipped
+        && let Some(first) = drawing_points.points.first_mut()
+        && let Some((closest_pt, _seg_id)) = spatial_grid.query_nearest_point(*first, SNAP_RADIUS, None)
+        && to_world(*first).distance(closest_pt) < SNAP_RADIUS as f64
+    {
+        *first = to_render(closest_pt);
+        start_anchor = Some(closest_pt);
     }
 
-    if let Some(confirm_point) = drawing_info.confirm_point {
+    let mut end_anchor = if end_clipped { None } else { drawing_info.confirm_point };
+    if let Some(confirm_point) = end_anchor
+        && let Some(last) = drawing_points.points.last_mut()
+    {
+        *last = to_render(confirm_point);
+    }
+    if closes_loop {
+        let first = drawing_points.points[0];
         if let Some(last) = drawing_points.points.last_mut() {
-            *last = confirm_point;
+            *last = first;
         }
+        end_anchor = Some(start_anchor.unwrap_or(to_world(first)));
     }
 
-    for pair in drawing_points.points.windows(2) {
-        if let [start, end] = pair {
-            let id = spatial_grid.segments.len() + new_segments.len();
-            new_segments.push(PathSegment {
-                start: *start,
-                end: *end,
-                id,
-            });
+    if settings.clip_mask.mode == ClipMode::ClipAndExtend && settings.clip_mask.is_active() && !closes_loop {
+        let points = &mut drawing_points.points;
+        if start_anchor.is_none()
+            && !start_clipped
+            && points.len() >= 2
+            && let Some(coast) = settings.clip_mask.extend_to_coast(points[0], points[0] - points[1], COAST_EXTEND_DISTANCE)
+        {
+            points.insert(0, coast);
+        }
+        let n = points.len();
+        if end_anchor.is_none()
+            && !end_clipped
+            && n >= 2
+            && let Some(coast) = settings.clip_mask.extend_to_coast(points[n - 1], points[n - 1] - points[n - 2], COAST_EXTEND_DISTANCE)
+        {
+            points.push(coast);
         }
     }
 
-    check_and_merge_svg(
-        commands,
-        asset_server,
-        drawing_info,
-        drawing_points,
-        svg_library,
-    );
+    let crossings = find_self_intersections(&drawing_points.points);
+    if let Some(first_crossing) = crossings.first() {
+        match settings.self_intersections.repair {
+            SelfIntersectionRepair::RemoveLoops => {
+                drawing_points.points = remove_loops(&drawing_points.points);
+                println!("Stroke crossed itself {} time(s), loops removed", crossings.len());
+            }
+            SelfIntersectionRepair::SplitAtCrossing => {
+                // The pieces stay in one list, separated by NaN points like merged lines
+                let pieces = split_at_crossings(&drawing_points.points);
+                println!("Stroke crossed itself {} time(s), split into {} pieces", crossings.len(), pieces.len());
+                drawing_points.points = pieces.join(&Vec2::NAN);
+            }
+            SelfIntersectionRepair::Reject => {
+                println!(
+                    "Stroke crosses itself at {:?}, undo (right click) past the loop and resume",
+                    first_crossing.point
+                );
+                drawing_info.is_paused = true;
+                return;
+            }
+        }
+    }
+
+    // Split the borders the stroke starts and ends on, so both lines share those vertices
+    start_anchor = start_anchor.map(|anchor| spatial_grid.weld_point(anchor));
+    end_anchor = end_anchor.map(|anchor| spatial_grid.weld_point(anchor));
+    if let (Some(anchor), Some(first)) = (start_anchor, drawing_points.points.first_mut()) {
+        *first = to_render(anchor);
+    }
+    if let (Some(anchor), Some(last)) = (end_anchor, drawing_points.points.last_mut()) {
+        *last = to_render(anchor);
+    }
+
+    // Simplified once, before the stroke goes into the grid so it only has the other borders
+    // to keep clear of, and the SVG and the grid get the very same polyline
+    let (mut path, curves) = smooth_pieces(&drawing_points.points, &settings.smoothing);
+    if curves.is_empty() {
+        path = simplify_stroke(&path, spatial_grid);
+    }
+    drawing_points.points = path;
+    drawing_points.curves = curves;
+
+    // Regions aren't made here: the topology picks the new segments up and the faces they
+    // close off become regions
+    let mut stored: Vec<DVec2> = drawing_points.points.iter().map(|p| to_world(*p)).collect();
+    if let (Some(anchor), Some(first)) = (start_anchor, stored.first_mut()) {
+        *first = anchor;
+    }
+    if let (Some(anchor), Some(last)) = (end_anchor, stored.last_mut()) {
+        *last = anchor;
+    }
 
-    spatial_grid.segments.extend(new_segments);
-    spatial_grid.rebuild_grid();
+    check_and_merge_svg(commands, asset_server, drawing_info, drawing_points, svg_library);
+    merge_into_grid(spatial_grid, &stored);
 
-    snap_state.is_blocking = false;
-    snap_state.initial_seg_id = None;
-    snap_state.initial_snap_pos = None;
+    snap_state.stop_blocking();
 
     drawing_info.snapped_seg_id = None;
     drawing_points.points.clear();
@@ -266,9 +368,8 @@ pub fn update_drawing_points(
 ) {
     if let Some(last) = drawing_info.last_pos {
         let segment = PathSegment {
-            start: last,
-            end: new_pos,
-            id: pending_segments.segments.len(),
+            start: to_world(last),
+            end: to_world(new_pos),
         };
         pending_segments.segments.push(segment);
     }
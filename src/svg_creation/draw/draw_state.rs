@@ -1,10 +1,19 @@
+use bevy::ecs::system::SystemParam;
+use bevy::math::DVec2;
 use bevy::prelude::*;
 use std::time::Duration;
-use crate::svg_creation::spatial_grid::PathSegment;
+use crate::svg_creation::{
+    clip_mask::ClipMask,
+    curve_fit::{CubicBezier, SmoothingConfig},
+    self_intersection::SelfIntersectionConfig,
+    spatial_grid::{PathSegment, SegmentId},
+};
 
 #[derive(Resource, Default)]
 pub struct DrawingPoints {
     pub points: Vec<Vec2>,
+    /// Fitted curves for the first sub-path of `points`, written out as `C` commands.
+    pub curves: Vec<CubicBezier>,
     pub line_entities: Vec<Entity>,
 }
 
@@ -13,35 +22,30 @@ pub struct PendingSegments {
     pub segments: Vec<PathSegment>,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
 pub enum DrawingFinalizeState {
+    #[default]
     None,
     Cooldown,
 }
 
-impl Default for DrawingFinalizeState {
-    fn default() -> Self {
-        DrawingFinalizeState::None
-    }
-}
-
 #[derive(Resource, Default)]
 pub struct DrawingInfo {
     pub counter: usize,
     pub last_pos: Option<Vec2>,
     pub last_mouse_pos: Option<Vec2>,
     pub last_mouse_time: Option<f32>,
-    pub snapped_seg_id: Option<usize>,
+    pub snapped_seg_id: Option<SegmentId>,
     pub is_drawing: bool,
     pub is_paused: bool,
-    pub started_from_seg_id: Option<usize>,
+    pub started_from_seg_id: Option<SegmentId>,
     pub finalize_state: DrawingFinalizeState,
     pub cooldown_timer: Option<Timer>,
     pub drawing_enabled: bool,
     pub start_candidate: Option<Vec2>,
     pub confirm_pending: bool,
-    pub confirm_point: Option<Vec2>,
-    pub confirm_seg_id: Option<usize>,
+    pub confirm_point: Option<DVec2>,
+    pub confirm_seg_id: Option<SegmentId>,
     pub confirm_prompt_printed: bool,
 }
 
@@ -62,3 +66,11 @@ impl Default for DrawingTimer {
         }
     }
 }
+
+/// Settings that decide how a finished stroke is cleaned up before it becomes a border.
+#[derive(SystemParam)]
+pub struct StrokeSettings<'w> {
+    pub smoothing: Res<'w, SmoothingConfig>,
+    pub self_intersections: Res<'w, SelfIntersectionConfig>,
+    pub clip_mask: Res<'w, ClipMask>,
+}
@@ -1,7 +1,7 @@
 use bevy::prelude::*;
 use bevy_prototype_lyon::prelude::*;
 use super::draw_state::DrawingPoints;
-use crate::svg_creation::math_utils::smooth_lines;
+use crate::svg_creation::{math_utils::smooth_lines, self_intersection::find_self_intersections};
 
 pub fn draw_lines(
     mut commands: Commands,
@@ -24,4 +24,22 @@ pub fn draw_lines(
         )).id();
         drawing_points.line_entities.push(entity);
     }
+
+    // Mark where the stroke crosses itself so it can be undone before finishing; taken from
+    // the raw points, since those are what gets checked when the stroke is finished
+    for crossing in find_self_intersections(&drawing_points.points) {
+        let marker = shapes::Circle {
+            radius: 2.0,
+            center: crossing.point,
+        };
+        let entity = commands.spawn((
+            ShapeBundle {
+                path: GeometryBuilder::build_as(&marker),
+                transform: Transform::from_xyz(0.0, 0.0, 1.0),
+                ..default()
+            },
+            Fill::color(Srgba::rgb(0.9, 0.1, 0.1)),
+        )).id();
+        drawing_points.line_entities.push(entity);
+    }
 }
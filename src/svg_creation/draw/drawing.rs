@@ -4,6 +4,8 @@ use crate::draw_logic::*;
 use crate::draw_state::*;
 use crate::draw_undo_delete::*;
 use crate::draw_visual::*;
+use crate::self_intersection::SelfIntersectionConfig;
+use crate::clip_mask::ClipMask;
 
 pub struct DrawPlugin;
 
@@ -15,6 +17,8 @@ impl Plugin for DrawPlugin {
             .insert_resource(DrawingPoints::default())
             .insert_resource(FollowConfig { speed: 2.0 })
             .insert_resource(DrawingTimer::default())
+            .insert_resource(SelfIntersectionConfig::default())
+            .insert_resource(ClipMask::default())
             .add_systems(Update, (
                 drawing_control_system,
                 undo_last_drawing,
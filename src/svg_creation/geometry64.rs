@@ -0,0 +1,244 @@
+use bevy::math::DVec2;
+use bevy::prelude::*;
+
+// Stored topology (grid segments, snapped endpoints) lives in f64 so that points far from
+// the origin still meet exactly. Mouse input comes in as f32 and everything drawn goes
+// back out as f32; these two are the only crossings between the two.
+
+pub fn to_world(point: Vec2) -> DVec2 {
+    point.as_dvec2()
+}
+
+pub fn to_render(point: DVec2) -> Vec2 {
+    point.as_vec2()
+}
+
+pub fn closest_point_on_segment(p: DVec2, a: DVec2, b: DVec2) -> DVec2 {
+    let ab = b - a;
+    let ab_len_sq = ab.length_squared();
+    if ab_len_sq == 0.0 {
+        return a;
+    }
+    let t = (p - a).dot(ab) / ab_len_sq;
+    if t <= 0.0 {
+        a
+    } else if t >= 1.0 {
+        b
+    } else {
+        a + ab * t
+    }
+}
+
+/// Where two segments cross, if they do. Parallel segments never report a crossing. The
+/// parallel test scales with the segment lengths rather than using a fixed epsilon, so it
+/// works the same for a 0.01 unit stroke and a 5000 unit border.
+pub fn segment_intersection_point(p1: DVec2, p2: DVec2, q1: DVec2, q2: DVec2) -> Option<DVec2> {
+    let s1 = p2 - p1;
+    let s2 = q2 - q1;
+    let denom = s1.perp_dot(s2);
+    if denom.abs() <= f64::EPSILON * s1.length() * s2.length() {
+        return None;
+    }
+    let d = q1 - p1;
+    let t = d.perp_dot(s2) / denom;
+    let s = d.perp_dot(s1) / denom;
+    if !(0.0..=1.0).contains(&s) || !(0.0..=1.0).contains(&t) {
+        return None;
+    }
+    // Hits on an endpoint return that endpoint as-is, so snapped vertices compare equal
+    Some(match (t, s) {
+        (0.0, _) => p1,
+        (1.0, _) => p2,
+        (_, 0.0) => q1,
+        (_, 1.0) => q2,
+        _ => p1 + s1 * t,
+    })
+}
+
+/// Even-odd point in polygon test.
+pub fn point_in_ring(point: DVec2, ring: &[DVec2]) -> bool {
+    let mut inside = false;
+    let mut j = ring.len().wrapping_sub(1);
+    for i in 0..ring.len() {
+        let (a, b) = (ring[i], ring[j]);
+        if (a.y > point.y) != (b.y > point.y)
+            && point.x < (b.x - a.x) * (point.y - a.y) / (b.y - a.y) + a.x
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+/// Rounds onto a grid of `cell` units, so two computations of the same crossing that differ
+/// in the last bits land on the same vertex.
+pub fn snap_round(point: DVec2, cell: f64) -> DVec2 {
+    (point / cell).round() * cell
+}
+
+/// Whether segment `a`-`b` touches the rectangle `min..max`, edges included.
+pub fn segment_intersects_rect(a: DVec2, b: DVec2, min: DVec2, max: DVec2) -> bool {
+    // Liang-Barsky: narrow the segment's parameter range against each slab in turn
+    let d = b - a;
+    let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+    for (p, q) in [
+        (-d.x, a.x - min.x),
+        (d.x, max.x - a.x),
+        (-d.y, a.y - min.y),
+        (d.y, max.y - a.y),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return false;
+            }
+            continue;
+        }
+        let t = q / p;
+        if p < 0.0 {
+            t0 = t0.max(t);
+        } else {
+            t1 = t1.min(t);
+        }
+        if t0 > t1 {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p(x: f64, y: f64) -> DVec2 {
+        DVec2::new(x, y)
+    }
+
+    #[test]
+    fn crossings_far_from_the_origin() {
+        // Nearly parallel, a thousandth of a unit apart at the ends, still cross
+        let hit = segment_intersection_point(
+            p(5000.0, 5000.0),
+            p(5100.0, 5000.001),
+            p(5000.0, 5000.001),
+            p(5100.0, 5000.0),
+        )
+        .unwrap();
+        assert!(hit.distance(p(5050.0, 5000.0005)) < 1e-9);
+        // Nearly parallel and apart never cross, and exactly parallel ones never report
+        assert!(
+            segment_intersection_point(
+                p(5000.0, 5000.0),
+                p(5100.0, 5000.001),
+                p(5000.0, 5000.01),
+                p(5100.0, 5000.02),
+            )
+            .is_none()
+        );
+        assert!(
+            segment_intersection_point(
+                p(5000.0, 5000.0),
+                p(6000.0, 5000.0),
+                p(5500.0, 5000.0),
+                p(6500.0, 5000.0),
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn endpoint_touches_return_the_endpoint_exactly() {
+        let (a, b) = (p(5000.1, 5000.3), p(5000.7, 5000.9));
+        let c = p(5003.3, 4999.9);
+        assert_eq!(segment_intersection_point(a, b, b, c), Some(b));
+        assert_eq!(segment_intersection_point(b, c, a, b), Some(b));
+        // A segment starting on the middle of another
+        let (d, e) = (p(5000.0, 5000.0), p(5020.0, 5000.0));
+        let start = p(5010.0, 5000.0);
+        assert_eq!(
+            segment_intersection_point(d, e, start, p(5010.0, 5010.0)),
+            Some(start)
+        );
+        // Ends a hair apart don't touch
+        assert!(segment_intersection_point(a, b, b + p(1e-9, 0.0), c).is_none());
+    }
+
+    #[test]
+    fn snap_round_merges_near_copies() {
+        let a = p(5000.123456789, -5000.987654321);
+        let b = a + p(5.0 * f64::EPSILON * 5000.0, -3.0 * f64::EPSILON * 5000.0);
+        assert_ne!(a, b);
+        assert_eq!(snap_round(a, 1e-6), snap_round(b, 1e-6));
+        assert!(snap_round(a, 1e-6).distance(a) <= 1e-6);
+        assert_eq!(snap_round(p(5000.4, -5000.6), 1.0), p(5000.0, -5001.0));
+    }
+
+    #[test]
+    fn segments_against_a_rect() {
+        let (min, max) = (p(5000.0, 5000.0), p(5010.0, 5010.0));
+        // Inside, crossing through, and touching a corner
+        assert!(segment_intersects_rect(
+            p(5002.0, 5002.0),
+            p(5003.0, 5004.0),
+            min,
+            max
+        ));
+        assert!(segment_intersects_rect(
+            p(4990.0, 5005.0),
+            p(5020.0, 5006.0),
+            min,
+            max
+        ));
+        assert!(segment_intersects_rect(
+            p(5010.0, 5010.0),
+            p(5020.0, 5030.0),
+            min,
+            max
+        ));
+        // Vertical along the left edge, and horizontal just above the top
+        assert!(segment_intersects_rect(
+            p(5000.0, 4990.0),
+            p(5000.0, 5020.0),
+            min,
+            max
+        ));
+        assert!(!segment_intersects_rect(
+            p(4990.0, 5010.001),
+            p(5020.0, 5010.001),
+            min,
+            max
+        ));
+        // Cutting past a corner without reaching it
+        assert!(!segment_intersects_rect(
+            p(5009.0, 5011.0),
+            p(5011.0, 5010.001),
+            min,
+            max
+        ));
+    }
+
+    #[test]
+    fn point_in_ring_far_from_the_origin() {
+        // A U: the notch between the arms is outside
+        let u: Vec<DVec2> = [
+            (0.0, 0.0),
+            (30.0, 0.0),
+            (30.0, 30.0),
+            (20.0, 30.0),
+            (20.0, 10.0),
+            (10.0, 10.0),
+            (10.0, 30.0),
+            (0.0, 30.0),
+        ]
+        .iter()
+        .map(|&(x, y)| p(x + 5000.0, y + 5000.0))
+        .collect();
+        assert!(point_in_ring(p(5005.0, 5005.0), &u));
+        assert!(point_in_ring(p(5025.0, 5025.0), &u));
+        assert!(!point_in_ring(p(5015.0, 5020.0), &u));
+        assert!(!point_in_ring(p(5031.0, 5005.0), &u));
+        assert!(point_in_ring(p(5009.999999, 5020.0), &u));
+        assert!(!point_in_ring(p(5010.000001, 5020.0), &u));
+    }
+}
@@ -0,0 +1,388 @@
+use bevy::math::DVec2;
+use bevy::prelude::*;
+use std::path::Path;
+
+use crate::spatial_grid::PathSegment;
+use crate::spatial_index::{Slot, SpatialIndex, SpatialIndexKind, read_index};
+use crate::topology::Topology;
+
+const INDEX_MAGIC: &[u8; 4] = b"SGIX";
+const TOPOLOGY_MAGIC: &[u8; 4] = b"SGTP";
+/// Bump whenever the layout of either file, of any backend or of the topology changes.
+const FORMAT_VERSION: u32 = 2;
+
+/// FNV-1a over everything that goes into an import. Hand rolled rather than std's hasher,
+/// whose output may change between Rust releases and would throw every cache away.
+pub struct CacheKey(u64);
+
+impl CacheKey {
+    pub fn new() -> Self {
+        let mut key = CacheKey(0xcbf2_9ce4_8422_2325);
+        key.add(&FORMAT_VERSION.to_le_bytes());
+        key
+    }
+
+    pub fn add(&mut self, bytes: &[u8]) -> &mut Self {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+        self
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for CacheKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Default)]
+pub struct CacheWriter {
+    pub bytes: Vec<u8>,
+}
+
+impl CacheWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn vec2(&mut self, value: Vec2) {
+        self.f32(value.x);
+        self.f32(value.y);
+    }
+
+    pub fn dvec2(&mut self, value: DVec2) {
+        self.f64(value.x);
+        self.f64(value.y);
+    }
+}
+
+/// Reads back what `CacheWriter` wrote. Every read is checked, so a truncated or corrupt
+/// file gives None rather than a panic.
+pub struct CacheReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> CacheReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (head, rest) = self.bytes.split_first_chunk::<N>()?;
+        self.bytes = rest;
+        Some(*head)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.take::<1>().map(|b| b[0])
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    /// A length or index, refused if it couldn't possibly fit in what's left of the file.
+    pub fn len(&mut self, min_bytes_each: usize) -> Option<usize> {
+        let len = usize::try_from(self.u64()?).ok()?;
+        (len.checked_mul(min_bytes_each)? <= self.bytes.len()).then_some(len)
+    }
+
+    pub fn usize(&mut self) -> Option<usize> {
+        usize::try_from(self.u64()?).ok()
+    }
+
+    pub fn f32(&mut self) -> Option<f32> {
+        self.take().map(f32::from_le_bytes)
+    }
+
+    pub fn f64(&mut self) -> Option<f64> {
+        self.take().map(f64::from_le_bytes)
+    }
+
+    pub fn vec2(&mut self) -> Option<Vec2> {
+        Some(Vec2::new(self.f32()?, self.f32()?))
+    }
+
+    pub fn dvec2(&mut self) -> Option<DVec2> {
+        Some(DVec2::new(self.f64()?, self.f64()?))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+/// Slots with their bounding boxes, as the tree backends keep them.
+pub fn write_boxes(out: &mut CacheWriter, entries: &[(Slot, DVec2, DVec2)]) {
+    out.usize(entries.len());
+    for &(slot, min, max) in entries {
+        out.u32(slot);
+        out.dvec2(min);
+        out.dvec2(max);
+    }
+}
+
+pub fn read_boxes(reader: &mut CacheReader) -> Option<Vec<(Slot, DVec2, DVec2)>> {
+    let count = reader.len(4 + 32)?;
+    (0..count)
+        .map(|_| Some((reader.u32()?, reader.dvec2()?, reader.dvec2()?)))
+        .collect()
+}
+
+/// Node indices, each checked against the `node_count` they have to point into.
+pub fn read_node_index(reader: &mut CacheReader, node_count: usize) -> Option<usize> {
+    reader.usize().filter(|&node| node < node_count)
+}
+
+/// True if following `children` down from `root` reaches every node exactly once. Indices
+/// being in range isn't enough: a cycle would send lookups round forever, and a shared or
+/// orphaned subtree would find slots twice or never.
+pub fn is_tree<I: IntoIterator<Item = usize>>(
+    root: Option<usize>,
+    node_count: usize,
+    children: impl Fn(usize) -> I,
+) -> bool {
+    let mut seen = vec![false; node_count];
+    let mut stack: Vec<usize> = root.into_iter().collect();
+    let mut reached = 0;
+    while let Some(node) = stack.pop() {
+        if node >= node_count || std::mem::replace(&mut seen[node], true) {
+            return false;
+        }
+        reached += 1;
+        stack.extend(children(node));
+    }
+    reached == node_count
+}
+
+fn kind_tag(kind: SpatialIndexKind) -> u8 {
+    match kind {
+        SpatialIndexKind::Grid => 0,
+        SpatialIndexKind::Quadtree => 1,
+        SpatialIndexKind::RTree => 2,
+    }
+}
+
+/// Writes a cache file: `magic` and `key`, whatever `write` adds, then a checksum of it all.
+fn save_cache(path: &Path, magic: &[u8; 4], key: u64, write: impl FnOnce(&mut CacheWriter)) {
+    let mut out = CacheWriter::default();
+    out.bytes.extend_from_slice(magic);
+    out.u64(key);
+    write(&mut out);
+    let checksum = CacheKey::new().add(&out.bytes).value();
+    out.u64(checksum);
+
+    if let Some(dir) = path.parent()
+        && let Err(err) = std::fs::create_dir_all(dir)
+    {
+        println!("Cache: couldn't create {:?}: {}", dir, err);
+        return;
+    }
+    match std::fs::write(path, &out.bytes) {
+        Ok(()) => println!("Cache written to {:?} ({} bytes)", path, out.bytes.len()),
+        Err(err) => println!("Cache: couldn't write {:?}: {}", path, err),
+    }
+}
+
+/// Reads back a file `save_cache` wrote with the same `magic` and `key`. None if it's
+/// missing, stale or corrupt, or if `read` doesn't use up exactly what was written.
+fn load_cache<T>(
+    path: &Path,
+    magic: &[u8; 4],
+    key: u64,
+    read: impl FnOnce(&mut CacheReader) -> Option<T>,
+) -> Option<T> {
+    let bytes = std::fs::read(path).ok()?;
+    let (body, checksum) = bytes.split_last_chunk::<8>()?;
+    let mut reader = CacheReader::new(body);
+    if reader.take::<4>()? != *magic || reader.u64()? != key {
+        println!("Cache at {:?} is stale, rebuilding", path);
+        return None;
+    }
+    if CacheKey::new().add(body).value() != u64::from_le_bytes(*checksum) {
+        println!("Cache at {:?} is corrupt, rebuilding", path);
+        return None;
+    }
+    let value = read(&mut reader)?;
+    reader.is_empty().then_some(value)
+}
+
+/// Writes the imported segments, in slot order, and the index built over them.
+pub fn save_index_cache(
+    path: &Path,
+    key: u64,
+    segments: &[(Slot, PathSegment)],
+    index: &dyn SpatialIndex,
+) {
+    save_cache(path, INDEX_MAGIC, key, |out| {
+        out.u8(kind_tag(index.kind()));
+        out.usize(segments.len());
+        for (slot, segment) in segments {
+            out.u32(*slot);
+            out.dvec2(segment.start);
+            out.dvec2(segment.end);
+        }
+        index.write_cache(out);
+    });
+}
+
+/// The segments and built index from the cache at `path`, if it's there, was made with the
+/// same `key` and reads back cleanly. Segments come back in slot order from 0.
+pub fn load_index_cache(
+    path: &Path,
+    key: u64,
+    cell_size: f32,
+) -> Option<(Vec<PathSegment>, Box<dyn SpatialIndex>)> {
+    load_cache(path, INDEX_MAGIC, key, |reader| {
+        let kind = match reader.u8()? {
+            0 => SpatialIndexKind::Grid,
+            1 => SpatialIndexKind::Quadtree,
+            2 => SpatialIndexKind::RTree,
+            _ => return None,
+        };
+        let count = reader.len(4 + 32)?;
+        let mut segments = Vec::with_capacity(count);
+        for i in 0..count {
+            if reader.u32()? as usize != i {
+                return None;
+            }
+            segments.push(PathSegment {
+                start: reader.dvec2()?,
+                end: reader.dvec2()?,
+            });
+        }
+        let index = read_index(kind, cell_size, reader)?;
+        Some((segments, index))
+    })
+}
+
+/// Writes the topology built over the imported segments, under the key of their index cache.
+pub fn save_topology_cache(path: &Path, key: u64, topology: &Topology) {
+    save_cache(path, TOPOLOGY_MAGIC, key, |out| topology.write_cache(out));
+}
+
+pub fn load_topology_cache(path: &Path, key: u64) -> Option<Topology> {
+    load_cache(path, TOPOLOGY_MAGIC, key, Topology::read_cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtree::RTree;
+    use crate::spatial_index::new_index;
+
+    fn segments() -> Vec<(Slot, PathSegment)> {
+        (0..500)
+            .map(|i| {
+                let start = DVec2::new((i * 37 % 400) as f64, (i * 91 % 300) as f64);
+                let segment = PathSegment {
+                    start,
+                    end: start + DVec2::new(3.0, 1.5),
+                };
+                (i as Slot, segment)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cache_round_trips_and_rejects_corruption() {
+        let segments = segments();
+        let path =
+            std::env::temp_dir().join(format!("index_cache_test_{}.bin", std::process::id()));
+        for kind in [
+            SpatialIndexKind::Grid,
+            SpatialIndexKind::Quadtree,
+            SpatialIndexKind::RTree,
+        ] {
+            let mut index = new_index(kind, 5.0);
+            index.rebuild(&segments);
+            save_index_cache(&path, 42, &segments, index.as_ref());
+
+            let (loaded, loaded_index) = load_index_cache(&path, 42, 5.0).unwrap();
+            assert_eq!(loaded.len(), segments.len());
+            assert!(loaded.iter().zip(&segments).all(|(a, (_, b))| a == b));
+            let (min, max) = (DVec2::new(50.0, 50.0), DVec2::new(120.0, 90.0));
+            let (mut expected, mut found) = (Vec::new(), Vec::new());
+            index.query_rect(min, max, &mut expected);
+            loaded_index.query_rect(min, max, &mut found);
+            expected.sort_unstable();
+            found.sort_unstable();
+            assert_eq!(expected, found);
+
+            assert!(load_index_cache(&path, 43, 5.0).is_none());
+            let mut bytes = std::fs::read(&path).unwrap();
+            let middle = bytes.len() / 2;
+            bytes[middle] ^= 0x10;
+            std::fs::write(&path, &bytes).unwrap();
+            assert!(load_index_cache(&path, 42, 5.0).is_none());
+            bytes.truncate(middle);
+            std::fs::write(&path, &bytes).unwrap();
+            assert!(load_index_cache(&path, 42, 5.0).is_none());
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn tree_nodes_must_form_a_tree() {
+        // A one-node R-tree, either a plain leaf or its own child
+        let cache = |children: &[usize]| {
+            let mut out = CacheWriter::default();
+            out.usize(0);
+            out.usize(0);
+            out.u8(1);
+            out.usize(1);
+            out.usize(0);
+            out.dvec2(DVec2::ZERO);
+            out.dvec2(DVec2::ONE);
+            out.usize(children.len());
+            children.iter().for_each(|&child| out.usize(child));
+            write_boxes(&mut out, &[]);
+            out.bytes
+        };
+        assert!(RTree::read_cache(&mut CacheReader::new(&cache(&[]))).is_some());
+        assert!(RTree::read_cache(&mut CacheReader::new(&cache(&[0]))).is_none());
+
+        assert!(is_tree(Some(0), 3, |node| match node {
+            0 => vec![1, 2],
+            _ => vec![],
+        }));
+        assert!(!is_tree(Some(0), 3, |node| match node {
+            0 => vec![1, 1],
+            _ => vec![],
+        }));
+        assert!(!is_tree(Some(0), 3, |node| match node {
+            0 => vec![1],
+            _ => vec![],
+        }));
+    }
+}
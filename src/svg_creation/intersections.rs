@@ -0,0 +1,353 @@
+use bevy::math::DVec2;
+use bevy::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    geometry64::{closest_point_on_segment, snap_round},
+    math_utils::{bounding_box, segment_intersection_point},
+    spatial_grid::{PathSegment, SegmentId, SpatialGrid},
+};
+
+const MAX_CELLS_PER_SEGMENT: usize = 4;
+
+/// A crossing between segment `segment` of the query path and segment `other_segment`
+/// of path `other_path`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PathIntersection {
+    pub point: Vec2,
+    pub segment: usize,
+    pub other_path: usize,
+    pub other_segment: usize,
+}
+
+/// Uniform hash over the segments of one path, so other geometry only gets tested against
+/// the segments that share a cell with it. NaN points split the path into sub-paths and
+/// never form a segment.
+pub struct SegmentHash {
+    points: Vec<Vec2>,
+    min: Vec2,
+    max: Vec2,
+    cell_size: f32,
+    cols: usize,
+    rows: usize,
+    cells: Vec<Vec<usize>>,
+}
+
+impl SegmentHash {
+    pub fn new(path: &[Vec2]) -> Self {
+        let segments: Vec<usize> = (0..path.len().saturating_sub(1))
+            .filter(|&i| path[i].is_finite() && path[i + 1].is_finite())
+            .collect();
+        let (min, max) = bounding_box(path).unwrap_or((Vec2::ZERO, Vec2::ZERO));
+        let mut hash = Self {
+            points: path.to_vec(),
+            min,
+            max,
+            cell_size: 1.0,
+            cols: 0,
+            rows: 0,
+            cells: Vec::new(),
+        };
+        if segments.is_empty() {
+            return hash;
+        }
+
+        // Roughly two segments' length per cell, grown until the cell count stays proportional
+        let total: f32 = segments
+            .iter()
+            .map(|&i| path[i].distance(path[i + 1]))
+            .sum();
+        let size = max - min;
+        let mut cell_size = (2.0 * total / segments.len() as f32).max(1.0e-3);
+        loop {
+            let cols = (size.x / cell_size).floor() as usize + 1;
+            let rows = (size.y / cell_size).floor() as usize + 1;
+            if cols * rows <= segments.len() * MAX_CELLS_PER_SEGMENT {
+                hash.cols = cols;
+                hash.rows = rows;
+                break;
+            }
+            cell_size *= 2.0;
+        }
+        hash.cell_size = cell_size;
+        hash.cells = vec![Vec::new(); hash.cols * hash.rows];
+
+        for i in segments {
+            if let Some((c0, r0, c1, r1)) = hash.cell_range(path[i], path[i + 1]) {
+                for row in r0..=r1 {
+                    for col in c0..=c1 {
+                        hash.cells[row * hash.cols + col].push(i);
+                    }
+                }
+            }
+        }
+        hash
+    }
+
+    fn cell_of(&self, p: Vec2) -> (usize, usize) {
+        let rel = ((p - self.min) / self.cell_size).floor();
+        (
+            (rel.x.max(0.0) as usize).min(self.cols - 1),
+            (rel.y.max(0.0) as usize).min(self.rows - 1),
+        )
+    }
+
+    fn cell_range(&self, a: Vec2, b: Vec2) -> Option<(usize, usize, usize, usize)> {
+        let (lo, hi) = (a.min(b), a.max(b));
+        if self.cols == 0
+            || hi.x < self.min.x
+            || hi.y < self.min.y
+            || lo.x > self.max.x
+            || lo.y > self.max.y
+        {
+            return None;
+        }
+        let (c0, r0) = self.cell_of(lo);
+        let (c1, r1) = self.cell_of(hi);
+        Some((c0, r0, c1, r1))
+    }
+
+    /// Calls `hit` once for every hashed segment that `a`-`b` crosses.
+    pub fn for_each_crossing(&self, a: Vec2, b: Vec2, mut hit: impl FnMut(usize, Vec2)) {
+        if !a.is_finite() || !b.is_finite() {
+            return;
+        }
+        let Some((c0, r0, c1, r1)) = self.cell_range(a, b) else {
+            return;
+        };
+        for row in r0..=r1 {
+            for col in c0..=c1 {
+                for &i in &self.cells[row * self.cols + col] {
+                    let (p, q) = (self.points[i], self.points[i + 1]);
+                    let Some(point) = segment_intersection_point(p, q, a, b) else {
+                        continue;
+                    };
+                    // A pair can share several cells; only report it from the cell holding the
+                    // crossing, clamped into both boxes so rounding can't push it out of range
+                    let lo = p.min(q).max(a.min(b));
+                    let hi = p.max(q).min(a.max(b));
+                    if self.cell_of(point.max(lo).min(hi)) == (col, row) {
+                        hit(i, point);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Every crossing between `path` and the paths in `others`.
+pub fn path_intersections(path: &[Vec2], others: &[&[Vec2]]) -> Vec<PathIntersection> {
+    let hash = SegmentHash::new(path);
+    let mut out = Vec::new();
+    for (other_path, other) in others.iter().enumerate() {
+        for (other_segment, w) in other.windows(2).enumerate() {
+            hash.for_each_crossing(w[0], w[1], |segment, point| {
+                out.push(PathIntersection {
+                    point,
+                    segment,
+                    other_path,
+                    other_segment,
+                });
+            });
+        }
+    }
+    sort_along_path(path, &mut out);
+    out
+}
+
+/// Copy of `path` with `cuts` (segment index, point on it) added as vertices. With `split`
+/// the path is also broken there into pieces that both end on the cut, each piece followed
+/// by a NaN point.
+pub fn insert_cuts(path: &[Vec2], cuts: &[(usize, Vec2)], split: bool) -> Vec<Vec2> {
+    let mut cuts = cuts.to_vec();
+    cuts.sort_by(|a, b| {
+        a.0.cmp(&b.0).then_with(|| {
+            let start = path[a.0];
+            a.1.distance_squared(start)
+                .total_cmp(&b.1.distance_squared(start))
+        })
+    });
+    let mut out = Vec::with_capacity(path.len() + cuts.len() * 3);
+    let mut cuts = cuts.into_iter().peekable();
+    for (i, &point) in path.iter().enumerate() {
+        if out.last() != Some(&point) {
+            out.push(point);
+        }
+        while let Some((_, cut)) = cuts.next_if(|(segment, _)| *segment == i) {
+            if out.last() != Some(&cut) {
+                out.push(cut);
+            }
+            if split {
+                out.push(Vec2::NAN);
+                out.push(cut);
+            }
+        }
+    }
+    if !split {
+        return out;
+    }
+    // Cuts on a path's ends leave one point pieces behind
+    let mut pieces = Vec::with_capacity(out.len());
+    for piece in out
+        .split(|p| !p.is_finite())
+        .filter(|piece| piece.len() >= 2)
+    {
+        pieces.extend_from_slice(piece);
+        pieces.push(Vec2::NAN);
+    }
+    pieces
+}
+
+fn sort_along_path(path: &[Vec2], intersections: &mut [PathIntersection]) {
+    intersections.sort_by(|a, b| {
+        a.segment.cmp(&b.segment).then_with(|| {
+            let start = path[a.segment];
+            a.point
+                .distance_squared(start)
+                .total_cmp(&b.point.distance_squared(start))
+        })
+    });
+}
+
+/// Cuts `segments` wherever two cross or one ends on another, with every vertex rounded
+/// onto a grid of `snap` units, so borders that meet share the exact same vertex. Stretches
+/// where borders run along each other come out as identical pieces, kept once.
+pub fn node_segments(segments: &[PathSegment], snap: f64, cell_size: f32) -> Vec<PathSegment> {
+    let rounded: Vec<PathSegment> = segments
+        .iter()
+        .map(|seg| PathSegment {
+            start: snap_round(seg.start, snap),
+            end: snap_round(seg.end, snap),
+        })
+        .filter(|seg| seg.start != seg.end)
+        .collect();
+    let mut grid = SpatialGrid::new(cell_size);
+    let ids = grid.load_segments(rounded.iter().copied());
+    grid.wait_for_rebuild();
+    let position: HashMap<SegmentId, usize> =
+        ids.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+
+    let mut cuts: Vec<Vec<DVec2>> = vec![Vec::new(); rounded.len()];
+    for (i, seg) in rounded.iter().enumerate() {
+        // Each crossing is worked out once, so both segments get the very same point
+        for (other, point) in grid.segments_crossing(seg.start, seg.end) {
+            if let Some(&j) = position.get(&other)
+                && j > i
+            {
+                let point = snap_round(point, snap);
+                cuts[i].push(point);
+                cuts[j].push(point);
+            }
+        }
+        // Ends lying on the segment, from borders that stop on it or run along it
+        let middle = (seg.start + seg.end) / 2.0;
+        let reach = seg.start.distance(seg.end) / 2.0 + snap;
+        for other in grid.query_radius(middle, reach) {
+            let Some(other_seg) = grid.get(other) else {
+                continue;
+            };
+            for end in [other_seg.start, other_seg.end] {
+                if closest_point_on_segment(end, seg.start, seg.end).distance(end) <= snap / 2.0 {
+                    cuts[i].push(end);
+                }
+            }
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut out = Vec::with_capacity(rounded.len());
+    for (seg, mut points) in rounded.iter().zip(cuts) {
+        points.push(seg.end);
+        points.sort_by(|a, b| {
+            a.distance_squared(seg.start)
+                .total_cmp(&b.distance_squared(seg.start))
+        });
+        let mut start = seg.start;
+        for end in points {
+            if end == start {
+                continue;
+            }
+            let key = |p: DVec2| (p.x.to_bits(), p.y.to_bits());
+            let (a, b) = (key(start), key(end));
+            if seen.insert((a.min(b), a.max(b))) {
+                out.push(PathSegment { start, end });
+            }
+            start = end;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crossed_lines_split_at_the_crossing() {
+        let stroke = [Vec2::new(0.0, -1.0), Vec2::new(0.0, 1.0)];
+        let line = [
+            Vec2::new(-1.0, 0.0),
+            Vec2::new(1.0, 0.0),
+            Vec2::new(1.0, 2.0),
+        ];
+        let crossings = path_intersections(&stroke, &[&line]);
+        assert_eq!(crossings.len(), 1);
+        let hit = crossings[0];
+        assert_eq!((hit.segment, hit.other_path, hit.other_segment), (0, 0, 0));
+        assert_eq!(hit.point, Vec2::ZERO);
+
+        let split = insert_cuts(&line, &[(hit.other_segment, hit.point)], true);
+        let pieces: Vec<&[Vec2]> = split
+            .split(|p| !p.is_finite())
+            .filter(|p| !p.is_empty())
+            .collect();
+        assert_eq!(
+            pieces,
+            vec![
+                &[Vec2::new(-1.0, 0.0), Vec2::ZERO][..],
+                &[Vec2::ZERO, Vec2::new(1.0, 0.0), Vec2::new(1.0, 2.0)][..],
+            ]
+        );
+
+        let joined = insert_cuts(&stroke, &[(hit.segment, hit.point)], false);
+        assert_eq!(
+            joined,
+            vec![Vec2::new(0.0, -1.0), Vec2::ZERO, Vec2::new(0.0, 1.0)]
+        );
+    }
+
+    #[test]
+    fn noding_cuts_crossings_and_overlaps() {
+        let seg = |a: (f64, f64), b: (f64, f64)| PathSegment {
+            start: DVec2::new(a.0, a.1),
+            end: DVec2::new(b.0, b.1),
+        };
+        let segments = [
+            seg((0.0, 0.0), (4.0, 0.0)),
+            // Crosses the first one
+            seg((2.0, -1.0), (2.0, 1.0)),
+            // Runs along it
+            seg((1.0, 0.0), (3.0, 0.0)),
+            // Only touches its end
+            seg((4.0, 0.0), (4.0, 2.0)),
+        ];
+        let noded = node_segments(&segments, 0.5, 1.0);
+        let mut pieces: Vec<(f64, f64, f64, f64)> = noded
+            .iter()
+            .map(|s| (s.start.x, s.start.y, s.end.x, s.end.y))
+            .collect();
+        pieces.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            pieces,
+            vec![
+                (0.0, 0.0, 1.0, 0.0),
+                (1.0, 0.0, 2.0, 0.0),
+                (2.0, -1.0, 2.0, 0.0),
+                (2.0, 0.0, 2.0, 1.0),
+                (2.0, 0.0, 3.0, 0.0),
+                (3.0, 0.0, 4.0, 0.0),
+                (4.0, 0.0, 4.0, 2.0),
+            ]
+        );
+    }
+}
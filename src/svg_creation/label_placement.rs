@@ -0,0 +1,276 @@
+use bevy::prelude::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::math_utils::{
+    bounding_box, closest_point_on_segment, point_in_polygon, polygon_signed_area,
+};
+
+const MAX_PROBES: usize = 10_000;
+
+/// Where to draw a region's name: `position` is the interior point furthest from any edge,
+/// `clearance` that distance, and `angle` the region's main axis in radians, kept within
+/// ±90° so text never reads upside down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LabelPlacement {
+    pub position: Vec2,
+    pub clearance: f32,
+    pub angle: f32,
+}
+
+/// Signed distance from `point` to the polygon outline: positive inside, negative outside.
+/// The first ring is the outline, any further rings are holes.
+fn signed_distance(point: Vec2, rings: &[Vec<Vec2>]) -> f32 {
+    let mut inside = false;
+    let mut min_dist = f32::INFINITY;
+    for (i, ring) in rings.iter().enumerate() {
+        let in_ring = point_in_polygon(point, ring);
+        if i == 0 {
+            inside = in_ring;
+        } else if in_ring {
+            inside = false;
+        }
+        for j in 0..ring.len() {
+            let a = ring[j];
+            let b = ring[(j + 1) % ring.len()];
+            min_dist = min_dist.min(closest_point_on_segment(point, a, b).distance(point));
+        }
+    }
+    if inside { min_dist } else { -min_dist }
+}
+
+struct Cell {
+    center: Vec2,
+    half: f32,
+    distance: f32,
+    potential: f32,
+}
+
+impl Cell {
+    fn new(center: Vec2, half: f32, rings: &[Vec<Vec2>]) -> Self {
+        let distance = signed_distance(center, rings);
+        Self {
+            center,
+            half,
+            distance,
+            potential: distance + half * std::f32::consts::SQRT_2,
+        }
+    }
+}
+
+impl PartialEq for Cell {
+    fn eq(&self, other: &Self) -> bool {
+        self.potential == other.potential
+    }
+}
+
+impl Eq for Cell {}
+
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Cell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.potential.total_cmp(&other.potential)
+    }
+}
+
+/// Worked out relative to the first vertex, so far from the origin the cross products
+/// don't swamp the f32 sums.
+fn area_centroid(ring: &[Vec2]) -> Option<Vec2> {
+    let origin = *ring.first()?;
+    let (mut twice_area, mut c) = (0.0, Vec2::ZERO);
+    for i in 0..ring.len() {
+        let a = ring[i] - origin;
+        let b = ring[(i + 1) % ring.len()] - origin;
+        let cross = a.perp_dot(b);
+        twice_area += cross;
+        c += (a + b) * cross;
+    }
+    if twice_area.abs() <= f32::EPSILON {
+        return None;
+    }
+    Some(origin + c / (3.0 * twice_area))
+}
+
+/// Polylabel: the interior point furthest from the outline and holes, found to within
+/// `precision` by quad-splitting cells that could still beat the best point so far.
+pub fn pole_of_inaccessibility(rings: &[Vec<Vec2>], precision: f32) -> Option<(Vec2, f32)> {
+    let outline = rings.first()?;
+    let (min, max) = bounding_box(outline)?;
+    let size = max - min;
+    let cell_size = size.x.min(size.y);
+    if cell_size <= 0.0 {
+        return Some((min, 0.0));
+    }
+    let precision = precision.max(cell_size * 1.0e-4);
+
+    let mut queue = BinaryHeap::new();
+    let half = cell_size / 2.0;
+    let mut y = min.y;
+    while y < max.y {
+        let mut x = min.x;
+        while x < max.x {
+            queue.push(Cell::new(Vec2::new(x + half, y + half), half, rings));
+            x += cell_size;
+        }
+        y += cell_size;
+    }
+
+    // Seed with the centroid and the box centre, whichever is further inside
+    let mut best = Cell::new((min + max) / 2.0, 0.0, rings);
+    if let Some(centroid) = area_centroid(outline) {
+        let candidate = Cell::new(centroid, 0.0, rings);
+        if candidate.distance > best.distance {
+            best = candidate;
+        }
+    }
+
+    let mut probes = 0;
+    while let Some(cell) = queue.pop() {
+        if cell.distance > best.distance {
+            best = Cell::new(cell.center, 0.0, rings);
+        }
+        probes += 1;
+        if cell.potential - best.distance <= precision || probes >= MAX_PROBES {
+            continue;
+        }
+        let h = cell.half / 2.0;
+        for offset in [
+            Vec2::new(-h, -h),
+            Vec2::new(h, -h),
+            Vec2::new(-h, h),
+            Vec2::new(h, h),
+        ] {
+            queue.push(Cell::new(cell.center + offset, h, rings));
+        }
+    }
+    Some((best.center, best.distance.max(0.0)))
+}
+
+/// Direction of the outline's long axis, from its second moments of area, as an angle
+/// in (-90°, 90°].
+pub fn label_axis(ring: &[Vec2]) -> f32 {
+    let Some(centroid) = area_centroid(ring) else {
+        return 0.0;
+    };
+    let (mut ixx, mut iyy, mut ixy) = (0.0, 0.0, 0.0);
+    for i in 0..ring.len() {
+        let a = ring[i] - centroid;
+        let b = ring[(i + 1) % ring.len()] - centroid;
+        let cross = a.perp_dot(b);
+        ixx += (a.y * a.y + a.y * b.y + b.y * b.y) * cross;
+        iyy += (a.x * a.x + a.x * b.x + b.x * b.x) * cross;
+        ixy += (a.x * b.y + 2.0 * a.x * a.y + 2.0 * b.x * b.y + b.x * a.y) * cross;
+    }
+    let sign = polygon_signed_area(ring).signum();
+    let (ixx, iyy, ixy) = (ixx * sign / 12.0, iyy * sign / 12.0, ixy * sign / 24.0);
+
+    // Principal axis of the spread of area; half of atan2 already lands in (-90°, 90°]
+    0.5 * (2.0 * ixy).atan2(iyy - ixx)
+}
+
+/// Some point inside the polygon, clear of its holes: the middle of the widest stretch
+/// inside it along the horizontal line through the outline's middle. Much cheaper than the
+/// pole when any inside point will do.
+pub fn interior_point(rings: &[Vec<Vec2>]) -> Option<Vec2> {
+    let (min, max) = bounding_box(rings.first()?)?;
+    let y = (min.y + max.y) / 2.0;
+    let mut xs = Vec::new();
+    for ring in rings {
+        for i in 0..ring.len() {
+            let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+            if (a.y > y) != (b.y > y) {
+                xs.push(a.x + (y - a.y) / (b.y - a.y) * (b.x - a.x));
+            }
+        }
+    }
+    xs.sort_by(f32::total_cmp);
+    // Sorted crossings pair up into the stretches inside, even-odd
+    xs.chunks_exact(2)
+        .max_by(|a, b| (a[1] - a[0]).total_cmp(&(b[1] - b[0])))
+        .map(|span| Vec2::new((span[0] + span[1]) / 2.0, y))
+}
+
+pub fn place_label(rings: &[Vec<Vec2>], precision: f32) -> Option<LabelPlacement> {
+    let (position, clearance) = pole_of_inaccessibility(rings, precision)?;
+    Some(LabelPlacement {
+        position,
+        clearance,
+        angle: label_axis(&rings[0]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(points: &[(f32, f32)]) -> Vec<Vec2> {
+        points.iter().map(|&(x, y)| Vec2::new(x, y)).collect()
+    }
+
+    /// A U with a 10 wide bottom bar and arms; its centroid falls in the notch.
+    fn u_shape() -> Vec<Vec2> {
+        ring(&[
+            (0.0, 0.0),
+            (30.0, 0.0),
+            (30.0, 30.0),
+            (20.0, 30.0),
+            (20.0, 10.0),
+            (10.0, 10.0),
+            (10.0, 30.0),
+            (0.0, 30.0),
+        ])
+    }
+
+    #[test]
+    fn pole_of_a_u_sits_in_a_bottom_corner() {
+        let rings = vec![u_shape()];
+        assert!(signed_distance(area_centroid(&rings[0]).unwrap(), &rings) < 0.0);
+        let placement = place_label(&rings, 0.01).unwrap();
+        assert!(signed_distance(placement.position, &rings) > 0.0);
+        // The widest circle touches both outer walls of a bottom corner and the notch's
+        // corner at (10, 10) or (20, 10): c = sqrt(2) * (10 - c)
+        let expected = 10.0 * std::f32::consts::SQRT_2 / (1.0 + std::f32::consts::SQRT_2);
+        assert!(
+            (placement.clearance - expected).abs() < 0.05,
+            "{}",
+            placement.clearance
+        );
+        assert!(placement.position.y < 10.0);
+    }
+
+    #[test]
+    fn thin_rectangles_read_along_their_length() {
+        let flat = ring(&[(0.0, 0.0), (100.0, 0.0), (100.0, 4.0), (0.0, 4.0)]);
+        assert!(label_axis(&flat).abs() < 1e-3);
+        let upright = ring(&[(0.0, 0.0), (4.0, 0.0), (4.0, 100.0), (0.0, 100.0)]);
+        assert!((label_axis(&upright).abs() - std::f32::consts::FRAC_PI_2).abs() < 1e-3);
+        // Turned 30° about a far-off point, and wound the other way round
+        let turn = Vec2::from_angle(30f32.to_radians());
+        let turned: Vec<Vec2> = flat
+            .iter()
+            .rev()
+            .map(|p| turn.rotate(*p) + Vec2::new(5000.0, 5000.0))
+            .collect();
+        assert!((label_axis(&turned) - 30f32.to_radians()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn interior_point_avoids_notches_and_holes() {
+        let rings = vec![u_shape()];
+        let point = interior_point(&rings).unwrap();
+        assert!(signed_distance(point, &rings) > 0.0);
+
+        let rings = vec![
+            ring(&[(0.0, 0.0), (40.0, 0.0), (40.0, 40.0), (0.0, 40.0)]),
+            ring(&[(10.0, 10.0), (30.0, 10.0), (30.0, 30.0), (10.0, 30.0)]),
+        ];
+        let point = interior_point(&rings).unwrap();
+        assert!(signed_distance(point, &rings) > 0.0);
+        assert!(interior_point(&[]).is_none());
+    }
+}
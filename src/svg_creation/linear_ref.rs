@@ -0,0 +1,220 @@
+use bevy::prelude::*;
+
+use crate::math_utils::closest_point_on_segment;
+
+/// Where a point falls along a polyline: `distance` from the start measured along the line,
+/// the `point` on the line, and the `segment` it's on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinearPosition {
+    pub distance: f32,
+    pub point: Vec2,
+    pub segment: usize,
+}
+
+pub fn polyline_length(path: &[Vec2]) -> f32 {
+    path.windows(2).map(|w| w[0].distance(w[1])).sum()
+}
+
+#[allow(dead_code)]
+pub fn ring_length(ring: &[Vec2]) -> f32 {
+    if ring.len() < 2 {
+        return 0.0;
+    }
+    polyline_length(ring) + ring[ring.len() - 1].distance(ring[0])
+}
+
+/// The point `distance` along the line, clamped to its ends.
+#[allow(dead_code)]
+pub fn point_at_distance(path: &[Vec2], distance: f32) -> Option<Vec2> {
+    let first = *path.first()?;
+    if distance <= 0.0 {
+        return Some(first);
+    }
+    let mut walked = 0.0;
+    for w in path.windows(2) {
+        let len = w[0].distance(w[1]);
+        if walked + len >= distance && len > 0.0 {
+            return Some(w[0].lerp(w[1], (distance - walked) / len));
+        }
+        walked += len;
+    }
+    path.last().copied()
+}
+
+/// Projects `point` on to the nearest spot of the line.
+#[allow(dead_code)]
+pub fn locate_point(path: &[Vec2], point: Vec2) -> Option<LinearPosition> {
+    if path.len() < 2 {
+        return path.first().map(|&p| LinearPosition {
+            distance: 0.0,
+            point: p,
+            segment: 0,
+        });
+    }
+    let mut best: Option<(f32, LinearPosition)> = None;
+    let mut walked = 0.0;
+    for (segment, w) in path.windows(2).enumerate() {
+        let on_line = closest_point_on_segment(point, w[0], w[1]);
+        let offset = on_line.distance_squared(point);
+        if best.is_none_or(|(best_offset, _)| offset < best_offset) {
+            best = Some((
+                offset,
+                LinearPosition {
+                    distance: walked + w[0].distance(on_line),
+                    point: on_line,
+                    segment,
+                },
+            ));
+        }
+        walked += w[0].distance(w[1]);
+    }
+    best.map(|(_, position)| position)
+}
+
+/// The stretch of the line between two distances along it, in order of travel.
+#[allow(dead_code)]
+pub fn substring(path: &[Vec2], from: f32, to: f32) -> Vec<Vec2> {
+    let (from, to) = (from.min(to), from.max(to));
+    let mut out = Vec::new();
+    let Some(start) = point_at_distance(path, from) else {
+        return out;
+    };
+    out.push(start);
+    let mut walked = 0.0;
+    for w in path.windows(2) {
+        walked += w[0].distance(w[1]);
+        if walked > from && walked < to {
+            out.push(w[1]);
+        }
+    }
+    if let Some(end) = point_at_distance(path, to)
+        && out.last() != Some(&end)
+    {
+        out.push(end);
+    }
+    out
+}
+
+/// Length of the outline two rings have in common: the parts of `a`'s edges that run along
+/// an edge of `b` within `tolerance`. Overlaps are merged per edge, so nothing counts twice.
+#[allow(dead_code)]
+pub fn shared_border_length(a: &[Vec2], b: &[Vec2], tolerance: f32) -> f32 {
+    if a.len() < 2 || b.len() < 2 {
+        return 0.0;
+    }
+    let mut total = 0.0;
+    let mut spans: Vec<(f32, f32)> = Vec::new();
+    for i in 0..a.len() {
+        let (p, q) = (a[i], a[(i + 1) % a.len()]);
+        let len = p.distance(q);
+        if len == 0.0 {
+            continue;
+        }
+        let dir = (q - p) / len;
+        spans.clear();
+        for j in 0..b.len() {
+            let (r, s) = (b[j], b[(j + 1) % b.len()]);
+            // Both ends of b's edge have to lie on a's edge line for the two to run together
+            if dir.perp_dot(r - p).abs() > tolerance || dir.perp_dot(s - p).abs() > tolerance {
+                continue;
+            }
+            let (tr, ts) = (dir.dot(r - p), dir.dot(s - p));
+            let (lo, hi) = (tr.min(ts).max(0.0), tr.max(ts).min(len));
+            if hi > lo {
+                spans.push((lo, hi));
+            }
+        }
+        spans.sort_by(|x, y| x.0.total_cmp(&y.0));
+        let mut reach = f32::NEG_INFINITY;
+        for &(lo, hi) in &spans {
+            let lo = lo.max(reach);
+            if hi > lo {
+                total += hi - lo;
+            }
+            reach = reach.max(hi);
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn l_shape() -> Vec<Vec2> {
+        vec![
+            Vec2::new(0.0, 0.0),
+            Vec2::new(10.0, 0.0),
+            Vec2::new(10.0, 5.0),
+        ]
+    }
+
+    #[test]
+    fn point_at_distance_walks_the_line_and_clamps_to_its_ends() {
+        let path = l_shape();
+        assert_eq!(point_at_distance(&path, -1.0), Some(Vec2::new(0.0, 0.0)));
+        assert_eq!(point_at_distance(&path, 4.0), Some(Vec2::new(4.0, 0.0)));
+        assert_eq!(point_at_distance(&path, 12.0), Some(Vec2::new(10.0, 2.0)));
+        assert_eq!(point_at_distance(&path, 100.0), Some(Vec2::new(10.0, 5.0)));
+        assert_eq!(point_at_distance(&[], 1.0), None);
+    }
+
+    #[test]
+    fn locate_point_inverts_point_at_distance() {
+        let path = l_shape();
+        let position = locate_point(&path, Vec2::new(12.0, 3.0)).unwrap();
+        assert_eq!(position.point, Vec2::new(10.0, 3.0));
+        assert_eq!(position.segment, 1);
+        assert!((position.distance - 13.0).abs() < 1e-5);
+        for distance in [0.0, 2.5, 10.0, 14.0] {
+            let point = point_at_distance(&path, distance).unwrap();
+            let position = locate_point(&path, point).unwrap();
+            assert!((position.distance - distance).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn substring_keeps_the_corners_in_between() {
+        let path = l_shape();
+        assert_eq!(
+            substring(&path, 13.0, 5.0),
+            vec![
+                Vec2::new(5.0, 0.0),
+                Vec2::new(10.0, 0.0),
+                Vec2::new(10.0, 3.0)
+            ]
+        );
+        assert_eq!(
+            substring(&path, 2.0, 6.0),
+            vec![Vec2::new(2.0, 0.0), Vec2::new(6.0, 0.0)]
+        );
+        assert!((polyline_length(&substring(&path, 0.0, 15.0)) - 15.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn shared_border_length_counts_only_the_common_edge_once() {
+        let square = |x: f32| {
+            vec![
+                Vec2::new(x, 0.0),
+                Vec2::new(x + 10.0, 0.0),
+                Vec2::new(x + 10.0, 10.0),
+                Vec2::new(x, 10.0),
+            ]
+        };
+        // Squares side by side share the edge x = 10
+        assert!((shared_border_length(&square(0.0), &square(10.0), 1e-3) - 10.0).abs() < 1e-4);
+        // Offset by half, they share half of it
+        let shifted: Vec<Vec2> = square(10.0)
+            .iter()
+            .map(|p| *p + Vec2::new(0.0, 5.0))
+            .collect();
+        assert!((shared_border_length(&square(0.0), &shifted, 1e-3) - 5.0).abs() < 1e-4);
+        // An edge of b split in two still covers a's edge only once
+        let mut split = square(10.0);
+        split.insert(0, Vec2::new(10.0, 5.0));
+        split.push(Vec2::new(10.0, 2.0));
+        assert!((shared_border_length(&square(0.0), &split, 1e-3) - 10.0).abs() < 1e-4);
+        assert_eq!(shared_border_length(&square(0.0), &square(30.0), 1e-3), 0.0);
+        assert!((ring_length(&square(0.0)) - 40.0).abs() < 1e-5);
+    }
+}
@@ -1,4 +1,7 @@
 use bevy::prelude::*;
+use bevy::tasks::{ComputeTaskPool, ParallelSlice, TaskPool};
+
+use crate::geometry64;
 
 pub fn closest_point_on_segment(p: Vec2, a: Vec2, b: Vec2) -> Vec2 {
     let ab = b - a;
@@ -10,39 +13,15 @@ pub fn closest_point_on_segment(p: Vec2, a: Vec2, b: Vec2) -> Vec2 {
     else { a + ab * t }
 }
 
-pub fn lines_intersect(p1: Vec2, p2: Vec2, q1: Vec2, q2: Vec2) -> bool {
-    let det = (p2.x - p1.x) * (q2.y - q1.y) - (p2.y - p1.y) * (q2.x - q1.x);
-    if det.abs() < f32::EPSILON { return false; }
-    let u = ((q1.x - p1.x) * (q2.y - q1.y) - (q1.y - p1.y) * (q2.x - q1.x)) / det;
-    let v = ((q1.x - p1.x) * (p2.y - p1.y) - (q1.y - p1.y) * (p2.x - p1.x)) / det;
-    u >= 0.0 && u <= 1.0 && v >= 0.0 && v <= 1.0
-}
-
+/// f32 front for `geometry64::segment_intersection_point`; the math itself runs in f64.
 pub fn segment_intersection_point(p1: Vec2, p2: Vec2, q1: Vec2, q2: Vec2) -> Option<Vec2> {
-    let s1 = p2 - p1;
-    let s2 = q2 - q1;
-    let denom = -s2.x * s1.y + s1.x * s2.y;
-    if denom.abs() < f32::EPSILON {
-        return None;
-    }
-    let s = (-s1.y * (p1.x - q1.x) + s1.x * (p1.y - q1.y)) / denom;
-    let t = ( s2.x * (p1.y - q1.y) - s2.y * (p1.x - q1.x)) / denom;
-    if s >= 0.0 && s <= 1.0 && t >= 0.0 && t <= 1.0 {
-        Some(Vec2::new(p1.x + (t * s1.x), p1.y + (t * s1.y)))
-    } else {
-        None
-    }
-}
-
-pub fn paths_intersect(path1: &[Vec2], path2: &[Vec2]) -> bool {
-    for seg1 in path1.windows(2) {
-        for seg2 in path2.windows(2) {
-            if lines_intersect(seg1[0], seg1[1], seg2[0], seg2[1]) {
-                return true;
-            }
-        }
-    }
-    false
+    geometry64::segment_intersection_point(
+        geometry64::to_world(p1),
+        geometry64::to_world(p2),
+        geometry64::to_world(q1),
+        geometry64::to_world(q2),
+    )
+    .map(geometry64::to_render)
 }
 
 pub fn bounding_box(points: &[Vec2]) -> Option<(Vec2, Vec2)> {
@@ -73,33 +52,84 @@ pub fn smooth_lines(path: &[Vec2], window: usize) -> Vec<Vec2> {
     smoothed
 }
 
+/// Douglas-Peucker with an explicit stack, so very long paths can't overflow the call stack.
 pub fn ramer_douglas_peucker(path: &[Vec2], epsilon: f32) -> Vec<Vec2> {
     if path.len() < 2 {
         return path.to_vec();
     }
+    let mut keep = Vec::new();
+    let mut stack = Vec::new();
+    ramer_douglas_peucker_mask(path, epsilon, &mut keep, &mut stack);
+    path.iter()
+        .zip(&keep)
+        .filter_map(|(p, &kept)| kept.then_some(*p))
+        .collect()
+}
+
+/// Marks the points Douglas-Peucker keeps in `keep`. Both buffers are reused between calls.
+pub fn ramer_douglas_peucker_mask(
+    path: &[Vec2],
+    epsilon: f32,
+    keep: &mut Vec<bool>,
+    stack: &mut Vec<(usize, usize)>,
+) {
+    keep.clear();
+    keep.resize(path.len(), false);
+    stack.clear();
+    if path.len() < 2 {
+        keep.iter_mut().for_each(|k| *k = true);
+        return;
+    }
+    let last = path.len() - 1;
+    keep[0] = true;
+    keep[last] = true;
+    stack.push((0, last));
 
-    let (start, end) = (path[0], path[path.len() - 1]);
-    let mut max_dist = 0.0;
-    let mut max_index = 0;
+    while let Some((first, last)) = stack.pop() {
+        let (start, end) = (path[first], path[last]);
+        let mut max_dist = 0.0;
+        let mut max_index = first;
 
-    for i in 1..path.len() - 1 {
-        let point = path[i];
-        let dist = perpendicular_distance(point, start, end);
-        if dist > max_dist {
-            max_dist = dist;
-            max_index = i;
+        for (i, &point) in path.iter().enumerate().take(last).skip(first + 1) {
+            let dist = perpendicular_distance(point, start, end);
+            if dist > max_dist {
+                max_dist = dist;
+                max_index = i;
+            }
+        }
+
+        if max_dist > epsilon && max_index != first {
+            keep[max_index] = true;
+            stack.push((max_index, last));
+            stack.push((first, max_index));
         }
     }
+}
 
-    if max_dist > epsilon {
-        let mut left = ramer_douglas_peucker(&path[..=max_index], epsilon);
-        let right = ramer_douglas_peucker(&path[max_index..], epsilon);
-        left.pop();
-        left.extend(right);
-        left
-    } else {
-        vec![start, end]
+/// Simplifies many paths, optionally spread over the compute task pool.
+pub fn ramer_douglas_peucker_many(paths: &[Vec<Vec2>], epsilon: f32, parallel: bool) -> Vec<Vec<Vec2>> {
+    if !parallel {
+        return paths.iter().map(|path| ramer_douglas_peucker(path, epsilon)).collect();
     }
+    let pool = ComputeTaskPool::get_or_init(TaskPool::default);
+    paths
+        .par_splat_map(pool, None, |_, chunk| {
+            let mut keep = Vec::new();
+            let mut stack = Vec::new();
+            chunk
+                .iter()
+                .map(|path| {
+                    ramer_douglas_peucker_mask(path, epsilon, &mut keep, &mut stack);
+                    path.iter()
+                        .zip(&keep)
+                        .filter_map(|(p, &kept)| kept.then_some(*p))
+                        .collect::<Vec<Vec2>>()
+                })
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+        .flatten()
+        .collect()
 }
 
 fn perpendicular_distance(point: Vec2, line_start: Vec2, line_end: Vec2) -> f32 {
@@ -112,3 +142,91 @@ fn perpendicular_distance(point: Vec2, line_start: Vec2, line_end: Vec2) -> f32
         (point - proj).length()
     }
 }
+
+pub fn polygon_signed_area(ring: &[Vec2]) -> f32 {
+    if ring.len() < 3 { return 0.0; }
+    let mut area = 0.0;
+    for i in 0..ring.len() {
+        let a = ring[i];
+        let b = ring[(i + 1) % ring.len()];
+        area += a.x * b.y - b.x * a.y;
+    }
+    area * 0.5
+}
+
+pub fn point_in_polygon(point: Vec2, ring: &[Vec2]) -> bool {
+    let mut inside = false;
+    let mut j = ring.len().wrapping_sub(1);
+    for i in 0..ring.len() {
+        let (a, b) = (ring[i], ring[j]);
+        if (a.y > point.y) != (b.y > point.y)
+            && point.x < (b.x - a.x) * (point.y - a.y) / (b.y - a.y) + a.x
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The textbook recursive version the iterative one has to agree with.
+    fn ramer_douglas_peucker_recursive(path: &[Vec2], epsilon: f32) -> Vec<Vec2> {
+        if path.len() < 3 {
+            return path.to_vec();
+        }
+        let last = path.len() - 1;
+        let mut max_dist = 0.0;
+        let mut max_index = 0;
+        for (i, &point) in path.iter().enumerate().take(last).skip(1) {
+            let dist = perpendicular_distance(point, path[0], path[last]);
+            if dist > max_dist {
+                max_dist = dist;
+                max_index = i;
+            }
+        }
+        if max_dist <= epsilon {
+            return vec![path[0], path[last]];
+        }
+        let mut left = ramer_douglas_peucker_recursive(&path[..=max_index], epsilon);
+        left.pop();
+        left.extend(ramer_douglas_peucker_recursive(&path[max_index..], epsilon));
+        left
+    }
+
+    #[test]
+    fn iterative_douglas_peucker_matches_recursive() {
+        let mut seed = 7u32;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32
+        };
+        let paths: Vec<Vec<Vec2>> = (0..20)
+            .map(|n| {
+                let mut p = Vec2::ZERO;
+                (0..50 + n * 30)
+                    .map(|_| {
+                        p += Vec2::new(next(), next() * 2.0 - 1.0);
+                        p
+                    })
+                    .collect()
+            })
+            .collect();
+
+        for epsilon in [0.0, 0.1, 0.5, 2.0] {
+            let expected: Vec<Vec<Vec2>> = paths
+                .iter()
+                .map(|path| ramer_douglas_peucker_recursive(path, epsilon))
+                .collect();
+            for (path, expected) in paths.iter().zip(&expected) {
+                assert_eq!(&ramer_douglas_peucker(path, epsilon), expected);
+            }
+            assert_eq!(ramer_douglas_peucker_many(&paths, epsilon, true), expected);
+        }
+    }
+}
@@ -1,11 +1,20 @@
+use bevy::math::DVec2;
 use bevy::prelude::*;
+use std::collections::BTreeMap;
 
 use crate::{
     svg_utils::{draw_svg, save_svg},
-    math_utils::{paths_intersect, smooth_lines, ramer_douglas_peucker},
-    draw_state::{DrawingInfo, DrawingPoints}
+    intersections::{insert_cuts, path_intersections},
+    curve_fit::{CubicBezier, SmoothingConfig, smooth_stroke},
+    draw_state::{DrawingInfo, DrawingPoints},
+    geometry64::{to_render, to_world},
+    simplify::{SimplifyPath, simplify_preserving_topology},
+    spatial_grid::SpatialGrid,
 };
 
+/// Corners of a polyline stroke cutting off less than this area are simplified away.
+const SIMPLIFY_MIN_AREA: f64 = 0.1;
+
 #[derive(Component)]
 pub struct SvgLine {
     pub path: Vec<Vec2>,
@@ -21,55 +30,129 @@ pub struct MergeSvgPlugin;
 impl Plugin for MergeSvgPlugin {
     fn build(&self, app: &mut App) {
         app
-            .insert_resource(SvgLibrary::default());
+            .insert_resource(SvgLibrary::default())
+            .insert_resource(SmoothingConfig::default());
     }
 }
 
 fn filter_close_points(points: &[Vec2], min_dist: f32) -> Vec<Vec2> {
     let mut out = Vec::new();
     for &pt in points {
-        if out.last().map_or(true, |last| pt.distance(*last) > min_dist) {
+        if out.last().is_none_or(|last| pt.distance(*last) > min_dist) {
             out.push(pt);
         }
     }
     out
 }
 
+/// Drops near-collinear points without letting the pieces, separated by NaN points, cross
+/// each other or jump over any border. Ends are kept, so anchors stay where they were welded.
+pub fn simplify_stroke(path: &[Vec2], spatial_grid: &SpatialGrid) -> Vec<Vec2> {
+    let paths: Vec<SimplifyPath> = path
+        .split(|p| !p.is_finite())
+        .map(|piece| SimplifyPath {
+            points: piece.iter().map(|p| to_world(*p)).collect(),
+            closed: false,
+        })
+        .collect();
+    let pieces: Vec<Vec<Vec2>> = simplify_preserving_topology(&paths, SIMPLIFY_MIN_AREA, spatial_grid)
+        .into_iter()
+        .map(|path| path.points.into_iter().map(to_render).collect())
+        .collect();
+    pieces.join(&Vec2::NAN)
+}
+
+/// Smooths a finished stroke. A stroke split at its own crossings comes as NaN separated
+/// pieces; each one is smoothed on its own with its ends kept, so the pieces still meet
+/// exactly and the ends stay on the borders they were welded to. Returns the flattened
+/// polyline, which is what goes into the grid, and the curves to write out, kept only for
+/// a stroke in one piece.
+pub fn smooth_pieces(points: &[Vec2], smoothing: &SmoothingConfig) -> (Vec<Vec2>, Vec<CubicBezier>) {
+    let pieces: Vec<&[Vec2]> = points
+        .split(|p| !p.is_finite())
+        .filter(|piece| piece.len() >= 2)
+        .collect();
+    let mut path = Vec::new();
+    let mut curves = Vec::new();
+    for piece in &pieces {
+        let (mut line, mut piece_curves) = smooth_stroke(&filter_close_points(piece, 1.0), smoothing);
+        if line.len() < 2 {
+            line = vec![piece[0], piece[piece.len() - 1]];
+        }
+        let last_idx = line.len() - 1;
+        line[0] = piece[0];
+        line[last_idx] = piece[piece.len() - 1];
+        if let Some(first) = piece_curves.first_mut() {
+            first.p0 = line[0];
+        }
+        if let Some(last) = piece_curves.last_mut() {
+            last.p3 = line[last_idx];
+        }
+        if !path.is_empty() {
+            path.push(Vec2::NAN);
+        }
+        path.extend(line);
+        if pieces.len() == 1 {
+            curves = piece_curves;
+        }
+    }
+    (path, curves)
+}
+
+/// Stores a finished stroke in the grid. Wherever it crosses a stored border both get a
+/// vertex, the border split in the grid like `check_and_merge_svg` cuts the lines it
+/// crosses, so the faces the stroke closes off show up in the topology. NaN points separate
+/// pieces.
+pub fn merge_into_grid(spatial_grid: &mut SpatialGrid, stroke: &[DVec2]) {
+    let mut noded: Vec<DVec2> = Vec::with_capacity(stroke.len());
+    for (i, &point) in stroke.iter().enumerate() {
+        if i > 0 && point.is_finite() && stroke[i - 1].is_finite() {
+            for (_, crossing) in spatial_grid.segments_crossing(stroke[i - 1], point) {
+                let vertex = spatial_grid.weld_point(crossing);
+                if noded.last() != Some(&vertex) && vertex != point {
+                    noded.push(vertex);
+                }
+            }
+        }
+        noded.push(point);
+    }
+    for pair in noded.windows(2) {
+        if pair[0].is_finite() && pair[1].is_finite() {
+            spatial_grid.insert_segment(pair[0], pair[1]);
+        }
+    }
+}
+
 pub fn check_and_merge_svg(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
     drawing_info: &mut ResMut<DrawingInfo>,
     drawing_points: &mut ResMut<DrawingPoints>,
     svg_library: &mut ResMut<SvgLibrary>,
-) -> Vec<Vec2> {
-    let window_size = 3; // Try stronger smoothing, might look funny
-    let epsilon = 0.2;
-    let original_points = drawing_points.points.clone();
-    let filtered_points = filter_close_points(&drawing_points.points, 1.0);
-    let smoothed_path = smooth_lines(&filtered_points, window_size);
-    let simplified = ramer_douglas_peucker(&smoothed_path, epsilon);
-
-    let final_path = if !simplified.is_empty() { simplified } else { smoothed_path.clone() };
-
-    if !original_points.is_empty() && final_path.len() >= 2 {
-        let mut path = final_path.clone();
-        path[0] = original_points[0];
-        let last_idx = path.len() - 1;
-        path[last_idx] = original_points[original_points.len() - 1];
+) {
+    let path = drawing_points.points.clone();
+    if !path.is_empty() {
         drawing_points.points = path.clone();
-        let mut new_path = path.clone();
+
+        // Lines the stroke crosses are merged in, cut where it crosses them so the crossing
+        // is a vertex of both
+        let line_paths: Vec<&[Vec2]> = svg_library.lines.iter().map(|(_, line)| line.path.as_slice()).collect();
+        let mut stroke_cuts = Vec::new();
+        let mut line_cuts: BTreeMap<usize, Vec<(usize, Vec2)>> = BTreeMap::new();
+        for crossing in path_intersections(&path, &line_paths) {
+            stroke_cuts.push((crossing.segment, crossing.point));
+            line_cuts.entry(crossing.other_path).or_default().push((crossing.other_segment, crossing.point));
+        }
+        let mut new_path = insert_cuts(&path, &stroke_cuts, false);
         new_path.push(Vec2::new(f32::NAN, f32::NAN));
         let mut entities_to_despawn = Vec::new();
-
-        for (entity, line) in &svg_library.lines {
-            if paths_intersect(&path, &line.path) {
-                new_path.extend(line.path.clone());
-                new_path.push(Vec2::new(f32::NAN, f32::NAN));
-                entities_to_despawn.push(*entity);
-                new_path.push(Vec2::new(f32::NAN, f32::NAN));
-            }
+        for (index, cuts) in &line_cuts {
+            let (entity, line) = &svg_library.lines[*index];
+            new_path.extend(insert_cuts(&line.path, cuts, true));
+            entities_to_despawn.push(*entity);
         }
 
+        svg_library.lines.retain(|(entity, _)| !entities_to_despawn.contains(entity));
         for entity in entities_to_despawn {
             if commands.get_entity(entity).is_some() {
                 commands.entity(entity).despawn();
@@ -97,17 +180,13 @@ pub fn check_and_merge_svg(
             },
         ));
 
-        let grid_path: Vec<Vec2> = new_path.iter().cloned().filter(|p| p.is_finite()).collect();
-
         drawing_points.points.clear();
+        drawing_points.curves.clear();
         drawing_info.last_pos = None;
         drawing_info.counter += 1;
-
-        return grid_path;
     } else {
         drawing_points.points.clear();
         drawing_info.last_pos = None;
         drawing_info.counter += 1;
-        return Vec::new();
     }
 }
@@ -1,7 +1,25 @@
 pub mod merge_svg;
 pub mod svg_utils;
 pub mod math_utils;
+pub mod geometry64;
+pub mod curve_fit;
+pub mod simplify;
+pub mod intersections;
+pub mod self_intersection;
+pub mod offset;
+pub mod clip_mask;
+pub mod label_placement;
+pub mod linear_ref;
+pub mod triangulate;
+pub mod spatial_index;
+pub mod index_cache;
+pub mod uniform_grid;
+pub mod quadtree;
+pub mod rtree;
 pub mod spatial_grid;
+pub mod topology;
+pub mod region;
+pub mod region_graph;
 
 pub mod draw;
 pub use draw::*;
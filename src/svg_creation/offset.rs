@@ -0,0 +1,266 @@
+use bevy::prelude::*;
+use std::f32::consts::PI;
+
+use crate::math_utils::polygon_signed_area;
+
+const INNER_MITER_LIMIT: f32 = 10.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(dead_code)]
+pub enum JoinStyle {
+    /// Sharp corners, falling back to a bevel past `limit` times the offset distance.
+    Miter {
+        limit: f32,
+    },
+    /// Arcs made of segments no further than `tolerance` from the true circle.
+    Round {
+        tolerance: f32,
+    },
+    Bevel,
+}
+
+impl Default for JoinStyle {
+    fn default() -> Self {
+        JoinStyle::Miter { limit: 4.0 }
+    }
+}
+
+fn dedup_points(points: &[Vec2], closed: bool) -> Vec<Vec2> {
+    let mut out: Vec<Vec2> = Vec::with_capacity(points.len());
+    for &p in points {
+        if out
+            .last()
+            .is_none_or(|last| last.distance(p) > f32::EPSILON)
+        {
+            out.push(p);
+        }
+    }
+    if closed {
+        while out.len() > 1 && out[0].distance(out[out.len() - 1]) <= f32::EPSILON {
+            out.pop();
+        }
+    }
+    out
+}
+
+fn arc_steps(radius: f32, sweep: f32, tolerance: f32) -> usize {
+    let tolerance = tolerance.clamp(1.0e-3, radius.max(1.0e-3));
+    let step = 2.0
+        * (1.0 - tolerance / radius.max(1.0e-3))
+            .clamp(-1.0, 1.0)
+            .acos();
+    if step <= f32::EPSILON {
+        return 1;
+    }
+    ((sweep.abs() / step).ceil() as usize).clamp(1, 256)
+}
+
+/// Pushes the offset corner at `corner` between the segment arriving with direction `d0`
+/// and the one leaving with `d1`. Offsets are to the left of the direction of travel.
+fn push_join(
+    out: &mut Vec<Vec2>,
+    corner: Vec2,
+    d0: Vec2,
+    d1: Vec2,
+    distance: f32,
+    join: JoinStyle,
+) {
+    let n0 = d0.perp() * distance;
+    let n1 = d1.perp() * distance;
+    if d0.dot(d1) > 1.0 - 1.0e-6 {
+        out.push(corner + n0);
+        return;
+    }
+
+    // Where the two offset edges meet, as a distance along the corner's bisector
+    let bisector = (n0 + n1).normalize_or_zero();
+    let cos_half = bisector.dot(n0.normalize_or_zero());
+    let miter_length = if cos_half > f32::EPSILON {
+        distance.abs() / cos_half
+    } else {
+        f32::INFINITY
+    };
+
+    // Inner side of the turn: the offset edges overlap, so cut them where they meet. Near
+    // hairpins that point runs away, so go through the corner and leave a fold instead
+    if d0.perp_dot(d1) * distance > 0.0 {
+        if miter_length <= INNER_MITER_LIMIT * distance.abs() {
+            out.push(corner + bisector * miter_length);
+        } else {
+            out.push(corner + n0);
+            out.push(corner);
+            out.push(corner + n1);
+        }
+        return;
+    }
+
+    match join {
+        JoinStyle::Bevel => {
+            out.push(corner + n0);
+            out.push(corner + n1);
+        }
+        JoinStyle::Miter { limit } => {
+            if miter_length <= limit * distance.abs() {
+                out.push(corner + bisector * miter_length);
+            } else {
+                out.push(corner + n0);
+                out.push(corner + n1);
+            }
+        }
+        JoinStyle::Round { tolerance } => {
+            let start = n0.to_angle();
+            let mut sweep = n1.to_angle() - start;
+            if sweep > PI {
+                sweep -= 2.0 * PI;
+            } else if sweep < -PI {
+                sweep += 2.0 * PI;
+            }
+            let steps = arc_steps(distance.abs(), sweep, tolerance);
+            for k in 0..=steps {
+                let angle = start + sweep * k as f32 / steps as f32;
+                out.push(corner + Vec2::from_angle(angle) * distance.abs());
+            }
+        }
+    }
+}
+
+/// Offsets a ring outward (positive `distance`) or inward (negative). Winding doesn't matter.
+/// Inward offsets of narrow parts can fold over; the result keeps those folds, so run
+/// `remove_loops` from `self_intersection` on it if a simple ring is needed.
+pub fn offset_polygon(ring: &[Vec2], distance: f32, join: JoinStyle) -> Vec<Vec2> {
+    let ring = dedup_points(ring, true);
+    if ring.len() < 3 || distance == 0.0 {
+        return ring;
+    }
+    // Work on a counter-clockwise ring so that the left side is the outside
+    let ccw = polygon_signed_area(&ring) > 0.0;
+    let ring: Vec<Vec2> = if ccw {
+        ring
+    } else {
+        ring.into_iter().rev().collect()
+    };
+    let offset = -distance;
+
+    let n = ring.len();
+    let mut out = Vec::with_capacity(n * 2);
+    for i in 0..n {
+        let prev = ring[(i + n - 1) % n];
+        let corner = ring[i];
+        let next = ring[(i + 1) % n];
+        let d0 = (corner - prev).normalize_or_zero();
+        let d1 = (next - corner).normalize_or_zero();
+        push_join(&mut out, corner, d0, d1, offset, join);
+    }
+    dedup_points(&out, true)
+}
+
+/// Offsets an open polyline sideways. Positive `distance` is to the left of the direction
+/// of travel.
+#[allow(dead_code)]
+pub fn offset_polyline(path: &[Vec2], distance: f32, join: JoinStyle) -> Vec<Vec2> {
+    let path = dedup_points(path, false);
+    if path.len() < 2 || distance == 0.0 {
+        return path;
+    }
+    let n = path.len();
+    let mut out = Vec::with_capacity(n * 2);
+    out.push(path[0] + (path[1] - path[0]).normalize_or_zero().perp() * distance);
+    for i in 1..n - 1 {
+        let d0 = (path[i] - path[i - 1]).normalize_or_zero();
+        let d1 = (path[i + 1] - path[i]).normalize_or_zero();
+        push_join(&mut out, path[i], d0, d1, distance, join);
+    }
+    out.push(path[n - 1] + (path[n - 1] - path[n - 2]).normalize_or_zero().perp() * distance);
+    dedup_points(&out, false)
+}
+
+/// Closed outline of everything within `distance` of the polyline, with round or square
+/// (bevel/miter) caps. Good for "within N units of the frontier" zones and thick borders.
+#[allow(dead_code)]
+pub fn buffer_polyline(path: &[Vec2], distance: f32, join: JoinStyle) -> Vec<Vec2> {
+    let path = dedup_points(path, false);
+    let distance = distance.abs();
+    if path.len() < 2 || distance == 0.0 {
+        return path;
+    }
+    let left = offset_polyline(&path, distance, join);
+    let reversed: Vec<Vec2> = path.iter().rev().copied().collect();
+    let right = offset_polyline(&reversed, distance, join);
+
+    let mut out = left;
+    push_cap(
+        &mut out,
+        path[path.len() - 1],
+        path[path.len() - 1] - path[path.len() - 2],
+        distance,
+        join,
+    );
+    out.extend(right);
+    push_cap(&mut out, path[0], path[0] - path[1], distance, join);
+    // Left side out, right side back makes a clockwise ring; keep rings counter-clockwise
+    out.reverse();
+    dedup_points(&out, true)
+}
+
+fn push_cap(out: &mut Vec<Vec2>, end: Vec2, direction: Vec2, distance: f32, join: JoinStyle) {
+    let d = direction.normalize_or_zero();
+    match join {
+        JoinStyle::Round { tolerance } => {
+            let start = d.perp().to_angle();
+            let steps = arc_steps(distance, PI, tolerance);
+            for k in 1..steps {
+                let angle = start - PI * k as f32 / steps as f32;
+                out.push(end + Vec2::from_angle(angle) * distance);
+            }
+        }
+        JoinStyle::Miter { .. } => {
+            out.push(end + (d.perp() + d) * distance);
+            out.push(end + (-d.perp() + d) * distance);
+        }
+        JoinStyle::Bevel => {}
+    }
+}
+
+/// The band between a ring and its inward offset, as an outer ring and a hole. Used for
+/// borders drawn thick but kept inside the region that owns them.
+pub fn inner_band(ring: &[Vec2], width: f32, join: JoinStyle) -> (Vec<Vec2>, Vec<Vec2>) {
+    (ring.to_vec(), offset_polygon(ring, -width.abs(), join))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn square_offsets_follow_the_join_style() {
+        let square = [
+            Vec2::new(0.0, 0.0),
+            Vec2::new(10.0, 0.0),
+            Vec2::new(10.0, 10.0),
+            Vec2::new(0.0, 10.0),
+        ];
+        let area = |ring: &[Vec2]| polygon_signed_area(ring).abs();
+
+        let miter = offset_polygon(&square, 1.0, JoinStyle::default());
+        assert_eq!(miter.len(), 4);
+        assert!(
+            miter
+                .iter()
+                .any(|p| p.distance(Vec2::new(-1.0, -1.0)) < 1.0e-4)
+        );
+        assert!((area(&miter) - 144.0).abs() < 1.0e-3);
+
+        let bevel = offset_polygon(&square, 1.0, JoinStyle::Bevel);
+        assert_eq!(bevel.len(), 8);
+        assert!((area(&bevel) - 142.0).abs() < 1.0e-3);
+
+        let round = offset_polygon(&square, 1.0, JoinStyle::Round { tolerance: 0.01 });
+        assert!((area(&round) - (140.0 + PI)).abs() < 0.05);
+
+        // Inner corners are always cut where the offset edges meet
+        let (outer, inner) = inner_band(&square, 1.0, JoinStyle::Bevel);
+        assert_eq!(outer, square);
+        assert_eq!(inner.len(), 4);
+        assert!((area(&inner) - 64.0).abs() < 1.0e-3);
+    }
+}
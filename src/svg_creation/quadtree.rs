@@ -0,0 +1,380 @@
+use bevy::math::DVec2;
+use bevy::prelude::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::geometry64::to_render;
+use crate::index_cache::{
+    CacheReader, CacheWriter, is_tree, read_boxes, read_node_index, write_boxes,
+};
+use crate::spatial_grid::PathSegment;
+use crate::spatial_index::{
+    Slot, SpatialIndex, SpatialIndexKind, box_distance, boxes_overlap, segment_box,
+};
+
+/// A node splits once it holds more segments than this.
+const NODE_CAPACITY: usize = 16;
+/// A fresh root is at least this many minimum node sizes across.
+const ROOT_CELLS: f64 = 64.0;
+
+type Entry = (Slot, DVec2, DVec2);
+
+struct QuadNode {
+    min: DVec2,
+    max: DVec2,
+    entries: Vec<Entry>,
+    children: Option<[usize; 4]>,
+}
+
+impl QuadNode {
+    fn new(min: DVec2, max: DVec2) -> Self {
+        Self {
+            min,
+            max,
+            entries: Vec::new(),
+            children: None,
+        }
+    }
+
+    fn contains(&self, min: DVec2, max: DVec2) -> bool {
+        min.x >= self.min.x && min.y >= self.min.y && max.x <= self.max.x && max.y <= self.max.y
+    }
+
+    fn quadrant(&self, i: usize) -> (DVec2, DVec2) {
+        let mid = (self.min + self.max) / 2.0;
+        let min = DVec2::new(
+            if i & 1 == 0 { self.min.x } else { mid.x },
+            if i & 2 == 0 { self.min.y } else { mid.y },
+        );
+        let max = DVec2::new(
+            if i & 1 == 0 { mid.x } else { self.max.x },
+            if i & 2 == 0 { mid.y } else { self.max.y },
+        );
+        (min, max)
+    }
+}
+
+/// Square cells that split in four only where segments crowd, so big empty areas stay a
+/// single node. Each segment sits in the smallest node that holds its whole box.
+pub struct QuadTree {
+    min_size: f64,
+    nodes: Vec<QuadNode>,
+    root: Option<usize>,
+}
+
+impl QuadTree {
+    /// Nodes never split below `min_size` across.
+    pub fn new(min_size: f64) -> Self {
+        Self {
+            min_size,
+            nodes: Vec::new(),
+            root: None,
+        }
+    }
+
+    fn push_node(&mut self, min: DVec2, max: DVec2) -> usize {
+        self.nodes.push(QuadNode::new(min, max));
+        self.nodes.len() - 1
+    }
+
+    /// Doubles the root towards `min..max` until it holds it. The old root becomes one of
+    /// the new root's quadrants as it is, nothing is re-inserted.
+    fn grow_to_fit(&mut self, min: DVec2, max: DVec2) -> usize {
+        let Some(mut root) = self.root else {
+            let side = (max - min).max_element().max(self.min_size * ROOT_CELLS);
+            let center = (min + max) / 2.0;
+            let root = self.push_node(center - side / 2.0, center + side / 2.0);
+            self.root = Some(root);
+            return root;
+        };
+        while !self.nodes[root].contains(min, max) {
+            let (old_min, old_max) = (self.nodes[root].min, self.nodes[root].max);
+            let size = old_max - old_min;
+            let grow_left = min.x < old_min.x;
+            let grow_down = min.y < old_min.y;
+            let new_min = old_min
+                - DVec2::new(
+                    if grow_left { size.x } else { 0.0 },
+                    if grow_down { size.y } else { 0.0 },
+                );
+            let new_max = new_min + size * 2.0;
+            let new_root = self.push_node(new_min, new_max);
+            let old_quadrant = usize::from(grow_left) | (usize::from(grow_down) << 1);
+            let mut children = [0; 4];
+            for (i, child) in children.iter_mut().enumerate() {
+                *child = if i == old_quadrant {
+                    root
+                } else {
+                    let (q_min, q_max) = self.nodes[new_root].quadrant(i);
+                    self.push_node(q_min, q_max)
+                };
+            }
+            self.nodes[new_root].children = Some(children);
+            root = new_root;
+        }
+        self.root = Some(root);
+        root
+    }
+
+    fn child_holding(&self, node: usize, min: DVec2, max: DVec2) -> Option<usize> {
+        let children = self.nodes[node].children?;
+        children
+            .into_iter()
+            .find(|&child| self.nodes[child].contains(min, max))
+    }
+
+    fn split_if_full(&mut self, node: usize) {
+        let size = self.nodes[node].max.x - self.nodes[node].min.x;
+        if self.nodes[node].children.is_some()
+            || self.nodes[node].entries.len() <= NODE_CAPACITY
+            || size / 2.0 < self.min_size
+        {
+            return;
+        }
+        let mut children = [0; 4];
+        for (i, child) in children.iter_mut().enumerate() {
+            let (q_min, q_max) = self.nodes[node].quadrant(i);
+            *child = self.push_node(q_min, q_max);
+        }
+        self.nodes[node].children = Some(children);
+
+        let entries = std::mem::take(&mut self.nodes[node].entries);
+        for entry in entries {
+            match self.child_holding(node, entry.1, entry.2) {
+                Some(child) => self.nodes[child].entries.push(entry),
+                None => self.nodes[node].entries.push(entry),
+            }
+        }
+        for child in children {
+            self.split_if_full(child);
+        }
+    }
+
+    pub fn read_cache(reader: &mut CacheReader) -> Option<Self> {
+        let min_size = reader.f64()?;
+        let has_root = reader.u8()? == 1;
+        let node_count = reader.len(33)?;
+        let root = if has_root {
+            Some(read_node_index(reader, node_count)?)
+        } else {
+            None
+        };
+        let mut nodes = Vec::with_capacity(node_count);
+        for _ in 0..node_count {
+            let (min, max) = (reader.dvec2()?, reader.dvec2()?);
+            let children = match reader.u8()? {
+                0 => None,
+                _ => {
+                    let mut children = [0; 4];
+                    for child in &mut children {
+                        *child = read_node_index(reader, node_count)?;
+                    }
+                    Some(children)
+                }
+            };
+            nodes.push(QuadNode {
+                min,
+                max,
+                entries: read_boxes(reader)?,
+                children,
+            });
+        }
+        let tree = is_tree(root, nodes.len(), |node| {
+            nodes[node].children.into_iter().flatten()
+        });
+        tree.then_some(Self {
+            min_size,
+            nodes,
+            root,
+        })
+    }
+
+    fn depth(&self, node: usize) -> usize {
+        match self.nodes[node].children {
+            Some(children) => {
+                1 + children
+                    .into_iter()
+                    .map(|child| self.depth(child))
+                    .max()
+                    .unwrap_or(0)
+            }
+            None => 1,
+        }
+    }
+}
+
+impl SpatialIndex for QuadTree {
+    fn kind(&self) -> SpatialIndexKind {
+        SpatialIndexKind::Quadtree
+    }
+
+    fn rebuild(&mut self, segments: &[(Slot, PathSegment)]) {
+        self.nodes.clear();
+        self.root = None;
+        let live: Vec<_> = segments
+            .iter()
+            .filter(|(_, seg)| seg.start != seg.end)
+            .collect();
+        let Some(min) = live
+            .iter()
+            .map(|(_, seg)| segment_box(seg).0)
+            .reduce(DVec2::min)
+        else {
+            return;
+        };
+        let max = live
+            .iter()
+            .map(|(_, seg)| segment_box(seg).1)
+            .fold(min, DVec2::max);
+        self.grow_to_fit(min, max);
+        for (id, segment) in live {
+            self.insert(*id, segment);
+        }
+    }
+
+    fn insert(&mut self, id: Slot, segment: &PathSegment) {
+        if segment.start == segment.end {
+            return;
+        }
+        let (min, max) = segment_box(segment);
+        let mut node = self.grow_to_fit(min, max);
+        while let Some(child) = self.child_holding(node, min, max) {
+            node = child;
+        }
+        self.nodes[node].entries.push((id, min, max));
+        self.split_if_full(node);
+    }
+
+    fn remove(&mut self, id: Slot, segment: &PathSegment) {
+        let (min, max) = segment_box(segment);
+        let mut node = self.root;
+        while let Some(current) = node {
+            let entries = &mut self.nodes[current].entries;
+            if let Some(pos) = entries.iter().position(|entry| entry.0 == id) {
+                entries.swap_remove(pos);
+                return;
+            }
+            node = self.child_holding(current, min, max);
+        }
+    }
+
+    fn query_rect(&self, min: DVec2, max: DVec2, out: &mut Vec<Slot>) {
+        let mut stack: Vec<usize> = self.root.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if !boxes_overlap(node.min, node.max, min, max) {
+                continue;
+            }
+            out.extend(
+                node.entries
+                    .iter()
+                    .filter(|entry| boxes_overlap(entry.1, entry.2, min, max))
+                    .map(|entry| entry.0),
+            );
+            stack.extend(node.children.into_iter().flatten());
+        }
+    }
+
+    /// Best-first over the nodes, closest box first.
+    fn nearest(
+        &self,
+        pos: DVec2,
+        radius: f64,
+        test: &mut dyn FnMut(Slot, f64) -> Option<f64>,
+    ) -> Option<Slot> {
+        let mut reach = radius;
+        let mut best = None;
+        // Box distances are never negative, so their bits sort the same as the values
+        let mut queue: BinaryHeap<Reverse<(u64, usize)>> = BinaryHeap::new();
+        if let Some(root) = self.root {
+            queue.push(Reverse((
+                box_distance(pos, self.nodes[root].min, self.nodes[root].max).to_bits(),
+                root,
+            )));
+        }
+        while let Some(Reverse((dist_bits, node))) = queue.pop() {
+            if f64::from_bits(dist_bits) >= reach {
+                break;
+            }
+            let node = &self.nodes[node];
+            for &(id, min, max) in &node.entries {
+                if box_distance(pos, min, max) < reach
+                    && let Some(dist) = test(id, reach)
+                {
+                    reach = dist;
+                    best = Some(id);
+                }
+            }
+            for child in node.children.into_iter().flatten() {
+                let dist = box_distance(pos, self.nodes[child].min, self.nodes[child].max);
+                if dist < reach {
+                    queue.push(Reverse((dist.to_bits(), child)));
+                }
+            }
+        }
+        best
+    }
+
+    fn debug_rects(&self, min: Vec2, max: Vec2) -> Vec<(Vec2, Vec2)> {
+        self.nodes
+            .iter()
+            .filter(|node| node.children.is_none())
+            .map(|node| (to_render(node.min), to_render(node.max)))
+            .filter(|&(rect_min, rect_max)| {
+                rect_max.x >= min.x
+                    && rect_min.x <= max.x
+                    && rect_max.y >= min.y
+                    && rect_min.y <= max.y
+            })
+            .collect()
+    }
+
+    fn describe(&self) -> String {
+        let Some(root) = self.root else {
+            return "quadtree empty".to_string();
+        };
+        format!(
+            "quadtree min {:?} max {:?} nodes {} depth {}",
+            self.nodes[root].min,
+            self.nodes[root].max,
+            self.nodes.len(),
+            self.depth(root)
+        )
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.nodes.capacity() * size_of::<QuadNode>()
+            + self
+                .nodes
+                .iter()
+                .map(|node| node.entries.capacity() * size_of::<Entry>())
+                .sum::<usize>()
+    }
+
+    fn compact(&mut self) {
+        self.nodes
+            .iter_mut()
+            .for_each(|node| node.entries.shrink_to_fit());
+    }
+
+    fn write_cache(&self, out: &mut CacheWriter) {
+        out.f64(self.min_size);
+        out.u8(u8::from(self.root.is_some()));
+        out.usize(self.nodes.len());
+        if let Some(root) = self.root {
+            out.usize(root);
+        }
+        for node in &self.nodes {
+            out.dvec2(node.min);
+            out.dvec2(node.max);
+            match node.children {
+                Some(children) => {
+                    out.u8(1);
+                    children.into_iter().for_each(|child| out.usize(child));
+                }
+                None => out.u8(0),
+            }
+            write_boxes(out, &node.entries);
+        }
+    }
+}
@@ -0,0 +1,494 @@
+use bevy::prelude::*;
+use bevy::tasks::{AsyncComputeTaskPool, Task, TaskPool, block_on, poll_once};
+use std::collections::HashMap;
+
+use crate::{
+    clip_mask::ClipMask,
+    geometry64::to_render,
+    label_placement::{LabelPlacement, interior_point, place_label},
+    linear_ref::ring_length,
+    math_utils::ramer_douglas_peucker,
+    offset::{JoinStyle, inner_band, offset_polygon},
+    region_graph::{RegionGraph, Surface},
+    self_intersection::find_self_intersections,
+    topology::{FaceChange, FaceId, Topology, topology_sync_system},
+    triangulate::{Triangulation, triangulate_polygon},
+};
+
+/// Faces smaller than this are slivers between nearly touching borders, not regions.
+const MIN_REGION_AREA: f64 = 1.0;
+const LABEL_PRECISION: f32 = 0.5;
+/// Borders are drawn as a band this wide just inside each region, in the region's colour.
+const BORDER_BAND_WIDTH: f32 = 1.5;
+/// Rings are simplified this much before they're filled and banded, well under the band's
+/// width so the slivers it leaves between neighbouring fills stay hidden under it.
+const RENDER_SIMPLIFY_TOLERANCE: f32 = 0.2;
+/// Finished region visuals turned into meshes per frame, so a whole map of them landing at
+/// once doesn't stall a single frame.
+const VISUALS_PER_FRAME: usize = 64;
+/// Attribute marking a region as a sea or lake, "true" when it is. New regions off the land
+/// mask get it set; splits inherit it like any other attribute.
+pub const WATER_ATTRIBUTE: &str = "water";
+
+/// An area enclosed by borders, one per bounded face of the `Topology` and sharing its id.
+#[derive(Component, Clone, Debug)]
+pub struct Region {
+    pub id: FaceId,
+    pub name: String,
+    /// Outline first, then the holes.
+    pub rings: Vec<Vec<Vec2>>,
+    pub attributes: HashMap<String, String>,
+}
+
+impl Region {
+    pub fn outline(&self) -> &[Vec2] {
+        &self.rings[0]
+    }
+
+    pub fn holes(&self) -> &[Vec<Vec2>] {
+        &self.rings[1..]
+    }
+
+    pub fn surface(&self) -> Surface {
+        surface_of(&self.attributes)
+    }
+}
+
+fn surface_of(attributes: &HashMap<String, String>) -> Surface {
+    match attributes.get(WATER_ATTRIBUTE).map(String::as_str) {
+        Some("true") => Surface::Water,
+        _ => Surface::Land,
+    }
+}
+
+/// Region entity of each face. The `Region` component is the only copy of its data.
+#[derive(Resource, Default)]
+pub struct RegionLibrary {
+    pub regions: HashMap<FaceId, Entity>,
+}
+
+/// What a region is drawn with, worked out off the main thread.
+struct RegionVisuals {
+    fill: Option<Triangulation>,
+    band: Triangulation,
+    label: Option<LabelPlacement>,
+}
+
+/// Visuals of a new or reshaped region being built on the async compute pool. Reshaping it
+/// again replaces the task, dropping the stale one.
+#[derive(Component)]
+struct RegionVisualsTask(Task<RegionVisuals>);
+
+pub struct RegionPlugin;
+
+impl Plugin for RegionPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(RegionLibrary::default())
+            .init_resource::<RegionGraph>()
+            .add_systems(
+                Update,
+                (
+                    region_sync_system,
+                    region_visuals_system,
+                    finish_region_visuals_system,
+                )
+                    .chain()
+                    .after(topology_sync_system),
+            );
+    }
+}
+
+/// Creates, reshapes and despawns Region entities as the topology's faces change.
+fn region_sync_system(
+    mut commands: Commands,
+    mut topology: ResMut<Topology>,
+    mut region_library: ResMut<RegionLibrary>,
+    mut region_graph: ResMut<RegionGraph>,
+    mut regions: Query<&mut Region>,
+    clip_mask: Res<ClipMask>,
+) {
+    let changes = topology.take_face_changes();
+    if changes.is_empty() {
+        return;
+    }
+    let mut split_from = HashMap::new();
+    let mut faces: Vec<FaceId> = changes
+        .into_iter()
+        .map(|change| match change {
+            FaceChange::Added {
+                face,
+                split_from: parent,
+            } => {
+                if let Some(parent) = parent {
+                    split_from.insert(face, parent);
+                }
+                face
+            }
+            FaceChange::Reshaped(face) | FaceChange::Removed(face) => face,
+        })
+        .collect();
+    // A face is always newer than the one it was split from, so parents go first
+    faces.sort_unstable();
+    faces.dedup();
+
+    let mut spawned = HashMap::new();
+    let (mut added, mut reshaped, mut removed) = (0, 0, 0);
+    for &face in &faces {
+        let big_enough = topology
+            .face(face)
+            .is_some_and(|face| face.outer_area >= MIN_REGION_AREA);
+        if !big_enough {
+            if let Some(entity) = region_library.regions.remove(&face) {
+                if let Some(entity_commands) = commands.get_entity(entity) {
+                    entity_commands.despawn_recursive();
+                }
+                removed += 1;
+            }
+            continue;
+        }
+        let rings: Vec<Vec<Vec2>> = topology
+            .face_rings(face)
+            .into_iter()
+            .map(|ring| ring.into_iter().map(to_render).collect())
+            .collect();
+        if let Some(&entity) = region_library.regions.get(&face) {
+            // Only the rings are the topology's; name and attributes stay as edited
+            if let Ok(mut region) = regions.get_mut(entity) {
+                region.rings = rings;
+                reshaped += 1;
+            }
+            continue;
+        }
+        let parent = split_from
+            .get(&face)
+            .and_then(|parent| region_library.regions.get(parent))
+            .and_then(|&entity| regions.get(entity).ok());
+        if let Some(parent) = parent {
+            println!("Region {} split off {}", face, parent.name);
+        }
+        let attributes = initial_attributes(parent, || {
+            !clip_mask.layers.is_empty()
+                && interior_point(&rings).is_some_and(|point| !clip_mask.contains(point))
+        });
+        spawned.insert(face, surface_of(&attributes));
+        spawn_region(&mut commands, &mut region_library, face, rings, attributes);
+        added += 1;
+    }
+    // Regions spawned just now aren't queryable until the commands are applied
+    region_graph.update(&topology, &faces, |face| {
+        spawned.get(&face).copied().or_else(|| {
+            let &entity = region_library.regions.get(&face)?;
+            regions.get(entity).ok().map(Region::surface)
+        })
+    });
+    println!(
+        "Regions: {} added {} reshaped {} removed, {} in all",
+        added,
+        reshaped,
+        removed,
+        region_library.regions.len()
+    );
+}
+
+/// Attributes a new region starts with: a copy of the region it was split off with a
+/// `parent` entry naming it, or for a fresh region only whether it lies off the land mask.
+fn initial_attributes(
+    parent: Option<&Region>,
+    off_mask: impl FnOnce() -> bool,
+) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    if let Some(parent) = parent {
+        attributes = parent.attributes.clone();
+        attributes.insert("parent".to_string(), parent.id.to_string());
+    } else if off_mask() {
+        attributes.insert(WATER_ATTRIBUTE.to_string(), "true".to_string());
+    }
+    attributes
+}
+
+/// Starts working out the fill, border band and label of new and reshaped regions on the
+/// async compute pool.
+fn region_visuals_system(
+    mut commands: Commands,
+    changed_regions: Query<(Entity, &Region), Changed<Region>>,
+) {
+    let pool = AsyncComputeTaskPool::get_or_init(TaskPool::default);
+    for (entity, region) in changed_regions.iter() {
+        let rings = region.rings.clone();
+        let task = pool.spawn(async move { region_visuals(&rings) });
+        commands.entity(entity).insert(RegionVisualsTask(task));
+    }
+}
+
+/// Swaps finished visuals in for a region's old meshes and label, a few regions per frame.
+fn finish_region_visuals_system(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut pending: Query<(Entity, &Region, &mut RegionVisualsTask)>,
+) {
+    let mut finished = 0;
+    for (entity, region, mut task) in pending.iter_mut() {
+        if finished == VISUALS_PER_FRAME {
+            break;
+        }
+        let Some(visuals) = block_on(poll_once(&mut task.0)) else {
+            continue;
+        };
+        finished += 1;
+        let mut entity_commands = commands.entity(entity);
+        entity_commands.remove::<RegionVisualsTask>();
+        entity_commands.despawn_descendants();
+        match visuals.fill {
+            Some(triangulation) => {
+                entity_commands.insert((
+                    Mesh2d(meshes.add(triangulation.to_mesh())),
+                    MeshMaterial2d(materials.add(region_color(region.id))),
+                ));
+            }
+            None => {
+                println!(
+                    "Region {}: outline crosses itself, left unfilled",
+                    region.id
+                );
+                entity_commands.remove::<(Mesh2d, MeshMaterial2d<ColorMaterial>)>();
+            }
+        }
+        let band = visuals.band;
+        if band.triangle_count() > 0 {
+            let material = materials.add(region_border_color(region.id));
+            entity_commands.with_children(|parent| {
+                parent.spawn((
+                    Mesh2d(meshes.add(band.to_mesh())),
+                    MeshMaterial2d(material),
+                    Transform::from_xyz(0.0, 0.0, 0.1),
+                ));
+            });
+        }
+        if let Some(label) = visuals.label {
+            entity_commands.with_children(|parent| {
+                parent.spawn((
+                    Text2d::new(region.name.clone()),
+                    TextFont {
+                        font_size: (label.clearance * 0.6).clamp(4.0, 28.0),
+                        ..default()
+                    },
+                    TextColor(Color::BLACK),
+                    Transform::from_translation(label.position.extend(0.5))
+                        .with_rotation(Quat::from_rotation_z(label.angle)),
+                ));
+            });
+        }
+    }
+}
+
+fn region_color(id: usize) -> Color {
+    Color::hsla((id as f32 * 137.508) % 360.0, 0.6, 0.6, 0.35)
+}
+
+fn region_border_color(id: usize) -> Color {
+    Color::hsla((id as f32 * 137.508) % 360.0, 0.6, 0.4, 0.8)
+}
+
+/// Fill, border band and label for a region's rings, outline first. Drawn from simplified
+/// rings, or the full ones if simplifying made the outline cross itself.
+fn region_visuals(rings: &[Vec<Vec2>]) -> RegionVisuals {
+    let simplified: Vec<Vec<Vec2>> = rings
+        .iter()
+        .map(|ring| {
+            let mut closed = ring.clone();
+            closed.extend(ring.first());
+            let mut simple = ramer_douglas_peucker(&closed, RENDER_SIMPLIFY_TOLERANCE);
+            simple.pop();
+            if simple.len() >= 3 {
+                simple
+            } else {
+                ring.clone()
+            }
+        })
+        .collect();
+    let (rings, fill) = match triangulate_polygon(&simplified[0], &simplified[1..]) {
+        Some(fill) => (&simplified[..], Some(fill)),
+        None => (rings, triangulate_polygon(&rings[0], &rings[1..])),
+    };
+    RegionVisuals {
+        fill,
+        band: border_band(rings),
+        label: place_label(rings, LABEL_PRECISION),
+    }
+}
+
+/// Strip inside the outline and around each hole. A ring whose offset folds over itself,
+/// where the region narrows below twice the band width, is left without one.
+fn border_band(rings: &[Vec<Vec2>]) -> Triangulation {
+    let join = JoinStyle::default();
+    let is_simple = |ring: &[Vec2]| {
+        let mut closed = ring.to_vec();
+        closed.extend(ring.first());
+        ring.len() >= 3 && find_self_intersections(&closed).is_empty()
+    };
+    let mut band = Triangulation::default();
+    let (outer, inner) = inner_band(&rings[0], BORDER_BAND_WIDTH, join);
+    if is_simple(&inner)
+        && let Some(triangles) = triangulate_polygon(&outer, &[inner])
+    {
+        band.append(triangles);
+    }
+    for hole in &rings[1..] {
+        let grown = offset_polygon(hole, BORDER_BAND_WIDTH, join);
+        if is_simple(&grown)
+            && let Some(triangles) = triangulate_polygon(&grown, std::slice::from_ref(hole))
+        {
+            band.append(triangles);
+        }
+    }
+    band
+}
+
+pub fn spawn_region(
+    commands: &mut Commands,
+    region_library: &mut ResMut<RegionLibrary>,
+    id: FaceId,
+    rings: Vec<Vec<Vec2>>,
+    attributes: HashMap<String, String>,
+) -> Entity {
+    let region = Region {
+        id,
+        name: format!("Region {}", id),
+        rings,
+        attributes,
+    };
+    let entity = commands
+        .spawn((
+            Transform::from_xyz(0.0, 0.0, -1.0),
+            Visibility::default(),
+            region,
+        ))
+        .id();
+    region_library.regions.insert(id, entity);
+    entity
+}
+
+/// Area of the region from its triangulation, so slivers and collinear runs don't skew it.
+#[allow(dead_code)]
+pub fn region_area(region: &Region) -> Option<f32> {
+    triangulate_polygon(region.outline(), region.holes()).map(|triangles| triangles.area())
+}
+
+/// Uniform random point inside the region, e.g. for spawning units. Takes three numbers
+/// in [0, 1) from the caller's RNG.
+#[allow(dead_code)]
+pub fn random_point_in_region(region: &Region, pick: f32, u: f32, v: f32) -> Option<Vec2> {
+    triangulate_polygon(region.outline(), region.holes())?
+        .sampler()
+        .sample(pick, u, v)
+}
+
+#[allow(dead_code)]
+pub fn region_perimeter(region: &Region) -> f32 {
+    region.rings.iter().map(|ring| ring_length(ring)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math_utils::{closest_point_on_segment, point_in_polygon};
+
+    fn rect(min: Vec2, max: Vec2) -> Vec<Vec2> {
+        vec![min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)]
+    }
+
+    fn ring_region() -> Region {
+        Region {
+            id: 1,
+            name: "Ring".to_owned(),
+            rings: vec![
+                rect(Vec2::ZERO, Vec2::new(40.0, 40.0)),
+                rect(Vec2::new(10.0, 10.0), Vec2::new(30.0, 30.0)),
+            ],
+            attributes: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn split_regions_inherit_their_parents_attributes() {
+        let mut parent = ring_region();
+        parent
+            .attributes
+            .insert("owner".to_owned(), "north".to_owned());
+        parent
+            .attributes
+            .insert(WATER_ATTRIBUTE.to_owned(), "true".to_owned());
+        // The mask isn't consulted for a split, the parent already says what it is
+        let attributes = initial_attributes(Some(&parent), || panic!("mask checked"));
+        assert_eq!(attributes.get("owner").map(String::as_str), Some("north"));
+        assert_eq!(attributes.get("parent").map(String::as_str), Some("1"));
+        assert_eq!(surface_of(&attributes), Surface::Water);
+
+        let fresh = initial_attributes(None, || true);
+        assert_eq!(fresh.len(), 1);
+        assert_eq!(surface_of(&fresh), Surface::Water);
+        assert!(initial_attributes(None, || false).is_empty());
+    }
+
+    #[test]
+    fn visuals_of_a_region_with_a_hole() {
+        let region = ring_region();
+        let visuals = region_visuals(&region.rings);
+        assert!((visuals.fill.unwrap().area() - 1200.0).abs() < 1e-2);
+
+        // Mitred, the band is a 1.5 wide frame inside the outline and another around the hole
+        let band = border_band(&region.rings);
+        let outer = 40.0 * 40.0 - 37.0 * 37.0;
+        let around_hole = 23.0 * 23.0 - 20.0 * 20.0;
+        assert!(
+            (band.area() - (outer + around_hole)).abs() < 1e-2,
+            "{}",
+            band.area()
+        );
+        assert_eq!(visuals.band.area(), band.area());
+        for t in 0..band.triangle_count() {
+            let (a, b, c) = band.triangle(t);
+            let centre = (a + b + c) / 3.0;
+            assert!(point_in_polygon(centre, region.outline()), "{centre}");
+            assert!(!point_in_polygon(centre, &region.holes()[0]), "{centre}");
+        }
+
+        // The label goes in the frame, widest at a corner: c = sqrt(2) * (10 - c)
+        let label = visuals.label.unwrap();
+        assert!(point_in_polygon(label.position, region.outline()));
+        assert!(!point_in_polygon(label.position, &region.holes()[0]));
+        let expected = 10.0 * std::f32::consts::SQRT_2 / (1.0 + std::f32::consts::SQRT_2);
+        assert!(
+            (label.clearance - expected).abs() < LABEL_PRECISION,
+            "{}",
+            label.clearance
+        );
+    }
+
+    #[test]
+    fn random_points_land_in_the_region_and_not_its_hole() {
+        let region = ring_region();
+        assert!((region_area(&region).unwrap() - 1200.0).abs() < 1e-2);
+        let f = |n: u32| (n % 97) as f32 / 97.0;
+        for i in 0..200 {
+            let p =
+                random_point_in_region(&region, f(i * 31), f(i * 17 + 5), f(i * 53 + 11)).unwrap();
+            assert!(
+                point_in_polygon(p, region.outline()) || on_edge(p, region.outline()),
+                "{p}"
+            );
+            assert!(
+                !point_in_polygon(p, &region.holes()[0]) || on_edge(p, &region.holes()[0]),
+                "{p}"
+            );
+        }
+    }
+
+    fn on_edge(p: Vec2, ring: &[Vec2]) -> bool {
+        (0..ring.len()).any(|i| {
+            let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+            closest_point_on_segment(p, a, b).distance(p) < 1e-3
+        })
+    }
+}
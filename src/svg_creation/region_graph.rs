@@ -0,0 +1,259 @@
+use bevy::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+use crate::geometry64::to_render;
+use crate::topology::{FaceId, Topology, UNBOUNDED_FACE};
+
+/// What a region is: land, or a sea or lake. The unbounded face outside every border counts
+/// as water.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Surface {
+    Land,
+    Water,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BorderKind {
+    /// Between two land regions.
+    Land,
+    /// Between a land region and water, all of it together.
+    Coast,
+}
+
+/// Everything two land regions, or a land region and the water around it, have in common.
+#[derive(Clone, Debug)]
+pub struct RegionBorder {
+    #[allow(dead_code)]
+    pub kind: BorderKind,
+    pub length: f32,
+    pub segments: Vec<(Vec2, Vec2)>,
+}
+
+/// Which land regions touch which, read off the topology. Kept up to date by the region
+/// sync, one changed face at a time. Water regions only show up as coasts.
+#[derive(Resource, Default)]
+pub struct RegionGraph {
+    /// Keyed by the lower id first. Coasts are keyed with the unbounded face, whichever
+    /// water they face.
+    borders: HashMap<(FaceId, FaceId), RegionBorder>,
+    neighbours: HashMap<FaceId, HashSet<FaceId>>,
+}
+
+fn border_key(a: FaceId, b: FaceId) -> (FaceId, FaceId) {
+    (a.min(b), a.max(b))
+}
+
+#[allow(dead_code)]
+impl RegionGraph {
+    /// Regions sharing a land border with `region`, lowest id first.
+    pub fn neighbours(&self, region: FaceId) -> Vec<FaceId> {
+        let mut neighbours: Vec<FaceId> = self
+            .neighbours
+            .get(&region)
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect();
+        neighbours.sort_unstable();
+        neighbours
+    }
+
+    pub fn are_adjacent(&self, a: FaceId, b: FaceId) -> bool {
+        self.neighbours
+            .get(&a)
+            .is_some_and(|neighbours| neighbours.contains(&b))
+    }
+
+    pub fn border(&self, a: FaceId, b: FaceId) -> Option<&RegionBorder> {
+        self.borders.get(&border_key(a, b))
+    }
+
+    pub fn coast(&self, region: FaceId) -> Option<&RegionBorder> {
+        self.border(region, UNBOUNDED_FACE)
+    }
+
+    pub fn is_coastal(&self, region: FaceId) -> bool {
+        self.coast(region).is_some()
+    }
+
+    /// Splits `regions` into groups connected over land borders without leaving the set,
+    /// e.g. the separate fronts of one country. Each group and the list are sorted.
+    pub fn connected_components(&self, regions: &[FaceId]) -> Vec<Vec<FaceId>> {
+        let set: HashSet<FaceId> = regions.iter().copied().collect();
+        let mut seen = HashSet::new();
+        let mut components = Vec::new();
+        let mut starts: Vec<FaceId> = set.iter().copied().collect();
+        starts.sort_unstable();
+        for start in starts {
+            if !seen.insert(start) {
+                continue;
+            }
+            let mut component = vec![start];
+            let mut stack = vec![start];
+            while let Some(region) = stack.pop() {
+                for &next in self.neighbours.get(&region).into_iter().flatten() {
+                    if set.contains(&next) && seen.insert(next) {
+                        component.push(next);
+                        stack.push(next);
+                    }
+                }
+            }
+            component.sort_unstable();
+            components.push(component);
+        }
+        components
+    }
+
+    fn remove(&mut self, region: FaceId) {
+        self.borders.remove(&border_key(region, UNBOUNDED_FACE));
+        for neighbour in self.neighbours.remove(&region).into_iter().flatten() {
+            self.borders.remove(&border_key(region, neighbour));
+            if let Some(theirs) = self.neighbours.get_mut(&neighbour) {
+                theirs.remove(&region);
+            }
+        }
+    }
+
+    /// Re-reads the borders of `faces` from the topology. Faces that aren't land regions any
+    /// more just drop out; `surface` says what each region is, None for faces that aren't.
+    pub fn update(
+        &mut self,
+        topology: &Topology,
+        faces: &[FaceId],
+        surface: impl Fn(FaceId) -> Option<Surface>,
+    ) {
+        for &face in faces {
+            self.remove(face);
+        }
+        for &face in faces {
+            let is_land = surface(face) == Some(Surface::Land);
+            let Some(region) = topology.face(face).filter(|_| is_land) else {
+                continue;
+            };
+            let mut found: HashMap<FaceId, RegionBorder> = HashMap::new();
+            for boundary in region.outer.iter().chain(&region.holes) {
+                for edge in topology.cycle(boundary.edge) {
+                    let other = topology.half_edge(topology.twin(edge)).face;
+                    // Lines dangling into the region
+                    if other == face {
+                        continue;
+                    }
+                    let other_surface = if other == UNBOUNDED_FACE {
+                        Some(Surface::Water)
+                    } else {
+                        surface(other)
+                    };
+                    let (kind, other) = match other_surface {
+                        Some(Surface::Land) => (BorderKind::Land, other),
+                        Some(Surface::Water) => (BorderKind::Coast, UNBOUNDED_FACE),
+                        // Slivers too small to be regions
+                        None => continue,
+                    };
+                    let start = topology.vertex(topology.half_edge(edge).origin).pos;
+                    let end = topology.vertex(topology.destination(edge)).pos;
+                    let border = found.entry(other).or_insert_with(|| RegionBorder {
+                        kind,
+                        length: 0.0,
+                        segments: Vec::new(),
+                    });
+                    border.length += start.distance(end) as f32;
+                    border.segments.push((to_render(start), to_render(end)));
+                }
+            }
+            for (other, border) in found {
+                if other != UNBOUNDED_FACE {
+                    self.neighbours.entry(face).or_default().insert(other);
+                    self.neighbours.entry(other).or_default().insert(face);
+                }
+                self.borders.insert(border_key(face, other), border);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spatial_grid::SpatialGrid;
+    use crate::topology::FaceChange;
+    use bevy::math::DVec2;
+
+    fn changed_faces(topology: &mut Topology) -> Vec<FaceId> {
+        topology
+            .take_face_changes()
+            .into_iter()
+            .map(|change| match change {
+                FaceChange::Added { face, .. }
+                | FaceChange::Reshaped(face)
+                | FaceChange::Removed(face) => face,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn borders_follow_surfaces_and_splits() {
+        let p = DVec2::new;
+        // Two 4 by 4 squares side by side, sharing the side at x = 4
+        let outline = [
+            p(0.0, 0.0),
+            p(4.0, 0.0),
+            p(8.0, 0.0),
+            p(8.0, 4.0),
+            p(4.0, 4.0),
+            p(0.0, 4.0),
+        ];
+        let mut grid = SpatialGrid::new(1.0);
+        let sides: Vec<_> = (0..outline.len())
+            .map(|i| grid.insert_segment(outline[i], outline[(i + 1) % outline.len()]))
+            .collect();
+        grid.insert_segment(p(4.0, 0.0), p(4.0, 4.0));
+        let mut topology = Topology::build(&[]);
+        topology.apply(&grid.take_changes());
+        let faces = changed_faces(&mut topology);
+        let (west, east) = (topology.locate(p(2.0, 2.0)), topology.locate(p(6.0, 2.0)));
+
+        // With the east square water, all of the west one's outline is coast
+        let mut surfaces = HashMap::from([(west, Surface::Land), (east, Surface::Water)]);
+        let mut graph = RegionGraph::default();
+        graph.update(&topology, &faces, |face| surfaces.get(&face).copied());
+        assert!(!graph.are_adjacent(west, east));
+        let coast = graph.coast(west).unwrap();
+        assert_eq!(coast.kind, BorderKind::Coast);
+        assert_eq!(coast.length, 16.0);
+        assert!(!graph.is_coastal(east));
+
+        surfaces.insert(east, Surface::Land);
+        graph.update(&topology, &[west, east], |face| {
+            surfaces.get(&face).copied()
+        });
+        assert_eq!(graph.neighbours(west), vec![east]);
+        let border = graph.border(east, west).unwrap();
+        assert_eq!(border.kind, BorderKind::Land);
+        assert_eq!(border.length, 4.0);
+        assert_eq!(graph.coast(west).unwrap().length, 12.0);
+
+        // Split the east square down the middle
+        grid.split_segment(sides[1], p(6.0, 0.0));
+        grid.split_segment(sides[3], p(6.0, 4.0));
+        grid.insert_segment(p(6.0, 0.0), p(6.0, 4.0));
+        topology.apply(&grid.take_changes());
+        let faces = changed_faces(&mut topology);
+        let (middle, far) = (topology.locate(p(5.0, 2.0)), topology.locate(p(7.0, 2.0)));
+        assert_ne!(middle, far);
+        surfaces.insert(middle, Surface::Land);
+        surfaces.insert(far, Surface::Land);
+        graph.update(&topology, &faces, |face| surfaces.get(&face).copied());
+
+        assert_eq!(graph.neighbours(west), vec![middle]);
+        assert!(graph.are_adjacent(middle, far));
+        assert!(!graph.are_adjacent(west, far));
+        assert_eq!(graph.border(middle, far).unwrap().length, 4.0);
+        assert_eq!(graph.coast(far).unwrap().length, 8.0);
+        let mut apart = vec![vec![west], vec![far]];
+        apart.sort();
+        assert_eq!(graph.connected_components(&[far, west]), apart);
+        let mut all = vec![west, middle, far];
+        all.sort_unstable();
+        assert_eq!(graph.connected_components(&[far, west, middle]), vec![all]);
+    }
+}
@@ -0,0 +1,391 @@
+use bevy::math::DVec2;
+use bevy::prelude::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::geometry64::to_render;
+use crate::index_cache::{
+    CacheReader, CacheWriter, is_tree, read_boxes, read_node_index, write_boxes,
+};
+use crate::spatial_grid::PathSegment;
+use crate::spatial_index::{
+    Slot, SpatialIndex, SpatialIndexKind, box_distance, boxes_overlap, segment_box,
+};
+
+/// Entries per leaf and children per node when packing.
+const NODE_CAPACITY: usize = 16;
+/// Segments inserted since the last pack, as a fraction of what was packed, before the
+/// whole tree is packed again.
+const REPACK_FRACTION: f64 = 0.25;
+
+type Entry = (Slot, DVec2, DVec2);
+
+struct RNode {
+    min: DVec2,
+    max: DVec2,
+    children: Vec<usize>,
+    entries: Vec<Entry>,
+}
+
+impl RNode {
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+/// R-tree bulk-loaded with Sort-Tile-Recursive packing, which gives tight, barely
+/// overlapping boxes for a map that's loaded once. Later inserts go into the leaf that grows
+/// least and split it when full, and once enough have piled up the tree is packed afresh.
+pub struct RTree {
+    nodes: Vec<RNode>,
+    root: Option<usize>,
+    packed: usize,
+    inserted_since_pack: usize,
+}
+
+fn area(min: DVec2, max: DVec2) -> f64 {
+    (max - min).element_product()
+}
+
+fn union(boxes: impl Iterator<Item = (DVec2, DVec2)>) -> (DVec2, DVec2) {
+    boxes.fold(
+        (DVec2::splat(f64::INFINITY), DVec2::splat(f64::NEG_INFINITY)),
+        |(min, max), (b_min, b_max)| (min.min(b_min), max.max(b_max)),
+    )
+}
+
+/// Orders `items` so that consecutive runs of NODE_CAPACITY are compact tiles: vertical
+/// slices by x, then sorted by y within each slice.
+fn sort_tile<T>(items: &mut [T], center: impl Fn(&T) -> DVec2) {
+    let groups = items.len().div_ceil(NODE_CAPACITY);
+    let slices = (groups as f64).sqrt().ceil() as usize;
+    let slice_len = (slices * NODE_CAPACITY).max(1);
+    items.sort_by(|a, b| center(a).x.total_cmp(&center(b).x));
+    for slice in items.chunks_mut(slice_len) {
+        slice.sort_by(|a, b| center(a).y.total_cmp(&center(b).y));
+    }
+}
+
+impl RTree {
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            root: None,
+            packed: 0,
+            inserted_since_pack: 0,
+        }
+    }
+
+    pub fn read_cache(reader: &mut CacheReader) -> Option<Self> {
+        let packed = reader.usize()?;
+        let inserted_since_pack = reader.usize()?;
+        let has_root = reader.u8()? == 1;
+        let node_count = reader.len(48)?;
+        let root = if has_root {
+            Some(read_node_index(reader, node_count)?)
+        } else {
+            None
+        };
+        let mut nodes = Vec::with_capacity(node_count);
+        for _ in 0..node_count {
+            let (min, max) = (reader.dvec2()?, reader.dvec2()?);
+            let child_count = reader.len(8)?;
+            let children = (0..child_count)
+                .map(|_| read_node_index(reader, node_count))
+                .collect::<Option<_>>()?;
+            nodes.push(RNode {
+                min,
+                max,
+                children,
+                entries: read_boxes(reader)?,
+            });
+        }
+        let tree = is_tree(root, nodes.len(), |node| {
+            nodes[node].children.iter().copied()
+        });
+        tree.then_some(Self {
+            nodes,
+            root,
+            packed,
+            inserted_since_pack,
+        })
+    }
+
+    fn push_node(&mut self, children: Vec<usize>, entries: Vec<Entry>) -> usize {
+        let child_boxes = children
+            .iter()
+            .map(|&child| (self.nodes[child].min, self.nodes[child].max));
+        let (min, max) = union(child_boxes.chain(entries.iter().map(|entry| (entry.1, entry.2))));
+        self.nodes.push(RNode {
+            min,
+            max,
+            children,
+            entries,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn pack(&mut self, mut entries: Vec<Entry>) {
+        self.nodes.clear();
+        self.root = None;
+        self.packed = entries.len();
+        self.inserted_since_pack = 0;
+        if entries.is_empty() {
+            return;
+        }
+        sort_tile(&mut entries, |entry| (entry.1 + entry.2) / 2.0);
+        let mut level: Vec<usize> = entries
+            .chunks(NODE_CAPACITY)
+            .map(|chunk| self.push_node(Vec::new(), chunk.to_vec()))
+            .collect();
+        while level.len() > 1 {
+            let nodes = &self.nodes;
+            sort_tile(&mut level, |&node| {
+                (nodes[node].min + nodes[node].max) / 2.0
+            });
+            level = level
+                .chunks(NODE_CAPACITY)
+                .map(|chunk| self.push_node(chunk.to_vec(), Vec::new()))
+                .collect();
+        }
+        self.root = Some(level[0]);
+    }
+
+    fn all_entries(&self) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        let mut stack: Vec<usize> = self.root.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            entries.extend_from_slice(&self.nodes[node].entries);
+            stack.extend_from_slice(&self.nodes[node].children);
+        }
+        entries
+    }
+
+    /// Splits an overfull leaf in half along its longer side and hangs the new half off
+    /// `parent`, or off a new root if the leaf was the root.
+    fn split_leaf(&mut self, leaf: usize, parent: Option<usize>) {
+        let node = &mut self.nodes[leaf];
+        let axis = if node.max.x - node.min.x >= node.max.y - node.min.y {
+            0
+        } else {
+            1
+        };
+        node.entries
+            .sort_by(|a, b| (a.1 + a.2)[axis].total_cmp(&(b.1 + b.2)[axis]));
+        let upper = node.entries.split_off(node.entries.len() / 2);
+        let (min, max) = union(node.entries.iter().map(|entry| (entry.1, entry.2)));
+        node.min = min;
+        node.max = max;
+        let new_leaf = self.push_node(Vec::new(), upper);
+        match parent {
+            Some(parent) => self.nodes[parent].children.push(new_leaf),
+            None => self.root = Some(self.push_node(vec![leaf, new_leaf], Vec::new())),
+        }
+    }
+}
+
+impl Default for RTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpatialIndex for RTree {
+    fn kind(&self) -> SpatialIndexKind {
+        SpatialIndexKind::RTree
+    }
+
+    fn rebuild(&mut self, segments: &[(Slot, PathSegment)]) {
+        let entries = segments
+            .iter()
+            .filter(|(_, seg)| seg.start != seg.end)
+            .map(|(id, seg)| {
+                let (min, max) = segment_box(seg);
+                (*id, min, max)
+            })
+            .collect();
+        self.pack(entries);
+    }
+
+    fn insert(&mut self, id: Slot, segment: &PathSegment) {
+        if segment.start == segment.end {
+            return;
+        }
+        let (min, max) = segment_box(segment);
+        let Some(mut node) = self.root else {
+            self.pack(vec![(id, min, max)]);
+            return;
+        };
+        let mut parent = None;
+        loop {
+            let current = &mut self.nodes[node];
+            current.min = current.min.min(min);
+            current.max = current.max.max(max);
+            if current.is_leaf() {
+                break;
+            }
+            let current = &self.nodes[node];
+            // The child whose box grows least, then the smallest one
+            let growth = |child: &RNode| {
+                let grown =
+                    area(child.min.min(min), child.max.max(max)) - area(child.min, child.max);
+                (grown, area(child.min, child.max))
+            };
+            let next = current.children.iter().copied().min_by(|&a, &b| {
+                let (ga, aa) = growth(&self.nodes[a]);
+                let (gb, ab) = growth(&self.nodes[b]);
+                ga.total_cmp(&gb).then(aa.total_cmp(&ab))
+            });
+            let Some(next) = next else {
+                break;
+            };
+            parent = Some(node);
+            node = next;
+        }
+        self.nodes[node].entries.push((id, min, max));
+        if self.nodes[node].entries.len() > NODE_CAPACITY * 2 {
+            self.split_leaf(node, parent);
+        }
+
+        self.inserted_since_pack += 1;
+        if self.inserted_since_pack as f64
+            > self.packed as f64 * REPACK_FRACTION + NODE_CAPACITY as f64
+        {
+            let entries = self.all_entries();
+            self.pack(entries);
+        }
+    }
+
+    fn remove(&mut self, id: Slot, segment: &PathSegment) {
+        let (min, max) = segment_box(segment);
+        let mut stack: Vec<usize> = self.root.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            let node = &mut self.nodes[node];
+            if !boxes_overlap(node.min, node.max, min, max) {
+                continue;
+            }
+            if let Some(pos) = node.entries.iter().position(|entry| entry.0 == id) {
+                // Boxes aren't shrunk, they just stay a little loose until the next pack
+                node.entries.swap_remove(pos);
+                return;
+            }
+            stack.extend_from_slice(&node.children);
+        }
+    }
+
+    fn query_rect(&self, min: DVec2, max: DVec2, out: &mut Vec<Slot>) {
+        let mut stack: Vec<usize> = self.root.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if !boxes_overlap(node.min, node.max, min, max) {
+                continue;
+            }
+            out.extend(
+                node.entries
+                    .iter()
+                    .filter(|entry| boxes_overlap(entry.1, entry.2, min, max))
+                    .map(|entry| entry.0),
+            );
+            stack.extend_from_slice(&node.children);
+        }
+    }
+
+    /// Best-first over the nodes, closest box first.
+    fn nearest(
+        &self,
+        pos: DVec2,
+        radius: f64,
+        test: &mut dyn FnMut(Slot, f64) -> Option<f64>,
+    ) -> Option<Slot> {
+        let mut reach = radius;
+        let mut best = None;
+        // Box distances are never negative, so their bits sort the same as the values
+        let mut queue: BinaryHeap<Reverse<(u64, usize)>> = BinaryHeap::new();
+        if let Some(root) = self.root {
+            queue.push(Reverse((
+                box_distance(pos, self.nodes[root].min, self.nodes[root].max).to_bits(),
+                root,
+            )));
+        }
+        while let Some(Reverse((dist_bits, node))) = queue.pop() {
+            if f64::from_bits(dist_bits) >= reach {
+                break;
+            }
+            let node = &self.nodes[node];
+            for &(id, min, max) in &node.entries {
+                if box_distance(pos, min, max) < reach
+                    && let Some(dist) = test(id, reach)
+                {
+                    reach = dist;
+                    best = Some(id);
+                }
+            }
+            for &child in &node.children {
+                let dist = box_distance(pos, self.nodes[child].min, self.nodes[child].max);
+                if dist < reach {
+                    queue.push(Reverse((dist.to_bits(), child)));
+                }
+            }
+        }
+        best
+    }
+
+    fn debug_rects(&self, min: Vec2, max: Vec2) -> Vec<(Vec2, Vec2)> {
+        self.nodes
+            .iter()
+            .filter(|node| node.is_leaf() && !node.entries.is_empty())
+            .map(|node| (to_render(node.min), to_render(node.max)))
+            .filter(|&(rect_min, rect_max)| {
+                rect_max.x >= min.x
+                    && rect_min.x <= max.x
+                    && rect_max.y >= min.y
+                    && rect_min.y <= max.y
+            })
+            .collect()
+    }
+
+    fn describe(&self) -> String {
+        let leaves = self.nodes.iter().filter(|node| node.is_leaf()).count();
+        format!(
+            "r-tree nodes {} leaves {} packed {} inserted since {}",
+            self.nodes.len(),
+            leaves,
+            self.packed,
+            self.inserted_since_pack
+        )
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.nodes.capacity() * size_of::<RNode>()
+            + self
+                .nodes
+                .iter()
+                .map(|node| {
+                    node.entries.capacity() * size_of::<Entry>()
+                        + node.children.capacity() * size_of::<usize>()
+                })
+                .sum::<usize>()
+    }
+
+    fn compact(&mut self) {
+        let entries = self.all_entries();
+        self.pack(entries);
+    }
+
+    fn write_cache(&self, out: &mut CacheWriter) {
+        out.usize(self.packed);
+        out.usize(self.inserted_since_pack);
+        out.u8(u8::from(self.root.is_some()));
+        out.usize(self.nodes.len());
+        if let Some(root) = self.root {
+            out.usize(root);
+        }
+        for node in &self.nodes {
+            out.dvec2(node.min);
+            out.dvec2(node.max);
+            out.usize(node.children.len());
+            node.children.iter().for_each(|&child| out.usize(child));
+            write_boxes(out, &node.entries);
+        }
+    }
+}
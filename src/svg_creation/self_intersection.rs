@@ -0,0 +1,136 @@
+use bevy::prelude::*;
+
+use crate::intersections::{SegmentHash, insert_cuts};
+
+/// Two non-adjacent segments of the same path that cross, `first_segment < second_segment`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SelfIntersection {
+    pub point: Vec2,
+    pub first_segment: usize,
+    pub second_segment: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SelfIntersectionRepair {
+    /// Cut out the loop between the two crossing segments.
+    #[default]
+    RemoveLoops,
+    /// Cut the stroke into pieces at every crossing, so each loop is a piece of its own.
+    SplitAtCrossing,
+    /// Refuse to finalize the stroke.
+    Reject,
+}
+
+impl SelfIntersectionRepair {
+    pub fn next(self) -> Self {
+        match self {
+            SelfIntersectionRepair::RemoveLoops => SelfIntersectionRepair::SplitAtCrossing,
+            SelfIntersectionRepair::SplitAtCrossing => SelfIntersectionRepair::Reject,
+            SelfIntersectionRepair::Reject => SelfIntersectionRepair::RemoveLoops,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct SelfIntersectionConfig {
+    pub repair: SelfIntersectionRepair,
+}
+
+/// Every place the path crosses itself, ordered along the path. Neighbouring segments
+/// sharing a vertex don't count, and neither do the two ends of a closed path meeting.
+pub fn find_self_intersections(path: &[Vec2]) -> Vec<SelfIntersection> {
+    if path.len() < 4 {
+        return Vec::new();
+    }
+    let last_segment = path.len() - 2;
+    let closed = path[0] == path[path.len() - 1];
+    let hash = SegmentHash::new(path);
+
+    let mut out = Vec::new();
+    for (i, w) in path.windows(2).enumerate() {
+        hash.for_each_crossing(w[0], w[1], |j, point| {
+            if j <= i + 1 || (closed && i == 0 && j == last_segment) {
+                return;
+            }
+            out.push(SelfIntersection {
+                point,
+                first_segment: i,
+                second_segment: j,
+            });
+        });
+    }
+    out.sort_by(|a, b| {
+        a.first_segment.cmp(&b.first_segment).then_with(|| {
+            let start = path[a.first_segment];
+            a.point
+                .distance_squared(start)
+                .total_cmp(&b.point.distance_squared(start))
+        })
+    });
+    out
+}
+
+/// Removes loops one at a time, earliest crossing first, until the path is simple.
+pub fn remove_loops(path: &[Vec2]) -> Vec<Vec2> {
+    let mut current = path.to_vec();
+    while let Some(crossing) = find_self_intersections(&current).first().copied() {
+        let mut next = current[..=crossing.first_segment].to_vec();
+        next.push(crossing.point);
+        next.extend_from_slice(&current[crossing.second_segment + 1..]);
+        current = next;
+    }
+    current
+}
+
+/// Cuts the path at every crossing. Both passes of the stroke end on the crossing, so the
+/// loops come out as pieces of their own; the pieces run end to end in stroke order.
+pub fn split_at_crossings(path: &[Vec2]) -> Vec<Vec<Vec2>> {
+    let mut cuts = Vec::new();
+    for crossing in find_self_intersections(path) {
+        cuts.push((crossing.first_segment, crossing.point));
+        cuts.push((crossing.second_segment, crossing.point));
+    }
+    insert_cuts(path, &cuts, true)
+        .split(|p| !p.is_finite())
+        .filter(|piece| !piece.is_empty())
+        .map(<[Vec2]>::to_vec)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_cuts_the_loop_into_its_own_piece() {
+        // Goes right, loops back down through its own first segment, then carries on
+        let path = [
+            Vec2::new(0.0, 0.0),
+            Vec2::new(4.0, 0.0),
+            Vec2::new(4.0, 2.0),
+            Vec2::new(2.0, 2.0),
+            Vec2::new(2.0, -2.0),
+        ];
+        let pieces = split_at_crossings(&path);
+        let crossing = Vec2::new(2.0, 0.0);
+        assert_eq!(
+            pieces,
+            vec![
+                vec![Vec2::new(0.0, 0.0), crossing],
+                vec![
+                    crossing,
+                    Vec2::new(4.0, 0.0),
+                    Vec2::new(4.0, 2.0),
+                    Vec2::new(2.0, 2.0),
+                    crossing
+                ],
+                vec![crossing, Vec2::new(2.0, -2.0)],
+            ]
+        );
+        assert!(
+            pieces
+                .iter()
+                .all(|piece| find_self_intersections(piece).is_empty())
+        );
+    }
+}
@@ -0,0 +1,382 @@
+use bevy::math::DVec2;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::{
+    geometry64::{point_in_ring, segment_intersection_point, to_render},
+    spatial_grid::SpatialGrid,
+};
+
+const KEY_SCALE: f64 = 1000.0;
+const TOUCH_EPSILON: f64 = 1.0e-4;
+
+type PointKey = (i64, i64);
+
+fn point_key(p: DVec2) -> PointKey {
+    (
+        (p.x * KEY_SCALE).round() as i64,
+        (p.y * KEY_SCALE).round() as i64,
+    )
+}
+
+/// A polyline, or a ring when `closed` is set (first point not repeated at the end).
+#[derive(Clone, Debug)]
+pub struct SimplifyPath {
+    pub points: Vec<DVec2>,
+    pub closed: bool,
+}
+
+struct Candidate {
+    area: f64,
+    index: usize,
+    version: u32,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    // Reversed so the BinaryHeap pops the smallest area first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .area
+            .total_cmp(&self.area)
+            .then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+fn triangle_area(a: DVec2, b: DVec2, c: DVec2) -> f64 {
+    ((b - a).perp_dot(c - a) * 0.5).abs()
+}
+
+fn crosses(a: DVec2, b: DVec2, c: DVec2, d: DVec2) -> bool {
+    match segment_intersection_point(a, b, c, d) {
+        Some(hit) => hit.distance(a) > TOUCH_EPSILON && hit.distance(b) > TOUCH_EPSILON,
+        None => false,
+    }
+}
+
+/// Arcs already simplified, bucketed by cell so a shortcut only checks its surroundings.
+struct FixedSegments {
+    cell_size: f64,
+    cells: HashMap<(i64, i64), Vec<(DVec2, DVec2)>>,
+}
+
+impl FixedSegments {
+    fn new(cell_size: f64) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell_range(&self, min: DVec2, max: DVec2) -> impl Iterator<Item = (i64, i64)> + use<> {
+        let (min, max) = (
+            (min / self.cell_size).floor(),
+            (max / self.cell_size).floor(),
+        );
+        let (x0, y0, x1, y1) = (min.x as i64, min.y as i64, max.x as i64, max.y as i64);
+        (x0..=x1).flat_map(move |x| (y0..=y1).map(move |y| (x, y)))
+    }
+
+    fn insert(&mut self, a: DVec2, b: DVec2) {
+        for cell in self.cell_range(a.min(b), a.max(b)) {
+            self.cells.entry(cell).or_default().push((a, b));
+        }
+    }
+
+    /// Segments that might touch `min..max`. Long ones can come up more than once.
+    fn near(&self, min: DVec2, max: DVec2) -> impl Iterator<Item = &(DVec2, DVec2)> {
+        self.cell_range(min, max)
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+    }
+}
+
+/// Simplifies several paths at once with Visvalingam-Whyatt. Stretches shared by more than
+/// one path are simplified once and reused, so neighbours keep an identical border, and a
+/// vertex is only removed if the shortcut crosses nothing in `spatial_grid`, in the other
+/// paths or in its own path, and does not jump over anything.
+pub fn simplify_preserving_topology(
+    paths: &[SimplifyPath],
+    min_area: f64,
+    spatial_grid: &SpatialGrid,
+) -> Vec<SimplifyPath> {
+    // A vertex is a node when it does not have exactly two distinct neighbours
+    let mut neighbours: HashMap<PointKey, HashSet<PointKey>> = HashMap::new();
+    for path in paths {
+        let n = path.points.len();
+        for i in 0..n {
+            let key = point_key(path.points[i]);
+            let entry = neighbours.entry(key).or_default();
+            if i > 0 || path.closed {
+                entry.insert(point_key(path.points[(i + n - 1) % n]));
+            }
+            if i + 1 < n || path.closed {
+                entry.insert(point_key(path.points[(i + 1) % n]));
+            }
+            entry.remove(&key);
+        }
+    }
+    let is_node = |p: DVec2| {
+        neighbours
+            .get(&point_key(p))
+            .is_none_or(|set| set.len() != 2)
+    };
+
+    let mut arcs_by_key: HashMap<Vec<PointKey>, Vec<DVec2>> = HashMap::new();
+    let mut fixed_segments = FixedSegments::new(spatial_grid.cell_size as f64);
+    let mut results = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        let points = &path.points;
+        if points.len() < 3 {
+            results.push(path.clone());
+            continue;
+        }
+
+        // Rotate rings so they start on a node; lock two opposite vertices if there is none
+        let mut walk: Vec<DVec2> = points.clone();
+        let mut breaks: Vec<usize> = Vec::new();
+        if path.closed {
+            match walk.iter().position(|p| is_node(*p)) {
+                Some(start) => walk.rotate_left(start),
+                None => breaks.push(walk.len() / 2),
+            }
+            walk.push(walk[0]);
+        }
+        breaks.extend((1..walk.len() - 1).filter(|&i| is_node(walk[i])));
+        breaks.push(walk.len() - 1);
+        breaks.sort_unstable();
+        breaks.dedup();
+
+        let mut simplified: Vec<DVec2> = vec![walk[0]];
+        let mut arc_start = 0;
+        for &arc_end in &breaks {
+            let arc = &walk[arc_start..=arc_end];
+            let forward: Vec<PointKey> = arc.iter().map(|p| point_key(*p)).collect();
+            let backward: Vec<PointKey> = forward.iter().rev().copied().collect();
+            let reversed = backward < forward;
+            let canonical = if reversed { backward } else { forward };
+
+            let result = match arcs_by_key.get(&canonical) {
+                Some(done) => done.clone(),
+                None => {
+                    let oriented: Vec<DVec2> = if reversed {
+                        arc.iter().rev().copied().collect()
+                    } else {
+                        arc.to_vec()
+                    };
+                    let done = simplify_arc(&oriented, min_area, spatial_grid, &fixed_segments);
+                    for w in done.windows(2) {
+                        fixed_segments.insert(w[0], w[1]);
+                    }
+                    arcs_by_key.insert(canonical, done.clone());
+                    done
+                }
+            };
+            if reversed {
+                simplified.extend(result.iter().rev().skip(1));
+            } else {
+                simplified.extend(result.iter().skip(1));
+            }
+            arc_start = arc_end;
+        }
+
+        if path.closed {
+            simplified.pop();
+        }
+        results.push(SimplifyPath {
+            points: simplified,
+            closed: path.closed,
+        });
+    }
+    results
+}
+
+fn simplify_arc(
+    arc: &[DVec2],
+    min_area: f64,
+    spatial_grid: &SpatialGrid,
+    fixed_segments: &FixedSegments,
+) -> Vec<DVec2> {
+    let n = arc.len();
+    if n < 3 {
+        return arc.to_vec();
+    }
+    let own_keys: HashSet<PointKey> = arc.iter().map(|p| point_key(*p)).collect();
+    let mut prev: Vec<usize> = (0..n).map(|i| i.saturating_sub(1)).collect();
+    let mut next: Vec<usize> = (0..n).map(|i| (i + 1).min(n - 1)).collect();
+    let mut removed = vec![false; n];
+    let mut version = vec![0u32; n];
+
+    let mut heap = BinaryHeap::new();
+    for i in 1..n - 1 {
+        heap.push(Candidate {
+            area: triangle_area(arc[i - 1], arc[i], arc[i + 1]),
+            index: i,
+            version: 0,
+        });
+    }
+
+    while let Some(candidate) = heap.pop() {
+        let i = candidate.index;
+        if removed[i] || candidate.version != version[i] {
+            continue;
+        }
+        if candidate.area >= min_area {
+            break;
+        }
+        let (a, c) = (prev[i], next[i]);
+        if !shortcut_is_safe(arc, a, c, &next, &own_keys, spatial_grid, fixed_segments) {
+            continue;
+        }
+
+        removed[i] = true;
+        next[a] = c;
+        prev[c] = a;
+        for j in [a, c] {
+            if j == 0 || j == n - 1 {
+                continue;
+            }
+            version[j] += 1;
+            heap.push(Candidate {
+                area: triangle_area(arc[prev[j]], arc[j], arc[next[j]]),
+                index: j,
+                version: version[j],
+            });
+        }
+    }
+
+    (0..n).filter(|&i| !removed[i]).map(|i| arc[i]).collect()
+}
+
+fn shortcut_is_safe(
+    arc: &[DVec2],
+    a: usize,
+    c: usize,
+    next: &[usize],
+    own_keys: &HashSet<PointKey>,
+    spatial_grid: &SpatialGrid,
+    fixed_segments: &FixedSegments,
+) -> bool {
+    let (start, end) = (arc[a], arc[c]);
+    // The area swept by the shortcut is the original stretch it replaces
+    let swept = &arc[a..=c];
+    let (mut min, mut max) = (start.min(end), start.max(end));
+    for p in swept {
+        min = min.min(*p);
+        max = max.max(*p);
+    }
+    let in_swept = |p: DVec2| swept.len() > 2 && point_in_ring(p, swept);
+    let inside = |p: DVec2| !own_keys.contains(&point_key(p)) && in_swept(p);
+
+    for seg_id in spatial_grid.query_rect(to_render(min), to_render(max)) {
+        let Some(seg) = spatial_grid.get(seg_id) else {
+            continue;
+        };
+        let (p, q) = (seg.start, seg.end);
+        if own_keys.contains(&point_key(p)) && own_keys.contains(&point_key(q)) {
+            continue;
+        }
+        if crosses(start, end, p, q) || inside(p) || inside(q) {
+            return false;
+        }
+    }
+    for &(p, q) in fixed_segments.near(min, max) {
+        if crosses(start, end, p, q) || inside(p) || inside(q) {
+            return false;
+        }
+    }
+
+    // Remaining segments of this arc, other than the two that touch the shortcut
+    let mut j = 0;
+    while j < arc.len() - 1 {
+        let k = next[j];
+        if (k <= a || j >= c) && crosses(start, end, arc[j], arc[k]) {
+            return false;
+        }
+        if (j < a && in_swept(arc[j])) || (k > c && in_swept(arc[k])) {
+            return false;
+        }
+        j = k;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zigzag() -> Vec<DVec2> {
+        (0..=8)
+            .map(|i| DVec2::new(i as f64, if i % 2 == 0 { 0.0 } else { 0.01 }))
+            .collect()
+    }
+
+    #[test]
+    fn shared_arcs_simplify_the_same_way() {
+        let grid = SpatialGrid::new(5.0);
+        let mut above = zigzag();
+        above.extend([DVec2::new(8.0, 5.0), DVec2::new(0.0, 5.0)]);
+        let mut below: Vec<DVec2> = zigzag().into_iter().rev().collect();
+        below.extend([DVec2::new(0.0, -5.0), DVec2::new(8.0, -5.0)]);
+        let paths = [
+            SimplifyPath {
+                points: above,
+                closed: true,
+            },
+            SimplifyPath {
+                points: below,
+                closed: true,
+            },
+        ];
+        let result = simplify_preserving_topology(&paths, 0.1, &grid);
+        let shared = |path: &SimplifyPath| {
+            let mut keys: Vec<PointKey> = path
+                .points
+                .iter()
+                .filter(|p| p.y.abs() < 1.0)
+                .map(|p| point_key(*p))
+                .collect();
+            keys.sort_unstable();
+            keys
+        };
+        assert_eq!(shared(&result[0]), shared(&result[1]));
+        assert!(result[0].points.len() < paths[0].points.len());
+    }
+
+    #[test]
+    fn shortcuts_never_cross_other_borders() {
+        let line = SimplifyPath {
+            points: zigzag(),
+            closed: false,
+        };
+        let free =
+            simplify_preserving_topology(std::slice::from_ref(&line), 0.1, &SpatialGrid::new(5.0));
+        assert_eq!(free[0].points, vec![DVec2::ZERO, DVec2::new(8.0, 0.0)]);
+
+        let mut grid = SpatialGrid::new(5.0);
+        grid.insert_segment(DVec2::new(4.0, 0.005), DVec2::new(4.0, -3.0));
+        let blocked = simplify_preserving_topology(&[line], 0.1, &grid);
+        for pair in blocked[0].points.windows(2) {
+            assert!(!crosses(
+                pair[0],
+                pair[1],
+                DVec2::new(4.0, 0.005),
+                DVec2::new(4.0, -3.0)
+            ));
+        }
+    }
+}
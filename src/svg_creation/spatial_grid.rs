@@ -1,282 +1,618 @@
+use bevy::math::DVec2;
 use bevy::prelude::*;
+use bevy::tasks::{block_on, poll_once, AsyncComputeTaskPool, Task, TaskPool};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
 
-use crate::math_utils::closest_point_on_segment;
+use crate::geometry64::{
+    closest_point_on_segment, segment_intersection_point, segment_intersects_rect, snap_round, to_world,
+};
+use crate::spatial_index::{boxes_overlap, new_index, segment_box, Slot, SpatialIndex, SpatialIndexKind};
 
+/// Stroke ends closer than this to a border are welded onto it.
+const WELD_TOLERANCE: f64 = 1.0e-3;
+/// Grid that welded vertices are rounded to.
+const SNAP_ROUNDING: f64 = 1.0e-6;
+/// Cell size of the uniform grid, and the smallest quadtree node.
+const CELL_SIZE: f32 = 5.0;
+
+/// A stored border segment. Endpoints are f64 so snapped strokes join it exactly.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct PathSegment {
-    pub start: Vec2,
-    pub end: Vec2,
-    pub id: usize,
+    pub start: DVec2,
+    pub end: DVec2,
+}
+
+/// Handle to a segment in the grid. Slots are reused after removal, but each reuse bumps
+/// the generation, so a handle to a removed segment never resolves to its replacement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SegmentId {
+    index: u32,
+    generation: u32,
+}
+
+impl SegmentId {
+    pub fn index(self) -> usize {
+        self.index as usize
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct SegmentSlot {
+    generation: u32,
+    segment: Option<PathSegment>,
+}
+
+/// A segment added to or taken out of the grid. Splits show up as the old segment removed
+/// and its halves added.
+#[derive(Clone, Copy, Debug)]
+pub enum SegmentChange {
+    Added(SegmentId, PathSegment),
+    Removed(SegmentId),
 }
 
+enum IndexEdit {
+    Insert(Slot, PathSegment),
+    Remove(Slot, PathSegment),
+}
+
+/// An index being built off the main thread, plus the edits made since it started, to be
+/// replayed on it once it's done.
+struct PendingRebuild {
+    kind: SpatialIndexKind,
+    task: Task<Box<dyn SpatialIndex>>,
+    edits: Vec<IndexEdit>,
+}
+
+/// The stored borders. Segments live in generational slots here, and every lookup goes
+/// through whichever `SpatialIndex` backend is selected.
 #[derive(Resource)]
 pub struct SpatialGrid {
-    pub bounds: Rect,
     pub cell_size: f32,
-    pub cols: usize,
-    pub rows: usize,
-    pub cells: Vec<Vec<usize>>,
-    pub segments: Vec<PathSegment>,
+    index: Box<dyn SpatialIndex>,
+    rebuilding: Option<PendingRebuild>,
+    /// Segments were bulk loaded past the index and the build that covers them is still
+    /// running; lookups scan every slot until it's swapped in rather than miss them.
+    unindexed: bool,
+    slots: Vec<SegmentSlot>,
+    free: Vec<u32>,
+    live: usize,
+    /// Changes not yet picked up by `take_changes`.
+    changes: Vec<SegmentChange>,
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct Rect {
-    pub min: Vec2,
-    pub max: Vec2,
+/// Where a point sits on the stored borders: a segment id and the exact spot on it.
+pub type BorderPosition = (SegmentId, DVec2);
+
+/// The borders within `reach` of a start position, walking along connected segments. Each
+/// vertex reached keeps its distance from the start, so asking whether some other point is
+/// within reach is a lookup rather than a search.
+#[derive(Clone, Debug)]
+pub struct BlockedZone {
+    from: BorderPosition,
+    reach: f64,
+    vertices: HashMap<(u64, u64), f64>,
+}
+
+/// f64 bits make exact vertex keys, and for the non-negative distances here they also sort
+/// the same as the values.
+fn vertex_key(p: DVec2) -> (u64, u64) {
+    (p.x.to_bits(), p.y.to_bits())
+}
+
+impl BlockedZone {
+    /// Distance along the borders from the start to `to`, if it's within reach.
+    pub fn distance_to(&self, grid: &SpatialGrid, to: BorderPosition) -> Option<f64> {
+        let (to_id, to_point) = to;
+        let to_seg = grid.get(to_id)?;
+        let mut best = if to_id == self.from.0 { self.from.1.distance(to_point) } else { f64::INFINITY };
+        for end in [to_seg.start, to_seg.end] {
+            if let Some(dist) = self.vertices.get(&vertex_key(end)) {
+                best = best.min(dist + end.distance(to_point));
+            }
+        }
+        (best <= self.reach).then_some(best)
+    }
+
+    pub fn contains(&self, grid: &SpatialGrid, to: BorderPosition) -> bool {
+        self.distance_to(grid, to).is_some()
+    }
 }
 
 #[derive(Resource)]
 pub struct SnapState {
-    pub initial_seg_id: Option<usize>,
-    pub blocked_range: usize,
-    pub initial_snap_pos: Option<Vec2>,
-    pub is_blocking: bool,
+    /// How far along the border from where the stroke started snapping stays blocked.
+    pub blocked_distance: f32,
+    /// The blocked stretch of border, found once when snapping starts.
+    pub blocked_zone: Option<BlockedZone>,
     pub is_enabled: bool,
 }
 
+impl SnapState {
+    /// The stroke left the border at `from`: block snapping back on to it nearby.
+    pub fn start_blocking(&mut self, grid: &SpatialGrid, from: BorderPosition) {
+        self.blocked_zone = grid.blocked_zone(from, self.blocked_distance as f64);
+    }
+
+    pub fn stop_blocking(&mut self) {
+        self.blocked_zone = None;
+    }
+}
+
 pub struct SpatialGridPlugin;
 
 impl Plugin for SpatialGridPlugin {
     fn build(&self, app: &mut App) {
         app
-            .insert_resource(SpatialGrid::new(50.0))
+            .insert_resource(SpatialGrid::new(CELL_SIZE))
             .insert_resource(SnapState {
-                initial_seg_id: None,
-                blocked_range: 20,
-                initial_snap_pos: None,
-                is_blocking: false,
+                blocked_distance: 50.0,
+                blocked_zone: None,
                 is_enabled: false,
-            });
+            })
+            .add_systems(Update, finish_index_rebuild_system);
+    }
+}
+
+/// Swaps in an index built in the background once it's ready.
+fn finish_index_rebuild_system(mut spatial_grid: ResMut<SpatialGrid>) {
+    if spatial_grid.is_rebuilding() {
+        spatial_grid.finish_rebuild();
     }
 }
 
 impl SpatialGrid {
-    pub fn new(_cell_size: f32) -> Self {
+    pub fn new(cell_size: f32) -> Self {
         Self {
-            bounds: Rect {
-                min: Vec2::ZERO,
-                max: Vec2::ZERO,
-            },
-            cell_size: 5.0,
-            cols: 0,
-            rows: 0,
-            cells: Vec::new(),
-            segments: Vec::new(),
+            cell_size,
+            index: new_index(SpatialIndexKind::default(), cell_size),
+            rebuilding: None,
+            unindexed: false,
+            slots: Vec::new(),
+            free: Vec::new(),
+            live: 0,
+            changes: Vec::new(),
         }
     }
 
-    pub fn set_bounds(&mut self, min: Vec2, max: Vec2) {
-        self.bounds.min = min;
-        self.bounds.max = max;
-        self.recalc_grid();
+    pub fn index(&self) -> &dyn SpatialIndex {
+        self.index.as_ref()
     }
 
-    pub fn set_bounds_from_viewbox(&mut self, viewbox: (f32, f32, f32, f32)) {
-        let (_min_x, _min_y, width, height) = viewbox;
-        self.bounds.min = Vec2::new(-width / 2.0, -height / 2.0);
-        self.bounds.max = Vec2::new(width / 2.0, height / 2.0);
-        self.recalc_grid();
+    /// The backend in use, or the one being built to replace it.
+    pub fn index_kind(&self) -> SpatialIndexKind {
+        self.rebuilding.as_ref().map_or(self.index.kind(), |pending| pending.kind)
     }
 
-    pub fn recalc_grid(&mut self) {
-        let width = self.bounds.max.x - self.bounds.min.x;
-        let height = self.bounds.max.y - self.bounds.min.y;
-        self.cols = (width / self.cell_size).ceil() as usize;
-        self.rows = (height / self.cell_size).ceil() as usize;
-        self.cells = vec![Vec::new(); self.cols * self.rows];
+    /// Swaps in a different backend and indexes everything stored into it, blocking.
+    pub fn set_index_kind(&mut self, kind: SpatialIndexKind) {
+        self.rebuilding = None;
+        self.index = new_index(kind, self.cell_size);
+        self.rebuild_grid();
     }
 
-    fn cell_index(&self, col: usize, row: usize) -> usize {
-        row * self.cols + col
+    pub fn is_rebuilding(&self) -> bool {
+        self.rebuilding.is_some()
     }
 
-    fn point_to_cell(&self, point: Vec2) -> Option<(usize, usize)> {
-        if point.x < self.bounds.min.x || point.x > self.bounds.max.x || point.y < self.bounds.min.y || point.y > self.bounds.max.y {
-            return None;
-        }
-        let col = ((point.x - self.bounds.min.x) / self.cell_size).floor() as usize;
-        let row = ((point.y - self.bounds.min.y) / self.cell_size).floor() as usize;
-        if col >= self.cols || row >= self.rows {
-            return None;
+    /// Builds a fresh `kind` index of everything stored on the async compute pool. The
+    /// current index keeps answering queries meanwhile, and edits made in the meantime are
+    /// replayed on the new one when `finish_rebuild` swaps it in. Any build already running
+    /// is dropped.
+    pub fn rebuild_in_background(&mut self, kind: SpatialIndexKind) {
+        self.rebuild_in_background_then(kind, |_, _| {});
+    }
+
+    /// Same, running `after_build` on the task pool with the snapshot and the finished index
+    /// before it's handed back. Edits made meanwhile aren't in either yet.
+    pub fn rebuild_in_background_then(
+        &mut self,
+        kind: SpatialIndexKind,
+        after_build: impl FnOnce(&[(Slot, PathSegment)], &dyn SpatialIndex) + Send + 'static,
+    ) {
+        let live = self.snapshot();
+        let mut index = new_index(kind, self.cell_size);
+        let task = AsyncComputeTaskPool::get_or_init(TaskPool::default).spawn(async move {
+            index.rebuild(&live);
+            after_build(&live, index.as_ref());
+            index
+        });
+        self.rebuilding = Some(PendingRebuild { kind, task, edits: Vec::new() });
+    }
+
+    /// Swaps in the background build if it has finished. True once it has.
+    pub fn finish_rebuild(&mut self) -> bool {
+        let Some(pending) = self.rebuilding.as_mut() else {
+            return false;
+        };
+        let Some(index) = block_on(poll_once(&mut pending.task)) else {
+            return false;
+        };
+        self.install_rebuilt(index);
+        true
+    }
+
+    /// Blocks until the background build is done and swaps it in.
+    pub fn wait_for_rebuild(&mut self) {
+        if let Some(pending) = self.rebuilding.as_mut() {
+            let index = block_on(&mut pending.task);
+            self.install_rebuilt(index);
         }
-        Some((col, row))
     }
 
-    pub fn remove_segment(&mut self, id: usize) {
-        if id >= self.segments.len() {
+    fn install_rebuilt(&mut self, mut index: Box<dyn SpatialIndex>) {
+        let Some(pending) = self.rebuilding.take() else {
             return;
+        };
+        for edit in &pending.edits {
+            match edit {
+                IndexEdit::Insert(slot, segment) => index.insert(*slot, segment),
+                IndexEdit::Remove(slot, segment) => index.remove(*slot, segment),
+            }
         }
-        let segment = self.segments[id];
-        let min_x = segment.start.x.min(segment.end.x);
-        let max_x = segment.start.x.max(segment.end.x);
-        let min_y = segment.start.y.min(segment.end.y);
-        let max_y = segment.start.y.max(segment.end.y);
-
-        let start_cell = self.point_to_cell(Vec2::new(min_x, min_y));
-        let end_cell = self.point_to_cell(Vec2::new(max_x, max_y));
-        if start_cell.is_none() || end_cell.is_none() {
-            return;
+        self.index = index;
+        self.unindexed = false;
+        if !pending.edits.is_empty() {
+            // The replayed edits went in one at a time, outside the bulk-built layout
+            self.repack_index();
         }
-        let (start_col, start_row) = start_cell.unwrap();
-        let (end_col, end_row) = end_cell.unwrap();
+        println!(
+            "Background rebuild done: {} segments {} edits replayed {}",
+            self.index.describe(),
+            self.live,
+            pending.edits.len()
+        );
+    }
 
-        for col in start_col.min(end_col)..=start_col.max(end_col) {
-            for row in start_row.min(end_row)..=start_row.max(end_row) {
-                let idx = self.cell_index(col, row);
-                self.cells[idx].retain(|&seg_id| seg_id != id);
-            }
+    fn index_insert(&mut self, slot: Slot, segment: PathSegment) {
+        self.index.insert(slot, &segment);
+        if let Some(pending) = self.rebuilding.as_mut() {
+            pending.edits.push(IndexEdit::Insert(slot, segment));
         }
-        self.segments[id] = PathSegment {
-            start: Vec2::ZERO,
-            end: Vec2::ZERO,
-            id,
-        };
     }
 
-    pub fn rebuild_grid(&mut self) {
-        if self.segments.is_empty() {
-            println!("No segments to build grid from.");
-            return;
+    fn index_remove(&mut self, slot: Slot, segment: PathSegment) {
+        self.index.remove(slot, &segment);
+        if let Some(pending) = self.rebuilding.as_mut() {
+            pending.edits.push(IndexEdit::Remove(slot, segment));
         }
-        let mut min_x = f32::INFINITY;
-        let mut min_y = f32::INFINITY;
-        let mut max_x = f32::NEG_INFINITY;
-        let mut max_y = f32::NEG_INFINITY;
+    }
 
-        for seg in &self.segments {
-            if seg.start == seg.end {
-                continue;
-            }
-            min_x = min_x.min(seg.start.x.min(seg.end.x));
-            min_y = min_y.min(seg.start.y.min(seg.end.y));
-            max_x = max_x.max(seg.start.x.max(seg.end.x));
-            max_y = max_y.max(seg.start.y.max(seg.end.y));
+    pub fn get(&self, id: SegmentId) -> Option<&PathSegment> {
+        let slot = self.slots.get(id.index())?;
+        if slot.generation != id.generation {
+            return None;
         }
+        slot.segment.as_ref()
+    }
+
+    /// Handle of the segment living in `slot`, if any.
+    fn id_at(&self, slot: Slot) -> Option<SegmentId> {
+        let entry = self.slots.get(slot as usize)?;
+        entry.segment.as_ref()?;
+        Some(SegmentId { index: slot, generation: entry.generation })
+    }
+
+    /// Live segments and their handles, in slot order.
+    pub fn iter(&self) -> impl Iterator<Item = (SegmentId, &PathSegment)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let id = SegmentId { index: index as u32, generation: slot.generation };
+            slot.segment.as_ref().map(|segment| (id, segment))
+        })
+    }
+
+    /// Every live segment by slot, to hand to a background task.
+    pub fn snapshot(&self) -> Vec<(Slot, PathSegment)> {
+        self.iter().map(|(id, seg)| (id.index, *seg)).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.live
+    }
 
-        if min_x > max_x || min_y > max_y {
-            println!("SpatialGrid: bounds calculation failed (min > max), using fallback bounds.");
-            self.bounds.min = Vec2::ZERO;
-            self.bounds.max = Vec2::ZERO;
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    fn allocate(&mut self, segment: PathSegment) -> SegmentId {
+        self.live += 1;
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index as usize];
+            slot.segment = Some(segment);
+            SegmentId { index, generation: slot.generation }
         } else {
-            self.bounds.min = Vec2::new(min_x, min_y);
-            self.bounds.max = Vec2::new(max_x, max_y);
+            self.slots.push(SegmentSlot { generation: 0, segment: Some(segment) });
+            SegmentId { index: (self.slots.len() - 1) as u32, generation: 0 }
         }
+    }
 
-        println!(
-            "After rebuild: bounds min {:?} max {:?} cols {} rows {}",
-            self.bounds.min, self.bounds.max, self.cols, self.rows
-        );
-        let degenerate = self.segments.iter().filter(|s| s.start == s.end).count();
-        println!("Degenerate segments: {}", degenerate);
+    /// Everything added and removed since the last call, in order. Bulk loads aren't in
+    /// here: they're whole maps, whose topology gets built in one go from `snapshot`.
+    pub fn take_changes(&mut self) -> Vec<SegmentChange> {
+        std::mem::take(&mut self.changes)
+    }
 
-        self.recalc_grid();
-        println!(
-            "Grid recalculated: min {:?} max {:?} cols {} rows {}",
-            self.bounds.min, self.bounds.max, self.cols, self.rows
-        );
+    /// Adds segments in bulk for loading whole maps, and rebuilds the index once in the
+    /// background so a detailed map doesn't freeze the window.
+    pub fn load_segments(&mut self, segments: impl IntoIterator<Item = PathSegment>) -> Vec<SegmentId> {
+        self.load_segments_then(segments, |_, _| {})
+    }
 
-        self.cells.iter_mut().for_each(|cell| cell.clear());
+    /// `load_segments` with a hook that gets the loaded segments and the built index, e.g.
+    /// to cache them, without holding up the main thread.
+    pub fn load_segments_then(
+        &mut self,
+        segments: impl IntoIterator<Item = PathSegment>,
+        after_build: impl FnOnce(&[(Slot, PathSegment)], &dyn SpatialIndex) + Send + 'static,
+    ) -> Vec<SegmentId> {
+        let ids = segments.into_iter().map(|segment| self.allocate(segment)).collect();
+        self.rebuild_in_background_then(self.index_kind(), after_build);
+        self.unindexed = true;
+        ids
+    }
 
-        for (seg_id, seg) in self.segments.iter().enumerate() {
-            if seg.start == seg.end {
+    /// Loads segments together with an index already built over them, slot `i` holding
+    /// `segments[i]`. Only an empty grid can take it as is; otherwise the slots wouldn't line
+    /// up, so it falls back to building a fresh index.
+    pub fn load_prebuilt(&mut self, segments: Vec<PathSegment>, index: Box<dyn SpatialIndex>) -> Vec<SegmentId> {
+        if !self.slots.is_empty() || self.rebuilding.is_some() {
+            return self.load_segments(segments);
+        }
+        let ids = segments.into_iter().map(|segment| self.allocate(segment)).collect();
+        self.index = index;
+        ids
+    }
+
+    /// Folds the index's pending edits back into its packed layout and hands free slots
+    /// out lowest index first. Nothing is moved, so every live handle stays valid; the slot
+    /// array itself keeps its holes.
+    pub fn repack_index(&mut self) {
+        self.free.sort_unstable_by(|a, b| b.cmp(a));
+        self.index.compact();
+    }
+
+    /// Ids of the segments that touch the rectangle, edges included, without duplicates.
+    pub fn query_rect(&self, min: Vec2, max: Vec2) -> Vec<SegmentId> {
+        let (min, max) = (to_world(min), to_world(max));
+        self.candidates(min, max)
+            .into_iter()
+            .filter(|&id| self.get(id).is_some_and(|seg| segment_intersects_rect(seg.start, seg.end, min, max)))
+            .collect()
+    }
+
+    /// Ids of the segments coming within `radius` of `center`, closest first.
+    pub fn query_radius(&self, center: DVec2, radius: f64) -> Vec<SegmentId> {
+        let mut hits: Vec<(f64, SegmentId)> = self
+            .candidates(center - radius, center + radius)
+            .into_iter()
+            .filter_map(|id| {
+                let seg = self.get(id)?;
+                let dist = closest_point_on_segment(center, seg.start, seg.end).distance(center);
+                (dist <= radius).then_some((dist, id))
+            })
+            .collect();
+        hits.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        hits.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// Every segment `a`-`b` crosses, with the crossing point, in order going from `a`.
+    pub fn segments_crossing(&self, a: DVec2, b: DVec2) -> Vec<(SegmentId, DVec2)> {
+        let mut hits: Vec<(SegmentId, DVec2)> = self
+            .candidates(a.min(b), a.max(b))
+            .into_iter()
+            .filter_map(|id| {
+                let seg = self.get(id)?;
+                segment_intersection_point(a, b, seg.start, seg.end).map(|point| (id, point))
+            })
+            .collect();
+        hits.sort_by(|x, y| x.1.distance_squared(a).total_cmp(&y.1.distance_squared(a)).then(x.0.cmp(&y.0)));
+        hits
+    }
+
+    /// Ids from the index whose segments might touch `min..max`, without duplicates.
+    fn candidates(&self, min: DVec2, max: DVec2) -> Vec<SegmentId> {
+        if self.unindexed {
+            return self
+                .iter()
+                .filter(|(_, seg)| {
+                    let (seg_min, seg_max) = segment_box(seg);
+                    boxes_overlap(seg_min, seg_max, min, max)
+                })
+                .map(|(id, _)| id)
+                .collect();
+        }
+        let mut slots = Vec::new();
+        self.index.query_rect(min, max, &mut slots);
+        slots.sort_unstable();
+        slots.dedup();
+        slots.into_iter().filter_map(|slot| self.id_at(slot)).collect()
+    }
+
+    /// Takes the segment out of the grid and frees its slot. Stale handles return None.
+    #[allow(dead_code)]
+    pub fn remove_segment(&mut self, id: SegmentId) -> Option<PathSegment> {
+        let segment = *self.get(id)?;
+        self.index_remove(id.index, segment);
+        let slot = &mut self.slots[id.index()];
+        slot.segment = None;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(id.index);
+        self.live -= 1;
+        self.changes.push(SegmentChange::Removed(id));
+        Some(segment)
+    }
+
+    /// Adds a segment and indexes it; nothing else is rebuilt.
+    pub fn insert_segment(&mut self, start: DVec2, end: DVec2) -> SegmentId {
+        let segment = PathSegment { start, end };
+        let id = self.allocate(segment);
+        self.changes.push(SegmentChange::Added(id, segment));
+        self.index_insert(id.index, segment);
+        id
+    }
+
+    /// Cuts segment `id` in two at `at`. `id` keeps the first half and the second half gets
+    /// a new id; the index is updated in place, no rebuild needed.
+    pub fn split_segment(&mut self, id: SegmentId, at: DVec2) -> Option<SegmentId> {
+        let old = *self.get(id)?;
+        if old.start == at || old.end == at || old.start == old.end {
+            return None;
+        }
+        let first = PathSegment { start: old.start, end: at };
+        self.index_remove(id.index, old);
+        self.slots[id.index()].segment = Some(first);
+        self.index_insert(id.index, first);
+        self.changes.push(SegmentChange::Removed(id));
+        self.changes.push(SegmentChange::Added(id, first));
+        Some(self.insert_segment(at, old.end))
+    }
+
+    /// Makes `point` a vertex of the border it lies on and returns that vertex: an existing
+    /// endpoint if one is within the weld tolerance, otherwise the snap-rounded point, with
+    /// the border segment split there. Points not on any border come back unchanged.
+    pub fn weld_point(&mut self, point: DVec2) -> DVec2 {
+        let mut best: Option<(f64, SegmentId, DVec2)> = None;
+        for seg_id in self.candidates(point - DVec2::ONE, point + DVec2::ONE) {
+            let Some(segment) = self.get(seg_id) else {
                 continue;
+            };
+            let on_segment = closest_point_on_segment(point, segment.start, segment.end);
+            let dist = on_segment.distance(point);
+            if dist <= WELD_TOLERANCE && best.is_none_or(|(best_dist, _, _)| dist < best_dist) {
+                best = Some((dist, seg_id, on_segment));
             }
-            let min_x = seg.start.x.min(seg.end.x);
-            let max_x = seg.start.x.max(seg.end.x);
-            let min_y = seg.start.y.min(seg.end.y);
-            let max_y = seg.start.y.max(seg.end.y);
-
-            let start_cell = self.point_to_cell(Vec2::new(min_x, min_y));
-            let end_cell = self.point_to_cell(Vec2::new(max_x, max_y));
-            if start_cell.is_none() || end_cell.is_none() {
-                continue;
+        }
+        let Some((_, seg_id, on_segment)) = best else {
+            return point;
+        };
+
+        let Some(&segment) = self.get(seg_id) else {
+            return point;
+        };
+        for end in [segment.start, segment.end] {
+            if end.distance(on_segment) <= WELD_TOLERANCE {
+                return end;
             }
-            let (start_col, start_row) = start_cell.unwrap();
-            let (end_col, end_row) = end_cell.unwrap();
+        }
+        let vertex = snap_round(on_segment, SNAP_ROUNDING);
+        self.split_segment(seg_id, vertex);
+        vertex
+    }
 
-            for col in start_col.min(end_col)..=start_col.max(end_col) {
-                for row in start_row.min(end_row)..=start_row.max(end_row) {
-                    let idx = self.cell_index(col, row);
-                    self.cells[idx].push(seg_id);
+    /// Ids of the segments with an endpoint exactly at `point`.
+    fn segments_at(&self, point: DVec2) -> Vec<SegmentId> {
+        // A hair of slack, in case the vertex sits on a cell edge
+        let slack = DVec2::splat(self.cell_size as f64 * 1.0e-3);
+        self.candidates(point - slack, point + slack)
+            .into_iter()
+            .filter(|&id| {
+                self.get(id).is_some_and(|seg| seg.start != seg.end && (seg.start == point || seg.end == point))
+            })
+            .collect()
+    }
+
+    /// Dijkstra over segment endpoints out to `reach` from `from`. None if `from`'s segment
+    /// is gone.
+    pub fn blocked_zone(&self, from: BorderPosition, reach: f64) -> Option<BlockedZone> {
+        let (from_id, from_point) = from;
+        let from_seg = self.get(from_id)?;
+        let mut vertices: HashMap<(u64, u64), f64> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        for end in [from_seg.start, from_seg.end] {
+            queue.push(Reverse((from_point.distance(end).to_bits(), vertex_key(end))));
+        }
+        while let Some(Reverse((dist_bits, node_key))) = queue.pop() {
+            let dist = f64::from_bits(dist_bits);
+            if dist > reach {
+                break;
+            }
+            if vertices.contains_key(&node_key) {
+                continue;
+            }
+            vertices.insert(node_key, dist);
+            let node = DVec2::new(f64::from_bits(node_key.0), f64::from_bits(node_key.1));
+            for seg_id in self.segments_at(node) {
+                let Some(seg) = self.get(seg_id) else {
+                    continue;
+                };
+                let other = if seg.start == node { seg.end } else { seg.start };
+                if !vertices.contains_key(&vertex_key(other)) {
+                    let next = dist + seg.start.distance(seg.end);
+                    queue.push(Reverse((next.to_bits(), vertex_key(other))));
                 }
             }
         }
+        Some(BlockedZone { from, reach, vertices })
+    }
+
+    /// Rebuilds the index on this thread, taking over the kind of any background build.
+    pub fn rebuild_grid(&mut self) {
+        if let Some(pending) = self.rebuilding.take() {
+            self.index = new_index(pending.kind, self.cell_size);
+        }
+        self.unindexed = false;
+        if self.is_empty() {
+            println!("No segments to build grid from.");
+            return;
+        }
+        let live: Vec<(Slot, PathSegment)> = self.iter().map(|(id, seg)| (id.index, *seg)).collect();
+        self.index.rebuild(&live);
+        println!("After rebuild: {} segments {}", self.index.describe(), live.len());
+    }
+
+    /// Closest segment to `pos` within `radius` that `accept` agrees to. Exact for any
+    /// radius, and `pos` may be anywhere. `accept` only sees candidates closer than the best
+    /// so far, so expensive checks run rarely.
+    pub fn nearest_segment_where(
+        &self,
+        pos: DVec2,
+        radius: f64,
+        mut accept: impl FnMut(SegmentId, &PathSegment, DVec2) -> bool,
+    ) -> Option<(SegmentId, DVec2, f64)> {
+        let mut test = |slot: Slot, reach: f64| {
+            let seg_id = self.id_at(slot)?;
+            let segment = self.get(seg_id)?;
+            let pt = closest_point_on_segment(pos, segment.start, segment.end);
+            let dist = pt.distance(pos);
+            (dist < reach && accept(seg_id, segment, pt)).then_some(dist)
+        };
+        let slot = if self.unindexed {
+            let mut reach = radius;
+            let mut best = None;
+            for slot in 0..self.slots.len() as Slot {
+                if let Some(dist) = test(slot, reach) {
+                    reach = dist;
+                    best = Some(slot);
+                }
+            }
+            best
+        } else {
+            self.index.nearest(pos, radius, &mut test)
+        };
+        let seg_id = self.id_at(slot?)?;
+        let segment = self.get(seg_id)?;
+        let pt = closest_point_on_segment(pos, segment.start, segment.end);
+        Some((seg_id, pt, pt.distance(pos)))
     }
 
     pub fn query_nearest_point(
         &self,
         pos: Vec2,
         snap_radius: f32,
-        initial_seg_id: Option<usize>,
-        blocked_range: usize,
-    ) -> Option<(Vec2, usize)> {
-        let cell_opt = self.point_to_cell(pos)?;
-        let (col, row) = cell_opt;
-
-        let mut closest_point = None;
-        let mut closest_dist = snap_radius;
-        let mut closest_id = None;
-
-        for cx in col.saturating_sub(1)..=(col + 1).min(self.cols - 1) {
-            for cy in row.saturating_sub(1)..=(row + 1).min(self.rows - 1) {
-                let idx = self.cell_index(cx, cy);
-                for &seg_id in &self.cells[idx] {
-                    if let Some(initial_id) = initial_seg_id {
-                        if seg_id >= initial_id.saturating_sub(blocked_range)
-                            && seg_id <= initial_id + blocked_range
-                        {
-                            continue;
-                        }
-                    }
-                    let segment = &self.segments[seg_id];
-                    if segment.start == segment.end {
-                        continue;
-                    }
-                    let pt = closest_point_on_segment(pos, segment.start, segment.end);
-                    let dist = pt.distance(pos);
-                    if dist < closest_dist {
-                        closest_dist = dist;
-                        closest_point = Some(pt);
-                        closest_id = Some(seg_id);
-                    }
-                }
-            }
-        }
-        match (closest_point, closest_id) {
-            (Some(pt), Some(id)) => Some((pt, id)),
-            _ => None,
-        }
+        blocked: Option<&BlockedZone>,
+    ) -> Option<(DVec2, SegmentId)> {
+        self.nearest_segment_where(to_world(pos), snap_radius as f64, |seg_id, _, pt| {
+            blocked.is_none_or(|zone| !zone.contains(self, (seg_id, pt)))
+        })
+        .map(|(id, pt, _)| (pt, id))
     }
+
     pub fn query_nearest_segment(
         &self,
         pos: Vec2,
         snap_radius: f32,
-    ) -> Option<(PathSegment, Vec2)> {
-        let cell_opt = self.point_to_cell(pos)?;
-        let (col, row) = cell_opt;
-        let mut closest_seg = None;
-        let mut closest_point = None;
-        let mut closest_dist = snap_radius;
-
-        for cx in col.saturating_sub(1)..=(col + 1).min(self.cols - 1) {
-            for cy in row.saturating_sub(1)..=(row + 1).min(self.rows - 1) {
-                let idx = self.cell_index(cx, cy);
-                for &seg_id in &self.cells[idx] {
-                    let segment = &self.segments[seg_id];
-                    if segment.start == segment.end { continue; }
-                    let pt = closest_point_on_segment(pos, segment.start, segment.end);
-                    let dist = pt.distance(pos);
-                    if dist < closest_dist {
-                        closest_dist = dist;
-                        closest_seg = Some(*segment);
-                        closest_point = Some(pt);
-                    }
-                }
-            }
-        }
-        match (closest_seg, closest_point) {
-            (Some(seg), Some(pt)) => Some((seg, pt)),
-            _ => None,
-        }
+    ) -> Option<(SegmentId, PathSegment, DVec2)> {
+        let (id, pt, _) = self.nearest_segment_where(to_world(pos), snap_radius as f64, |_, _, _| true)?;
+        Some((id, *self.get(id)?, pt))
     }
 }
@@ -0,0 +1,203 @@
+use bevy::math::DVec2;
+use bevy::prelude::*;
+
+use crate::index_cache::{CacheReader, CacheWriter};
+use crate::quadtree::QuadTree;
+use crate::rtree::RTree;
+use crate::spatial_grid::PathSegment;
+use crate::uniform_grid::UniformGrid;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SpatialIndexKind {
+    /// Uniform cells. Cheap to update, but pays for empty ocean as much as for Europe.
+    #[default]
+    Grid,
+    /// Cells split only where segments crowd together.
+    Quadtree,
+    /// Bulk-loaded bounding boxes. Fastest lookups, repacked as strokes are added.
+    RTree,
+}
+
+impl SpatialIndexKind {
+    /// Parses the names used in the settings file: grid, quadtree or rtree.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "grid" => Some(SpatialIndexKind::Grid),
+            "quadtree" => Some(SpatialIndexKind::Quadtree),
+            "rtree" | "r-tree" => Some(SpatialIndexKind::RTree),
+            _ => None,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            SpatialIndexKind::Grid => SpatialIndexKind::Quadtree,
+            SpatialIndexKind::Quadtree => SpatialIndexKind::RTree,
+            SpatialIndexKind::RTree => SpatialIndexKind::Grid,
+        }
+    }
+}
+
+/// Slot of a segment in `SpatialGrid`. Backends store these rather than full `SegmentId`s;
+/// the grid keeps the index in step with its slots, so a slot in the index always means the
+/// segment living there now.
+pub type Slot = u32;
+
+/// Buckets the stored borders for lookups. Backends only keep slots and boxes; the segments
+/// themselves live in `SpatialGrid`, which does the exact geometry through `nearest`'s
+/// callback.
+pub trait SpatialIndex: Send + Sync {
+    fn kind(&self) -> SpatialIndexKind;
+
+    /// Throws the contents away and indexes `segments` in one go.
+    fn rebuild(&mut self, segments: &[(Slot, PathSegment)]);
+
+    fn insert(&mut self, slot: Slot, segment: &PathSegment);
+
+    /// `segment` has to be the one `slot` was inserted with.
+    fn remove(&mut self, slot: Slot, segment: &PathSegment);
+
+    /// Adds every slot whose segment might touch `min..max` to `out`. It may report extra
+    /// slots and the same slot twice, but never misses one.
+    fn query_rect(&self, min: DVec2, max: DVec2, out: &mut Vec<Slot>);
+
+    /// Hands candidates around `pos` to `test`, closest areas first, until nothing left
+    /// could be within `radius` or closer than the best so far. `test` gets the distance to
+    /// beat and returns the candidate's distance if it takes it. Returns the last one taken.
+    fn nearest(
+        &self,
+        pos: DVec2,
+        radius: f64,
+        test: &mut dyn FnMut(Slot, f64) -> Option<f64>,
+    ) -> Option<Slot>;
+
+    /// Outlines of the cells or nodes overlapping `min..max`, for the debug overlay.
+    fn debug_rects(&self, min: Vec2, max: Vec2) -> Vec<(Vec2, Vec2)>;
+
+    /// One line summary for the log.
+    fn describe(&self) -> String;
+
+    /// Bytes held by the index, allocations included.
+    fn memory_bytes(&self) -> usize;
+
+    /// Folds edits made since the last bulk build into the packed layout and trims spare
+    /// capacity. Slots stay as they are.
+    fn compact(&mut self) {}
+
+    /// Writes the built structure for `read_index` to load back as is.
+    fn write_cache(&self, out: &mut CacheWriter);
+}
+
+pub fn new_index(kind: SpatialIndexKind, cell_size: f32) -> Box<dyn SpatialIndex> {
+    match kind {
+        SpatialIndexKind::Grid => Box::new(UniformGrid::new(cell_size)),
+        SpatialIndexKind::Quadtree => Box::new(QuadTree::new(cell_size as f64)),
+        SpatialIndexKind::RTree => Box::new(RTree::new()),
+    }
+}
+
+/// Loads an index `write_cache` wrote. None if the data doesn't hold together.
+pub fn read_index(
+    kind: SpatialIndexKind,
+    cell_size: f32,
+    reader: &mut CacheReader,
+) -> Option<Box<dyn SpatialIndex>> {
+    Some(match kind {
+        SpatialIndexKind::Grid => {
+            Box::new(UniformGrid::read_cache(reader).filter(|grid| grid.cell_size == cell_size)?)
+        }
+        SpatialIndexKind::Quadtree => Box::new(QuadTree::read_cache(reader)?),
+        SpatialIndexKind::RTree => Box::new(RTree::read_cache(reader)?),
+    })
+}
+
+pub fn segment_box(segment: &PathSegment) -> (DVec2, DVec2) {
+    (
+        segment.start.min(segment.end),
+        segment.start.max(segment.end),
+    )
+}
+
+pub fn box_distance(pos: DVec2, min: DVec2, max: DVec2) -> f64 {
+    pos.clamp(min, max).distance(pos)
+}
+
+pub fn boxes_overlap(a_min: DVec2, a_max: DVec2, b_min: DVec2, b_max: DVec2) -> bool {
+    a_min.x <= b_max.x && b_min.x <= a_max.x && a_min.y <= b_max.y && b_min.y <= a_max.y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry64::closest_point_on_segment;
+    use std::collections::HashMap;
+
+    #[test]
+    fn nearest_matches_brute_force_on_every_backend() {
+        let mut seed = 11u64;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let mut random_segment = |spread: f64| {
+            let start = DVec2::new(next() * spread, next() * spread);
+            PathSegment {
+                start,
+                end: start + DVec2::new(next() * 8.0 - 4.0, next() * 8.0 - 4.0),
+            }
+        };
+        let segments: Vec<(Slot, PathSegment)> = (0..2000)
+            .map(|slot| (slot, random_segment(400.0)))
+            .collect();
+        // Added after the bulk load, some of them off its bounds, then half taken out again
+        let edits: Vec<(Slot, PathSegment)> = (2000..2200)
+            .map(|slot| (slot, random_segment(600.0)))
+            .collect();
+        let queries: Vec<DVec2> = (0..500)
+            .map(|_| DVec2::new(next() * 700.0 - 50.0, next() * 700.0 - 50.0))
+            .collect();
+
+        let mut live: HashMap<Slot, PathSegment> = segments.iter().copied().collect();
+        live.extend(edits.iter().step_by(2).copied());
+        let distance = |pos: DVec2, segment: &PathSegment| {
+            closest_point_on_segment(pos, segment.start, segment.end).distance(pos)
+        };
+        let segment_of = |slot: Slot| &live[&slot];
+
+        for kind in [
+            SpatialIndexKind::Grid,
+            SpatialIndexKind::Quadtree,
+            SpatialIndexKind::RTree,
+        ] {
+            let mut index = new_index(kind, 5.0);
+            index.rebuild(&segments);
+            for (slot, segment) in &edits {
+                index.insert(*slot, segment);
+            }
+            for (slot, segment) in edits.iter().skip(1).step_by(2) {
+                index.remove(*slot, segment);
+            }
+
+            for radius in [3.0, 15.0, 1000.0] {
+                for &pos in &queries {
+                    let expected = live
+                        .values()
+                        .map(|segment| distance(pos, segment))
+                        .filter(|&d| d <= radius)
+                        .min_by(f64::total_cmp);
+                    let found = index.nearest(pos, radius, &mut |slot, best| {
+                        let d = distance(pos, segment_of(slot));
+                        (d <= radius && d < best).then_some(d)
+                    });
+                    assert_eq!(
+                        found.map(|slot| distance(pos, segment_of(slot))),
+                        expected,
+                        "{kind:?} at {pos} within {radius}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -25,7 +25,10 @@ pub fn save_svg(
     drawing_info: &ResMut<DrawingInfo>,
 ) -> (PathBuf, f32, f32, f32, f32) {
     let points = &drawing_points.points;
-    let (min_x, min_y, max_x, max_y) = if let Some((min, max)) = bounding_box(points) {
+    let curves = &drawing_points.curves;
+    let mut extent = points.clone();
+    extent.extend(curves.iter().flat_map(|c| [c.p1, c.p2]));
+    let (min_x, min_y, max_x, max_y) = if let Some((min, max)) = bounding_box(&extent) {
         (min.x, min.y, max.x, max.y)
     } else {
         println!("Bounding Box Empty!");
@@ -42,7 +45,7 @@ pub fn save_svg(
         std::fs::create_dir_all("assets/svgs").unwrap();
     }
 
-    let mut file = std::fs::File::create(&path).unwrap();
+    let mut file = std::fs::File::create(path).unwrap();
     let _ = writeln!(
         file,
         "<svg xmlns='http://www.w3.org/2000/svg' width='{}' height='{}'>",
@@ -51,10 +54,29 @@ pub fn save_svg(
     );
     let _ = write!(file, "<path d='");
 
+    // The first sub-path is replaced by its fitted curves when there are any
+    let mut in_fitted_path = !curves.is_empty();
+    if let Some(first) = curves.first() {
+        let _ = write!(file, "M {} {} ", first.p0.x - min_x, max_y - first.p0.y);
+        for curve in curves {
+            let _ = write!(
+                file,
+                "C {} {} {} {} {} {} ",
+                curve.p1.x - min_x, max_y - curve.p1.y,
+                curve.p2.x - min_x, max_y - curve.p2.y,
+                curve.p3.x - min_x, max_y - curve.p3.y,
+            );
+        }
+    }
+
     let mut move_next = true;
     for point in points {
         if !point.x.is_finite() || !point.y.is_finite() {
             move_next = true;
+            in_fitted_path = false;
+            continue;
+        }
+        if in_fitted_path {
             continue;
         }
         let x = point.x - min_x;
@@ -0,0 +1,987 @@
+use bevy::math::DVec2;
+use bevy::prelude::*;
+use bevy::tasks::{AsyncComputeTaskPool, Task, TaskPool, block_on, poll_once};
+use std::collections::{HashMap, HashSet};
+
+use crate::index_cache::{CacheReader, CacheWriter};
+use crate::rtree::RTree;
+use crate::spatial_grid::{PathSegment, SegmentChange, SpatialGrid};
+use crate::spatial_index::{Slot, SpatialIndex};
+
+/// Cycles enclosing less than this are treated as flat, like the two sides of a lone line.
+const MIN_FACE_AREA: f64 = 1.0e-9;
+/// How far left of a component's leftmost vertex, relative to its coordinate, to test which
+/// face the component sits in.
+const OUTSIDE_NUDGE: f64 = 1.0e-9;
+
+pub type FaceId = usize;
+/// Everything outside all the borders. Always there, and the only face without an outer
+/// boundary.
+pub const UNBOUNDED_FACE: FaceId = 0;
+const NO_FACE: FaceId = FaceId::MAX;
+
+pub struct Vertex {
+    pub pos: DVec2,
+    /// Half-edges leaving the vertex, counter-clockwise by angle.
+    outgoing: Vec<usize>,
+}
+
+/// One side of a border edge, running from `origin` with its face on the left. Half-edges
+/// come in pairs, so the twin of `h` is always `h ^ 1`.
+pub struct HalfEdge {
+    pub origin: usize,
+    pub next: usize,
+    pub prev: usize,
+    pub face: FaceId,
+    alive: bool,
+}
+
+/// A closed walk of half-edges around a face, starting at `edge`.
+#[derive(Clone, Copy, Debug)]
+pub struct Boundary {
+    pub edge: usize,
+    pub min: DVec2,
+    pub max: DVec2,
+    /// Leftmost vertex. Nothing else of the same component lies left of it, so just left
+    /// of it is where to look for the face the component sits in.
+    anchor: DVec2,
+}
+
+impl Boundary {
+    fn outside_point(&self) -> DVec2 {
+        self.anchor - DVec2::X * self.anchor.x.abs().max(1.0) * OUTSIDE_NUDGE
+    }
+
+    fn holds_box(&self, min: DVec2, max: DVec2) -> bool {
+        self.min.x <= min.x && self.min.y <= min.y && self.max.x >= max.x && self.max.y >= max.y
+    }
+}
+
+/// An area closed off by borders: a counter-clockwise outer boundary, and a clockwise one
+/// around each separate group of borders inside it.
+pub struct Face {
+    pub outer: Option<Boundary>,
+    pub holes: Vec<Boundary>,
+    /// Area inside the outer boundary, holes not taken off.
+    pub outer_area: f64,
+}
+
+/// A bounded face appearing, changing shape or going away.
+#[derive(Clone, Copy, Debug)]
+pub enum FaceChange {
+    /// `split_from` is the face it was cut out of, if it came from one.
+    Added {
+        face: FaceId,
+        split_from: Option<FaceId>,
+    },
+    Reshaped(FaceId),
+    Removed(FaceId),
+}
+
+struct Cycle {
+    boundary: Boundary,
+    area: f64,
+    edges: Vec<usize>,
+    /// Faces the half-edges were on before, and how many of them.
+    votes: HashMap<FaceId, usize>,
+}
+
+/// A topology being built off the main thread, and the grid changes made since it started,
+/// to be applied to it once it lands.
+struct PendingBuild {
+    task: Task<Topology>,
+    changes: Vec<SegmentChange>,
+}
+
+/// Doubly-connected edge list over every stored border, base map and strokes alike. Borders
+/// are expected to meet only at shared vertices, which noding makes sure of for the imported
+/// map and welding for strokes. Face ids stay put while a face is only reshaped; a face cut
+/// in two keeps its id for the bigger half.
+#[derive(Resource)]
+pub struct Topology {
+    vertices: Vec<Vertex>,
+    vertex_at: HashMap<(u64, u64), usize>,
+    free_vertices: Vec<usize>,
+    half_edges: Vec<HalfEdge>,
+    free_pairs: Vec<usize>,
+    /// Edge between two vertices, lower index first: its half-edge leaving the lower one,
+    /// and how many segments lie along it. Duplicate segments share one edge.
+    edges: HashMap<(usize, usize), (usize, usize)>,
+    /// Keyed by grid slot. A slot only comes back into use after its old segment's removal
+    /// has come through, so slots never mix up two segments.
+    segment_edges: HashMap<Slot, (usize, usize)>,
+    faces: Vec<Option<Face>>,
+    /// Box of every outer boundary, by face id, for `locate`.
+    face_boxes: RTree,
+    building: Option<PendingBuild>,
+    /// Changes not yet picked up by `take_face_changes`.
+    face_changes: Vec<FaceChange>,
+}
+
+pub struct TopologyPlugin;
+
+impl Plugin for TopologyPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Topology::default())
+            .add_systems(Update, topology_sync_system);
+    }
+}
+
+/// Applies whatever the grid gained or lost since last frame, or holds it for the build
+/// running in the background and swaps that in once it's done.
+pub fn topology_sync_system(mut spatial_grid: ResMut<SpatialGrid>, mut topology: ResMut<Topology>) {
+    let changes = spatial_grid.take_changes();
+    if let Some(pending) = topology.building.as_mut() {
+        pending.changes.extend(changes);
+        topology.finish_build();
+    } else if !changes.is_empty() {
+        topology.apply(&changes);
+    }
+}
+
+/// An outer boundary goes in the face index as the diagonal of its box, which has that same
+/// box.
+fn box_entry(boundary: &Boundary) -> PathSegment {
+    PathSegment {
+        start: boundary.min,
+        end: boundary.max,
+    }
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Self {
+            vertices: Vec::new(),
+            vertex_at: HashMap::new(),
+            free_vertices: Vec::new(),
+            half_edges: Vec::new(),
+            free_pairs: Vec::new(),
+            edges: HashMap::new(),
+            segment_edges: HashMap::new(),
+            faces: vec![Some(Face {
+                outer: None,
+                holes: Vec::new(),
+                outer_area: f64::INFINITY,
+            })],
+            face_boxes: RTree::new(),
+            building: None,
+            face_changes: Vec::new(),
+        }
+    }
+}
+
+impl Topology {
+    /// Topology of a whole map's worth of segments at once, e.g. a bulk load, which the grid
+    /// doesn't report through its changes.
+    pub fn build(segments: &[(Slot, PathSegment)]) -> Self {
+        let mut topology = Self::default();
+        let mut touched = HashSet::new();
+        for &(slot, segment) in segments {
+            topology.add_segment(slot, segment, &mut touched);
+        }
+        if !touched.is_empty() {
+            topology.refresh(&touched, HashSet::new());
+        }
+        println!(
+            "Topology built: {} vertices {} edges {} faces",
+            topology.vertex_count(),
+            topology.edge_count(),
+            topology.face_count()
+        );
+        topology
+    }
+
+    /// Replaces everything with a topology of `segments` built on the async compute pool,
+    /// running `after_build` there on it before it's handed back. Grid changes made meanwhile
+    /// are applied on top once it's swapped in; until then the old topology stays readable.
+    pub fn build_in_background_then(
+        &mut self,
+        segments: Vec<(Slot, PathSegment)>,
+        after_build: impl FnOnce(&Topology) + Send + 'static,
+    ) {
+        let task = AsyncComputeTaskPool::get_or_init(TaskPool::default).spawn(async move {
+            let topology = Topology::build(&segments);
+            after_build(&topology);
+            topology
+        });
+        self.building = Some(PendingBuild {
+            task,
+            changes: Vec::new(),
+        });
+    }
+
+    /// Swaps in the background build if it has finished. True once it has.
+    pub fn finish_build(&mut self) -> bool {
+        let Some(pending) = self.building.as_mut() else {
+            return false;
+        };
+        let Some(built) = block_on(poll_once(&mut pending.task)) else {
+            return false;
+        };
+        let changes = std::mem::take(&mut pending.changes);
+        *self = built;
+        self.apply(&changes);
+        true
+    }
+
+    pub fn vertex(&self, vertex: usize) -> &Vertex {
+        &self.vertices[vertex]
+    }
+
+    pub fn half_edge(&self, edge: usize) -> &HalfEdge {
+        &self.half_edges[edge]
+    }
+
+    pub fn face(&self, face: FaceId) -> Option<&Face> {
+        self.faces.get(face)?.as_ref()
+    }
+
+    pub fn faces(&self) -> impl Iterator<Item = (FaceId, &Face)> {
+        self.faces
+            .iter()
+            .enumerate()
+            .filter_map(|(id, face)| Some((id, face.as_ref()?)))
+    }
+
+    pub fn face_count(&self) -> usize {
+        self.faces.iter().flatten().count()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.vertex_at.len()
+    }
+
+    /// Bounded faces added, reshaped and removed since the last call, in order. The same
+    /// face can show up more than once.
+    pub fn take_face_changes(&mut self) -> Vec<FaceChange> {
+        std::mem::take(&mut self.face_changes)
+    }
+
+    /// The other side of `edge`.
+    pub fn twin(&self, edge: usize) -> usize {
+        edge ^ 1
+    }
+
+    /// Where `edge` ends: its twin's origin.
+    pub fn destination(&self, edge: usize) -> usize {
+        self.half_edges[edge ^ 1].origin
+    }
+
+    /// Half-edges of the cycle through `start`, in walking order.
+    pub fn cycle(&self, start: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(Some(start), move |&h| {
+            Some(self.half_edges[h].next).filter(|&next| next != start)
+        })
+    }
+
+    pub fn boundary_points(&self, boundary: &Boundary) -> Vec<DVec2> {
+        self.cycle(boundary.edge)
+            .map(|h| self.vertices[self.half_edges[h].origin].pos)
+            .collect()
+    }
+
+    /// Outer ring first, if the face has one, then its holes.
+    pub fn face_rings(&self, face: FaceId) -> Vec<Vec<DVec2>> {
+        let Some(face) = self.face(face) else {
+            return Vec::new();
+        };
+        face.outer
+            .iter()
+            .chain(&face.holes)
+            .map(|boundary| self.boundary_points(boundary))
+            .collect()
+    }
+
+    /// The face `point` lies in: the smallest one whose outer boundary holds it.
+    pub fn locate(&self, point: DVec2) -> FaceId {
+        let mut candidates = Vec::new();
+        self.face_boxes.query_rect(point, point, &mut candidates);
+        candidates
+            .into_iter()
+            .filter_map(|slot| {
+                let id = slot as FaceId;
+                let face = self.face(id)?;
+                let outer = face.outer?;
+                self.boundary_contains(&outer, point)
+                    .then_some((id, face.outer_area))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(UNBOUNDED_FACE, |(id, _)| id)
+    }
+
+    fn boundary_contains(&self, boundary: &Boundary, point: DVec2) -> bool {
+        let mut inside = false;
+        for h in self.cycle(boundary.edge) {
+            let a = self.vertices[self.half_edges[h].origin].pos;
+            let b = self.vertices[self.destination(h)].pos;
+            if (a.y > point.y) != (b.y > point.y)
+                && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+            {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    /// Brings the topology in step with a batch of grid changes and redraws the faces
+    /// around the vertices they touched.
+    pub fn apply(&mut self, changes: &[SegmentChange]) {
+        let mut touched = HashSet::new();
+        let mut affected = HashSet::new();
+        for change in changes {
+            match *change {
+                SegmentChange::Added(id, segment) => {
+                    self.add_segment(id.index() as Slot, segment, &mut touched);
+                }
+                SegmentChange::Removed(id) => {
+                    let Some(key) = self.segment_edges.remove(&(id.index() as Slot)) else {
+                        continue;
+                    };
+                    let Some(entry) = self.edges.get_mut(&key) else {
+                        continue;
+                    };
+                    entry.1 -= 1;
+                    if entry.1 == 0 {
+                        let edge = entry.0;
+                        self.edges.remove(&key);
+                        affected
+                            .extend([self.half_edges[edge].face, self.half_edges[edge ^ 1].face]);
+                        self.remove_edge(edge);
+                        touched.extend([key.0, key.1]);
+                    }
+                }
+            }
+        }
+        if touched.is_empty() {
+            return;
+        }
+        let (created, retired) = self.refresh(&touched, affected);
+        println!(
+            "Topology: {} vertices {} edges {} faces, {} faces new {} gone",
+            self.vertex_count(),
+            self.edge_count(),
+            self.face_count(),
+            created.len(),
+            retired.len()
+        );
+    }
+
+    fn add_segment(&mut self, slot: Slot, segment: PathSegment, touched: &mut HashSet<usize>) {
+        if segment.start == segment.end {
+            return;
+        }
+        let (u, v) = (self.vertex_for(segment.start), self.vertex_for(segment.end));
+        let key = (u.min(v), u.max(v));
+        match self.edges.get_mut(&key) {
+            Some(entry) => entry.1 += 1,
+            None => {
+                let edge = self.add_edge(key.0, key.1);
+                self.edges.insert(key, (edge, 1));
+                touched.extend([u, v]);
+            }
+        }
+        self.segment_edges.insert(slot, key);
+    }
+
+    fn vertex_for(&mut self, pos: DVec2) -> usize {
+        // + 0.0 turns -0.0 into 0.0, so both key the same vertex
+        let key = ((pos.x + 0.0).to_bits(), (pos.y + 0.0).to_bits());
+        if let Some(&vertex) = self.vertex_at.get(&key) {
+            return vertex;
+        }
+        let vertex = match self.free_vertices.pop() {
+            Some(vertex) => {
+                self.vertices[vertex] = Vertex {
+                    pos,
+                    outgoing: Vec::new(),
+                };
+                vertex
+            }
+            None => {
+                self.vertices.push(Vertex {
+                    pos,
+                    outgoing: Vec::new(),
+                });
+                self.vertices.len() - 1
+            }
+        };
+        self.vertex_at.insert(key, vertex);
+        vertex
+    }
+
+    fn add_edge(&mut self, u: usize, v: usize) -> usize {
+        let new = |origin, twin| HalfEdge {
+            origin,
+            next: twin,
+            prev: twin,
+            face: NO_FACE,
+            alive: true,
+        };
+        let edge = match self.free_pairs.pop() {
+            Some(edge) => edge,
+            None => {
+                self.half_edges.push(new(0, 0));
+                self.half_edges.push(new(0, 0));
+                self.half_edges.len() - 2
+            }
+        };
+        self.half_edges[edge] = new(u, edge ^ 1);
+        self.half_edges[edge ^ 1] = new(v, edge);
+        self.attach(u, edge);
+        self.attach(v, edge ^ 1);
+        edge
+    }
+
+    fn remove_edge(&mut self, edge: usize) {
+        for h in [edge, edge ^ 1] {
+            self.half_edges[h].alive = false;
+            let origin = self.half_edges[h].origin;
+            self.vertices[origin].outgoing.retain(|&out| out != h);
+            self.relink(origin);
+            if self.vertices[origin].outgoing.is_empty() {
+                let pos = self.vertices[origin].pos;
+                self.vertex_at
+                    .remove(&((pos.x + 0.0).to_bits(), (pos.y + 0.0).to_bits()));
+                self.free_vertices.push(origin);
+            }
+        }
+        self.free_pairs.push(edge);
+    }
+
+    fn angle(&self, edge: usize) -> f64 {
+        let dir = self.vertices[self.destination(edge)].pos
+            - self.vertices[self.half_edges[edge].origin].pos;
+        dir.y.atan2(dir.x)
+    }
+
+    fn attach(&mut self, vertex: usize, edge: usize) {
+        let angle = self.angle(edge);
+        let at = self.vertices[vertex]
+            .outgoing
+            .partition_point(|&out| self.angle(out) < angle);
+        self.vertices[vertex].outgoing.insert(at, edge);
+        self.relink(vertex);
+    }
+
+    /// Re-links the half-edges coming into `vertex`: each one carries on along the outgoing
+    /// half-edge just clockwise of its twin, which keeps its face on the left.
+    fn relink(&mut self, vertex: usize) {
+        let outgoing = self.vertices[vertex].outgoing.clone();
+        let count = outgoing.len();
+        for (i, &out) in outgoing.iter().enumerate() {
+            let next = outgoing[(i + count - 1) % count];
+            self.half_edges[out ^ 1].next = next;
+            self.half_edges[next].prev = out ^ 1;
+        }
+    }
+
+    fn trace(&self, start: usize, visited: &mut [bool]) -> Cycle {
+        let first = self.vertices[self.half_edges[start].origin].pos;
+        let mut boundary = Boundary {
+            edge: start,
+            min: first,
+            max: first,
+            anchor: first,
+        };
+        let mut area = 0.0;
+        let mut edges = Vec::new();
+        let mut votes = HashMap::new();
+        for h in self.cycle(start) {
+            visited[h] = true;
+            edges.push(h);
+            *votes.entry(self.half_edges[h].face).or_insert(0) += 1;
+            let a = self.vertices[self.half_edges[h].origin].pos;
+            let b = self.vertices[self.destination(h)].pos;
+            area += a.perp_dot(b);
+            boundary.min = boundary.min.min(a);
+            boundary.max = boundary.max.max(a);
+            if (a.x, a.y) < (boundary.anchor.x, boundary.anchor.y) {
+                boundary.anchor = a;
+            }
+        }
+        votes.remove(&NO_FACE);
+        Cycle {
+            boundary,
+            area: area / 2.0,
+            edges,
+            votes,
+        }
+    }
+
+    fn label(&mut self, boundary: &Boundary, face: FaceId) {
+        let edges: Vec<usize> = self.cycle(boundary.edge).collect();
+        for h in edges {
+            self.half_edges[h].face = face;
+        }
+    }
+
+    /// Re-walks every cycle through `touched` and rebuilds the faces they bound. `affected`
+    /// are faces that lost half-edges outright. Returns the faces created and removed.
+    fn refresh(
+        &mut self,
+        touched: &HashSet<usize>,
+        mut affected: HashSet<FaceId>,
+    ) -> (Vec<FaceId>, Vec<FaceId>) {
+        let mut visited = vec![false; self.half_edges.len()];
+        let mut cycles = Vec::new();
+        for &vertex in touched {
+            for i in 0..self.vertices[vertex].outgoing.len() {
+                let incoming = self.vertices[vertex].outgoing[i] ^ 1;
+                if self.half_edges[incoming].alive && !visited[incoming] {
+                    cycles.push(self.trace(incoming, &mut visited));
+                }
+            }
+        }
+        affected.extend(cycles.iter().flat_map(|cycle| cycle.votes.keys().copied()));
+        affected.remove(&NO_FACE);
+
+        // Boundaries walked again above are replaced below, and those whose edge went are gone
+        let half_edges = &self.half_edges;
+        let stale =
+            |boundary: &Boundary| !half_edges[boundary.edge].alive || visited[boundary.edge];
+        for &id in &affected {
+            if let Some(Some(face)) = self.faces.get_mut(id) {
+                if let Some(outer) = face.outer.filter(stale) {
+                    face.outer = None;
+                    self.face_boxes.remove(id as Slot, &box_entry(&outer));
+                }
+                face.holes.retain(|hole| !stale(hole));
+            }
+        }
+
+        // Biggest first, so that's the piece that keeps the id when a face is cut in two
+        let (mut bounded, flat): (Vec<Cycle>, Vec<Cycle>) = cycles
+            .into_iter()
+            .partition(|cycle| cycle.area > MIN_FACE_AREA);
+        bounded.sort_by(|a, b| b.area.total_cmp(&a.area));
+        let mut created = Vec::new();
+        for cycle in bounded {
+            let reused = cycle
+                .votes
+                .iter()
+                .filter(|&(&face, _)| {
+                    face != UNBOUNDED_FACE
+                        && matches!(self.faces.get(face), Some(Some(face)) if face.outer.is_none())
+                })
+                .max_by_key(|&(&face, &count)| (count, std::cmp::Reverse(face)))
+                .map(|(&face, _)| face);
+            let id = reused.unwrap_or_else(|| {
+                let split_from = cycle
+                    .votes
+                    .iter()
+                    .filter(|&(&face, _)| face != UNBOUNDED_FACE)
+                    .max_by_key(|&(&face, &count)| (count, std::cmp::Reverse(face)))
+                    .map(|(&face, _)| face);
+                self.faces.push(None);
+                let id = self.faces.len() - 1;
+                created.push(id);
+                self.face_changes.push(FaceChange::Added {
+                    face: id,
+                    split_from,
+                });
+                id
+            });
+            for &h in &cycle.edges {
+                self.half_edges[h].face = id;
+            }
+            let holes = self.faces[id]
+                .take()
+                .map(|face| face.holes)
+                .unwrap_or_default();
+            self.faces[id] = Some(Face {
+                outer: Some(cycle.boundary),
+                holes,
+                outer_area: cycle.area,
+            });
+            self.face_boxes
+                .insert(id as Slot, &box_entry(&cycle.boundary));
+        }
+
+        let mut retired = Vec::new();
+        let mut orphans = Vec::new();
+        for &face in &affected {
+            if face != UNBOUNDED_FACE
+                && let Some(Some(old)) = self.faces.get(face)
+                && old.outer.is_none()
+            {
+                orphans.extend(
+                    self.faces[face]
+                        .take()
+                        .into_iter()
+                        .flat_map(|old| old.holes),
+                );
+                retired.push(face);
+                self.face_changes.push(FaceChange::Removed(face));
+            }
+        }
+
+        // Loose groups of borders go in whichever face holds them
+        let mut placed = HashSet::new();
+        for boundary in flat.iter().map(|cycle| cycle.boundary).chain(orphans) {
+            let face = self.locate(boundary.outside_point());
+            self.label(&boundary, face);
+            placed.insert(boundary.edge);
+            affected.insert(face);
+            if let Some(Some(face)) = self.faces.get_mut(face) {
+                face.holes.push(boundary);
+            }
+        }
+
+        // A new face can close around groups that were already loose somewhere
+        if !created.is_empty() {
+            let mut moves = Vec::new();
+            for (owner, face) in self.faces() {
+                for hole in face
+                    .holes
+                    .iter()
+                    .filter(|hole| !placed.contains(&hole.edge))
+                {
+                    let enclosed = created.iter().any(|&new| {
+                        self.face(new)
+                            .and_then(|face| face.outer)
+                            .is_some_and(|outer| outer.holds_box(hole.min, hole.max))
+                    });
+                    if enclosed {
+                        let target = self.locate(hole.outside_point());
+                        if target != owner {
+                            moves.push((owner, target, *hole));
+                        }
+                    }
+                }
+            }
+            for (owner, target, hole) in moves {
+                affected.extend([owner, target]);
+                if let Some(Some(face)) = self.faces.get_mut(owner) {
+                    face.holes.retain(|other| other.edge != hole.edge);
+                }
+                self.label(&hole, target);
+                if let Some(Some(face)) = self.faces.get_mut(target) {
+                    face.holes.push(hole);
+                }
+            }
+        }
+
+        let mut reshaped: Vec<FaceId> = affected
+            .into_iter()
+            .filter(|&face| {
+                face != UNBOUNDED_FACE && !created.contains(&face) && self.face(face).is_some()
+            })
+            .collect();
+        reshaped.sort_unstable();
+        self.face_changes
+            .extend(reshaped.into_iter().map(FaceChange::Reshaped));
+        (created, retired)
+    }
+
+    /// Everything but the face index, which is quick to rebuild, and pending face changes;
+    /// whoever reads it back gets every bounded face as added.
+    pub fn write_cache(&self, out: &mut CacheWriter) {
+        out.usize(self.vertices.len());
+        for vertex in &self.vertices {
+            out.dvec2(vertex.pos);
+            out.usize(vertex.outgoing.len());
+            vertex.outgoing.iter().for_each(|&edge| out.usize(edge));
+        }
+        out.usize(self.free_vertices.len());
+        self.free_vertices
+            .iter()
+            .for_each(|&vertex| out.usize(vertex));
+        out.usize(self.half_edges.len());
+        for edge in &self.half_edges {
+            out.usize(edge.origin);
+            out.usize(edge.next);
+            out.usize(edge.prev);
+            out.usize(edge.face);
+            out.u8(u8::from(edge.alive));
+        }
+        out.usize(self.free_pairs.len());
+        self.free_pairs.iter().for_each(|&edge| out.usize(edge));
+        out.usize(self.edges.len());
+        for (&(u, v), &(edge, count)) in &self.edges {
+            [u, v, edge, count].into_iter().for_each(|n| out.usize(n));
+        }
+        out.usize(self.segment_edges.len());
+        for (&slot, &(u, v)) in &self.segment_edges {
+            out.u32(slot);
+            out.usize(u);
+            out.usize(v);
+        }
+        let write_boundary = |out: &mut CacheWriter, boundary: &Boundary| {
+            out.usize(boundary.edge);
+            out.dvec2(boundary.min);
+            out.dvec2(boundary.max);
+            out.dvec2(boundary.anchor);
+        };
+        out.usize(self.faces.len());
+        for face in &self.faces {
+            out.u8(u8::from(face.is_some()));
+            let Some(face) = face else {
+                continue;
+            };
+            out.u8(u8::from(face.outer.is_some()));
+            if let Some(outer) = &face.outer {
+                write_boundary(out, outer);
+            }
+            out.usize(face.holes.len());
+            face.holes.iter().for_each(|hole| write_boundary(out, hole));
+            out.f64(face.outer_area);
+        }
+    }
+
+    /// Reads back what `write_cache` wrote. Every index is checked and the live half-edges
+    /// must link up both ways, so walking a cycle of a corrupt file can't run forever.
+    pub fn read_cache(reader: &mut CacheReader) -> Option<Self> {
+        let mut topology = Self::default();
+        let vertex_count = reader.len(24)?;
+        for _ in 0..vertex_count {
+            let pos = reader.dvec2()?;
+            let outgoing_count = reader.len(8)?;
+            let outgoing = (0..outgoing_count)
+                .map(|_| reader.usize())
+                .collect::<Option<_>>()?;
+            topology.vertices.push(Vertex { pos, outgoing });
+        }
+        let free_count = reader.len(8)?;
+        topology.free_vertices = (0..free_count)
+            .map(|_| reader.usize())
+            .collect::<Option<_>>()?;
+        let edge_count = reader.len(33)?;
+        for _ in 0..edge_count {
+            topology.half_edges.push(HalfEdge {
+                origin: reader.usize()?,
+                next: reader.usize()?,
+                prev: reader.usize()?,
+                face: reader.usize()?,
+                alive: reader.u8()? == 1,
+            });
+        }
+        let free_count = reader.len(8)?;
+        topology.free_pairs = (0..free_count)
+            .map(|_| reader.usize())
+            .collect::<Option<_>>()?;
+        let pair_count = reader.len(32)?;
+        for _ in 0..pair_count {
+            let key = (reader.usize()?, reader.usize()?);
+            let entry = (reader.usize()?, reader.usize()?);
+            topology.edges.insert(key, entry);
+        }
+        let segment_count = reader.len(20)?;
+        for _ in 0..segment_count {
+            let slot = reader.u32()?;
+            let key = (reader.usize()?, reader.usize()?);
+            topology.segment_edges.insert(slot, key);
+        }
+        let read_boundary = |reader: &mut CacheReader| {
+            Some(Boundary {
+                edge: reader.usize()?,
+                min: reader.dvec2()?,
+                max: reader.dvec2()?,
+                anchor: reader.dvec2()?,
+            })
+        };
+        let face_count = reader.len(1)?;
+        topology.faces.clear();
+        for _ in 0..face_count {
+            if reader.u8()? == 0 {
+                topology.faces.push(None);
+                continue;
+            }
+            let outer = if reader.u8()? == 1 {
+                Some(read_boundary(reader)?)
+            } else {
+                None
+            };
+            let hole_count = reader.len(56)?;
+            let holes = (0..hole_count)
+                .map(|_| read_boundary(reader))
+                .collect::<Option<_>>()?;
+            topology.faces.push(Some(Face {
+                outer,
+                holes,
+                outer_area: reader.f64()?,
+            }));
+        }
+        if !topology.is_consistent() {
+            return None;
+        }
+
+        let freed: HashSet<usize> = topology.free_vertices.iter().copied().collect();
+        for (vertex, data) in topology.vertices.iter().enumerate() {
+            if !freed.contains(&vertex) {
+                let pos = data.pos;
+                topology
+                    .vertex_at
+                    .insert(((pos.x + 0.0).to_bits(), (pos.y + 0.0).to_bits()), vertex);
+            }
+        }
+        let boxes: Vec<(Slot, PathSegment)> = topology
+            .faces()
+            .filter_map(|(id, face)| Some((id as Slot, box_entry(&face.outer?))))
+            .collect();
+        topology.face_boxes.rebuild(&boxes);
+        topology.face_changes = topology
+            .faces()
+            .filter(|&(id, _)| id != UNBOUNDED_FACE)
+            .map(|(face, _)| FaceChange::Added {
+                face,
+                split_from: None,
+            })
+            .collect();
+        Some(topology)
+    }
+
+    fn is_consistent(&self) -> bool {
+        let (vertices, half_edges) = (self.vertices.len(), self.half_edges.len());
+        let alive = |edge: usize| edge < half_edges && self.half_edges[edge].alive;
+        let edges_ok = self.half_edges.iter().enumerate().all(|(h, edge)| {
+            !edge.alive
+                || (edge.origin < vertices
+                    && (edge.face == NO_FACE || edge.face < self.faces.len())
+                    && alive(edge.next)
+                    && alive(edge.prev)
+                    && self.half_edges[edge.next].prev == h
+                    && alive(h ^ 1))
+        });
+        let boundary_ok = |boundary: &Boundary| alive(boundary.edge);
+        let faces_ok = matches!(self.faces.first(), Some(Some(face)) if face.outer.is_none())
+            && self.faces.iter().flatten().all(|face| {
+                face.outer.as_ref().is_none_or(boundary_ok) && face.holes.iter().all(boundary_ok)
+            });
+        edges_ok
+            && faces_ok
+            && self
+                .vertices
+                .iter()
+                .all(|v| v.outgoing.iter().all(|&edge| alive(edge)))
+            && self.free_vertices.iter().all(|&vertex| vertex < vertices)
+            && self
+                .free_pairs
+                .iter()
+                .all(|&edge| edge + 1 < half_edges && edge % 2 == 0)
+            && self
+                .edges
+                .iter()
+                .all(|(&(u, v), &(edge, _))| u < vertices && v < vertices && alive(edge))
+            && self
+                .segment_edges
+                .values()
+                .all(|key| self.edges.contains_key(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(slot: Slot, min: DVec2, size: f64) -> Vec<(Slot, PathSegment)> {
+        let corners = [
+            min,
+            min + DVec2::new(size, 0.0),
+            min + DVec2::splat(size),
+            min + DVec2::new(0.0, size),
+        ];
+        (0..4)
+            .map(|i| {
+                let segment = PathSegment {
+                    start: corners[i],
+                    end: corners[(i + 1) % 4],
+                };
+                (slot + i as Slot, segment)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cached_topology_reads_back_the_same() {
+        let mut segments = square(0, DVec2::ZERO, 4.0);
+        segments.extend(square(4, DVec2::new(1.0, 1.0), 1.0));
+        let topology = Topology::build(&segments);
+        let mut out = CacheWriter::default();
+        topology.write_cache(&mut out);
+        let read = Topology::read_cache(&mut CacheReader::new(&out.bytes)).unwrap();
+
+        assert_eq!(read.face_count(), topology.face_count());
+        assert_eq!(read.edge_count(), topology.edge_count());
+        for point in [
+            DVec2::new(0.5, 0.5),
+            DVec2::new(1.5, 1.5),
+            DVec2::new(9.0, 0.0),
+        ] {
+            assert_eq!(read.locate(point), topology.locate(point));
+        }
+        assert_ne!(
+            read.locate(DVec2::new(0.5, 0.5)),
+            read.locate(DVec2::new(1.5, 1.5))
+        );
+        assert_eq!(read.face_changes.len(), 2);
+
+        // A half-edge pointing back at itself breaks the cycles, and is refused
+        let mut broken = Topology::read_cache(&mut CacheReader::new(&out.bytes)).unwrap();
+        broken.half_edges[0].next = 0;
+        let mut out = CacheWriter::default();
+        broken.write_cache(&mut out);
+        assert!(Topology::read_cache(&mut CacheReader::new(&out.bytes)).is_none());
+    }
+
+    #[test]
+    fn chord_splits_a_face_and_removing_it_merges_them() {
+        let corners = [
+            DVec2::ZERO,
+            DVec2::new(4.0, 0.0),
+            DVec2::new(4.0, 4.0),
+            DVec2::new(0.0, 4.0),
+        ];
+        let mut grid = SpatialGrid::new(1.0);
+        let sides: Vec<_> = (0..4)
+            .map(|i| grid.insert_segment(corners[i], corners[(i + 1) % 4]))
+            .collect();
+        let mut topology = Topology::build(&[]);
+        topology.apply(&grid.take_changes());
+        // The square and the unbounded face
+        assert_eq!(topology.face_count(), 2);
+        let whole = topology.locate(DVec2::new(1.0, 2.0));
+        assert_ne!(whole, UNBOUNDED_FACE);
+        topology.take_face_changes();
+
+        // Chord from the bottom side to the top, welded into both
+        grid.split_segment(sides[0], DVec2::new(2.0, 0.0));
+        grid.split_segment(sides[2], DVec2::new(2.0, 4.0));
+        let chord = grid.insert_segment(DVec2::new(2.0, 0.0), DVec2::new(2.0, 4.0));
+        topology.apply(&grid.take_changes());
+        assert_eq!(topology.face_count(), 3);
+        let left = topology.locate(DVec2::new(1.0, 2.0));
+        let right = topology.locate(DVec2::new(3.0, 2.0));
+        assert_ne!(left, right);
+        assert!(left == whole || right == whole);
+        let cut_off = if left == whole { right } else { left };
+        assert!(topology.take_face_changes().iter().any(|change| matches!(
+            change,
+            FaceChange::Added { face, split_from: Some(from) } if *face == cut_off && *from == whole
+        )));
+        for face in [left, right] {
+            let rings = topology.face_rings(face);
+            assert_eq!(rings.len(), 1);
+            assert_eq!(rings[0].len(), 4);
+        }
+
+        grid.remove_segment(chord);
+        topology.apply(&grid.take_changes());
+        assert_eq!(topology.face_count(), 2);
+        let merged = topology.locate(DVec2::new(1.0, 2.0));
+        assert_eq!(topology.locate(DVec2::new(3.0, 2.0)), merged);
+        assert!(merged == left || merged == right);
+        let gone = if merged == left { right } else { left };
+        assert!(
+            topology
+                .take_face_changes()
+                .iter()
+                .any(|change| matches!(change, FaceChange::Removed(face) if *face == gone))
+        );
+        // The split points stay as vertices of the merged outline
+        assert_eq!(topology.face_rings(merged)[0].len(), 6);
+    }
+}
@@ -0,0 +1,537 @@
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssetUsages;
+
+use crate::math_utils::polygon_signed_area;
+
+/// Relative slack allowed between the triangles' total area and the polygon's.
+const AREA_TOLERANCE: f32 = 1.0e-3;
+
+/// Triangles as a vertex list and an index buffer, three indices per triangle, all wound
+/// counter-clockwise.
+#[derive(Clone, Debug, Default)]
+pub struct Triangulation {
+    pub vertices: Vec<Vec2>,
+    pub indices: Vec<u32>,
+}
+
+fn cleaned_ring(ring: &[Vec2], ccw: bool) -> Vec<Vec2> {
+    let mut out: Vec<Vec2> = Vec::with_capacity(ring.len());
+    for &p in ring {
+        if p.is_finite() && out.last().is_none_or(|last| *last != p) {
+            out.push(p);
+        }
+    }
+    while out.len() > 1 && out[0] == out[out.len() - 1] {
+        out.pop();
+    }
+    if (polygon_signed_area(&out) > 0.0) != ccw {
+        out.reverse();
+    }
+    out
+}
+
+fn cross(a: Vec2, b: Vec2, c: Vec2) -> f32 {
+    (b - a).perp_dot(c - a)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Start,
+    End,
+    Split,
+    Merge,
+    Regular,
+}
+
+/// Every ring as one vertex list with `next`/`prev` links, the outline counter-clockwise and
+/// holes clockwise so the inside is always to the left of an edge. `rank` is each vertex's
+/// place in the sweep, top to bottom and left to right on ties.
+struct Rings {
+    points: Vec<Vec2>,
+    next: Vec<usize>,
+    prev: Vec<usize>,
+    rank: Vec<usize>,
+}
+
+impl Rings {
+    fn new(rings: &[Vec<Vec2>]) -> Self {
+        let mut points: Vec<Vec2> = Vec::new();
+        let (mut next, mut prev) = (Vec::new(), Vec::new());
+        for ring in rings {
+            let start = points.len();
+            let n = ring.len();
+            points.extend(ring);
+            next.extend((0..n).map(|i| start + (i + 1) % n));
+            prev.extend((0..n).map(|i| start + (i + n - 1) % n));
+        }
+        let mut order: Vec<usize> = (0..points.len()).collect();
+        order.sort_by(|&a, &b| {
+            points[b]
+                .y
+                .total_cmp(&points[a].y)
+                .then(points[a].x.total_cmp(&points[b].x))
+                .then(a.cmp(&b))
+        });
+        let mut rank = vec![0; points.len()];
+        for (r, &v) in order.iter().enumerate() {
+            rank[v] = r;
+        }
+        Self {
+            points,
+            next,
+            prev,
+            rank,
+        }
+    }
+
+    fn kind(&self, v: usize) -> VertexKind {
+        let (p, n) = (self.prev[v], self.next[v]);
+        let convex = cross(self.points[p], self.points[v], self.points[n]) > 0.0;
+        match (self.rank[p] > self.rank[v], self.rank[n] > self.rank[v]) {
+            (true, true) if convex => VertexKind::Start,
+            (true, true) => VertexKind::Split,
+            (false, false) if convex => VertexKind::End,
+            (false, false) => VertexKind::Merge,
+            _ => VertexKind::Regular,
+        }
+    }
+
+    /// Where the edge starting at `edge` crosses the horizontal through `at`. Horizontal
+    /// edges answer with the point of theirs closest to `at`.
+    fn x_at(&self, edge: usize, at: Vec2) -> f32 {
+        let (a, b) = (self.points[edge], self.points[self.next[edge]]);
+        if a.y == b.y {
+            return at.x.clamp(a.x.min(b.x), a.x.max(b.x));
+        }
+        a.x + (at.y - a.y) * (b.x - a.x) / (b.y - a.y)
+    }
+}
+
+/// Sweeps top to bottom adding the diagonals that cut the rings into y-monotone pieces.
+/// The sweep status holds the edges with the inside to their right, sorted left to right,
+/// each with the helper vertex a later diagonal would connect to. None if the rings turn
+/// out to cross.
+fn monotone_diagonals(rings: &Rings) -> Option<Vec<(usize, usize)>> {
+    let n = rings.points.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_unstable_by_key(|&v| rings.rank[v]);
+    let kinds: Vec<VertexKind> = (0..n).map(|v| rings.kind(v)).collect();
+
+    let mut status: Vec<usize> = Vec::new();
+    let mut helper = vec![usize::MAX; n];
+    let mut diagonals = Vec::new();
+
+    for v in order {
+        let at = rings.points[v];
+        let left_of = |status: &[usize]| -> Option<usize> {
+            let pos = status.partition_point(|&e| rings.x_at(e, at) < at.x);
+            pos.checked_sub(1).map(|pos| status[pos])
+        };
+        let insert = |status: &mut Vec<usize>, edge: usize| {
+            let lower = rings.points[rings.next[edge]];
+            let pos = status.partition_point(|&e| {
+                let x = rings.x_at(e, at);
+                if x != at.x {
+                    x < at.x
+                } else {
+                    cross(at, lower, rings.points[rings.next[e]]) < 0.0
+                }
+            });
+            status.insert(pos, edge);
+        };
+        let remove = |status: &mut Vec<usize>, edge: usize| -> Option<()> {
+            let pos = status.iter().position(|&e| e == edge)?;
+            status.remove(pos);
+            Some(())
+        };
+        let close_merge = |helper: usize, diagonals: &mut Vec<(usize, usize)>| {
+            if kinds[helper] == VertexKind::Merge {
+                diagonals.push((v, helper));
+            }
+        };
+
+        let prev_edge = rings.prev[v];
+        match kinds[v] {
+            VertexKind::Start => {
+                insert(&mut status, v);
+                helper[v] = v;
+            }
+            VertexKind::End => {
+                remove(&mut status, prev_edge)?;
+                close_merge(helper[prev_edge], &mut diagonals);
+            }
+            VertexKind::Split => {
+                let left = left_of(&status)?;
+                diagonals.push((v, helper[left]));
+                helper[left] = v;
+                insert(&mut status, v);
+                helper[v] = v;
+            }
+            VertexKind::Merge => {
+                remove(&mut status, prev_edge)?;
+                close_merge(helper[prev_edge], &mut diagonals);
+                let left = left_of(&status)?;
+                close_merge(helper[left], &mut diagonals);
+                helper[left] = v;
+            }
+            VertexKind::Regular if rings.rank[rings.prev[v]] < rings.rank[v] => {
+                // On a left-hand chain, the inside is to the right
+                remove(&mut status, prev_edge)?;
+                close_merge(helper[prev_edge], &mut diagonals);
+                insert(&mut status, v);
+                helper[v] = v;
+            }
+            VertexKind::Regular => {
+                let left = left_of(&status)?;
+                close_merge(helper[left], &mut diagonals);
+                helper[left] = v;
+            }
+        }
+    }
+    Some(diagonals)
+}
+
+/// Walks the pieces the diagonals cut the rings into, each counter-clockwise. Where several
+/// edges leave a vertex the walk takes the first one clockwise from the edge it came in on,
+/// which keeps the piece being walked on its left.
+fn split_pieces(rings: &Rings, diagonals: &[(usize, usize)]) -> Option<Vec<Vec<usize>>> {
+    let n = rings.points.len();
+    let mut from: Vec<usize> = (0..n).collect();
+    let mut to: Vec<usize> = rings.next.clone();
+    for &(a, b) in diagonals {
+        from.extend([a, b]);
+        to.extend([b, a]);
+    }
+    let mut outgoing: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for h in 0..from.len() {
+        outgoing[from[h]].push(h);
+    }
+    let angle = |a: usize, b: usize| (rings.points[b] - rings.points[a]).to_angle();
+    let following = |h: usize| -> usize {
+        let (a, b) = (from[h], to[h]);
+        let leaving = &outgoing[b];
+        if leaving.len() == 1 {
+            return leaving[0];
+        }
+        let back = angle(b, a);
+        *leaving
+            .iter()
+            .min_by(|&&x, &&y| {
+                let turn = |h: usize| {
+                    let t = (back - angle(b, to[h])).rem_euclid(std::f32::consts::TAU);
+                    if t == 0.0 { std::f32::consts::TAU } else { t }
+                };
+                turn(x).total_cmp(&turn(y))
+            })
+            .unwrap()
+    };
+
+    let mut visited = vec![false; from.len()];
+    let mut pieces = Vec::new();
+    for start in 0..from.len() {
+        if visited[start] {
+            continue;
+        }
+        let mut piece = Vec::new();
+        let mut h = start;
+        loop {
+            if visited[h] {
+                return None;
+            }
+            visited[h] = true;
+            piece.push(from[h]);
+            h = following(h);
+            if h == start {
+                break;
+            }
+        }
+        pieces.push(piece);
+    }
+    Some(pieces)
+}
+
+/// Standard stack walk down a y-monotone piece, linear once its two chains are merged.
+fn triangulate_monotone(rings: &Rings, piece: &[usize], indices: &mut Vec<u32>) -> Option<()> {
+    let m = piece.len();
+    if m < 3 {
+        return None;
+    }
+    let rank = |k: usize| rings.rank[piece[k]];
+    let top = (0..m).min_by_key(|&k| rank(k))?;
+    let bottom = (0..m).max_by_key(|&k| rank(k))?;
+
+    // Counter-clockwise from the top runs down the left chain, then back up the right one
+    let mut on_left = vec![false; m];
+    let mut k = top;
+    while k != bottom {
+        on_left[k] = true;
+        let next = (k + 1) % m;
+        if next != bottom && rank(next) < rank(k) {
+            return None;
+        }
+        k = next;
+    }
+    while k != top {
+        let next = (k + 1) % m;
+        if rank(next) > rank(k) {
+            return None;
+        }
+        k = next;
+    }
+
+    let mut push = |a: usize, b: usize, c: usize| {
+        let (a, mut b, mut c) = (piece[a], piece[b], piece[c]);
+        if cross(rings.points[a], rings.points[b], rings.points[c]) < 0.0 {
+            std::mem::swap(&mut b, &mut c);
+        }
+        indices.extend([a as u32, b as u32, c as u32]);
+    };
+    let point = |k: usize| rings.points[piece[k]];
+
+    let mut sorted: Vec<usize> = (0..m).collect();
+    sorted.sort_unstable_by_key(|&k| rank(k));
+    let mut stack = vec![sorted[0], sorted[1]];
+    for &u in &sorted[2..m - 1] {
+        let top_of_stack = *stack.last()?;
+        if on_left[u] != on_left[top_of_stack] {
+            while stack.len() > 1 {
+                let a = stack.pop()?;
+                push(u, a, *stack.last()?);
+            }
+            stack = vec![top_of_stack, u];
+        } else {
+            let mut last = stack.pop()?;
+            while let Some(&t) = stack.last() {
+                let inside = if on_left[u] {
+                    cross(point(t), point(last), point(u)) > 0.0
+                } else {
+                    cross(point(u), point(last), point(t)) > 0.0
+                };
+                if !inside {
+                    break;
+                }
+                push(u, last, t);
+                last = stack.pop()?;
+            }
+            stack.push(last);
+            stack.push(u);
+        }
+    }
+    let u = sorted[m - 1];
+    while stack.len() > 1 {
+        let a = stack.pop()?;
+        push(u, a, *stack.last()?);
+    }
+    Some(())
+}
+
+/// Sweep-line split into y-monotone pieces, each then triangulated in linear time, so
+/// O(n log n) for the sort plus the sweep status' insertions. None when the rings cross or
+/// touch in a way that leaves no valid triangulation, rather than wrong triangles; the
+/// triangle areas must add up to the polygon's.
+pub fn triangulate_polygon(outline: &[Vec2], holes: &[Vec<Vec2>]) -> Option<Triangulation> {
+    let outline = cleaned_ring(outline, true);
+    if outline.len() < 3 {
+        return None;
+    }
+    let mut expected = polygon_signed_area(&outline);
+    let mut rings = vec![outline];
+    for hole in holes {
+        let hole = cleaned_ring(hole, false);
+        if hole.len() >= 3 {
+            expected += polygon_signed_area(&hole);
+            rings.push(hole);
+        }
+    }
+    let rings = Rings::new(&rings);
+
+    let diagonals = monotone_diagonals(&rings)?;
+    let mut indices = Vec::with_capacity(rings.points.len() * 3);
+    for piece in split_pieces(&rings, &diagonals)? {
+        triangulate_monotone(&rings, &piece, &mut indices)?;
+    }
+    let triangulation = Triangulation {
+        vertices: rings.points,
+        indices,
+    };
+    let tolerance = AREA_TOLERANCE * expected.abs().max(1.0);
+    ((triangulation.area() - expected).abs() <= tolerance).then_some(triangulation)
+}
+
+impl Triangulation {
+    pub fn append(&mut self, other: Triangulation) {
+        let base = self.vertices.len() as u32;
+        self.vertices.extend(other.vertices);
+        self.indices
+            .extend(other.indices.into_iter().map(|i| i + base));
+    }
+
+    pub fn triangle(&self, t: usize) -> (Vec2, Vec2, Vec2) {
+        (
+            self.vertices[self.indices[t * 3] as usize],
+            self.vertices[self.indices[t * 3 + 1] as usize],
+            self.vertices[self.indices[t * 3 + 2] as usize],
+        )
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    pub fn area(&self) -> f32 {
+        (0..self.triangle_count())
+            .map(|t| {
+                let (a, b, c) = self.triangle(t);
+                cross(a, b, c).abs() * 0.5
+            })
+            .sum()
+    }
+
+    /// Area-weighted sampler over the triangles, for uniform points inside the shape.
+    pub fn sampler(self) -> TriangleSampler {
+        TriangleSampler::new(self)
+    }
+
+    pub fn to_mesh(&self) -> Mesh {
+        let positions: Vec<[f32; 3]> = self.vertices.iter().map(|v| [v.x, v.y, 0.0]).collect();
+        let normals = vec![[0.0, 0.0, 1.0]; positions.len()];
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+        .with_inserted_indices(Indices::U32(self.indices.clone()))
+    }
+}
+
+/// Area-weighted picking of uniformly distributed points inside a triangulation.
+pub struct TriangleSampler {
+    triangulation: Triangulation,
+    cumulative: Vec<f32>,
+}
+
+impl TriangleSampler {
+    pub fn new(triangulation: Triangulation) -> Self {
+        let mut total = 0.0;
+        let cumulative = (0..triangulation.triangle_count())
+            .map(|t| {
+                let (a, b, c) = triangulation.triangle(t);
+                total += cross(a, b, c).abs() * 0.5;
+                total
+            })
+            .collect();
+        Self {
+            triangulation,
+            cumulative,
+        }
+    }
+
+    /// Maps three uniform numbers in [0, 1) to a uniform point inside the shape.
+    pub fn sample(&self, pick: f32, u: f32, v: f32) -> Option<Vec2> {
+        let total = *self.cumulative.last()?;
+        let target = pick.clamp(0.0, 1.0) * total;
+        let t = self
+            .cumulative
+            .partition_point(|&c| c < target)
+            .min(self.cumulative.len() - 1);
+        let (a, b, c) = self.triangulation.triangle(t);
+        let (mut u, mut v) = (u.clamp(0.0, 1.0), v.clamp(0.0, 1.0));
+        if u + v > 1.0 {
+            u = 1.0 - u;
+            v = 1.0 - v;
+        }
+        Some(a + (b - a) * u + (c - a) * v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(points: &[(f32, f32)]) -> Vec<Vec2> {
+        points.iter().map(|&(x, y)| Vec2::new(x, y)).collect()
+    }
+
+    #[test]
+    fn triangles_cover_the_polygon_area() {
+        // A comb: every tooth top is an end vertex and every gap a merge vertex
+        let mut comb = vec![(0.0, 0.0), (20.0, 0.0)];
+        for tooth in (0..5).rev() {
+            let x = tooth as f32 * 4.0;
+            comb.extend([(x + 3.0, 10.0), (x + 2.0, 3.0), (x + 1.0, 3.0), (x, 10.0)]);
+        }
+        comb.dedup();
+        let comb = ring(&comb);
+        let triangles = triangulate_polygon(&comb, &[]).unwrap();
+        assert!((triangles.area() - polygon_signed_area(&comb).abs()).abs() < 1.0e-3);
+        assert_eq!(triangles.triangle_count(), comb.len() - 2);
+
+        // Holes turn up as split and merge vertices, and may be given either way round
+        let outline = ring(&[(0.0, 0.0), (30.0, 0.0), (30.0, 20.0), (0.0, 20.0)]);
+        let holes = vec![
+            ring(&[(2.0, 2.0), (8.0, 2.0), (5.0, 9.0)]),
+            ring(&[
+                (12.0, 12.0),
+                (12.0, 18.0),
+                (20.0, 18.0),
+                (16.0, 15.0),
+                (20.0, 12.0),
+            ]),
+        ];
+        let holes_area: f32 = holes.iter().map(|h| polygon_signed_area(h).abs()).sum();
+        let triangles = triangulate_polygon(&outline, &holes).unwrap();
+        assert!((triangles.area() - (600.0 - holes_area)).abs() < 1.0e-3);
+        for t in 0..triangles.triangle_count() {
+            let (a, b, c) = triangles.triangle(t);
+            assert!(cross(a, b, c) >= 0.0);
+        }
+    }
+
+    #[test]
+    fn samples_fall_inside_the_polygon() {
+        let outline = ring(&[(0.0, 0.0), (30.0, 0.0), (30.0, 20.0), (0.0, 20.0)]);
+        let hole = ring(&[(5.0, 5.0), (25.0, 5.0), (25.0, 15.0), (5.0, 15.0)]);
+        let sampler = triangulate_polygon(&outline, std::slice::from_ref(&hole))
+            .unwrap()
+            .sampler();
+        let steps = 12;
+        let mut in_bottom_strip = 0;
+        let mut total = 0;
+        for i in 0..steps {
+            for j in 0..steps {
+                for k in 0..steps {
+                    let f = |n: usize| (n as f32 + 0.5) / steps as f32;
+                    let p = sampler.sample(f(i), f(j), f(k)).unwrap();
+                    assert!(
+                        p.x >= -1e-4 && p.x <= 30.0 + 1e-4 && p.y >= -1e-4 && p.y <= 20.0 + 1e-4
+                    );
+                    assert!(
+                        !(p.x > 5.0 && p.x < 25.0 && p.y > 5.0 && p.y < 15.0),
+                        "{p} in the hole"
+                    );
+                    total += 1;
+                    if p.y < 5.0 {
+                        in_bottom_strip += 1;
+                    }
+                }
+            }
+        }
+        // The strip under the hole is 150 of the frame's 400, so it gets about that share
+        let share = in_bottom_strip as f32 / total as f32;
+        assert!((share - 150.0 / 400.0).abs() < 0.1, "{share}");
+        assert!(
+            Triangulation::default()
+                .sampler()
+                .sample(0.5, 0.5, 0.5)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn crossing_outline_fails() {
+        let bowtie = ring(&[(0.0, 0.0), (4.0, 4.0), (4.0, 0.0), (0.0, 4.0)]);
+        assert!(triangulate_polygon(&bowtie, &[]).is_none());
+    }
+}
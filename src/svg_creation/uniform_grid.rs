@@ -0,0 +1,618 @@
+use bevy::math::DVec2;
+use bevy::prelude::*;
+use bevy::tasks::{ComputeTaskPool, ParallelSlice, TaskPool};
+use std::collections::HashMap;
+
+use crate::geometry64::{to_render, to_world};
+use crate::index_cache::{CacheReader, CacheWriter};
+use crate::spatial_grid::PathSegment;
+use crate::spatial_index::{Slot, SpatialIndex, SpatialIndexKind};
+
+/// Bounds grow by whole chunks of this many cells, so a run of strokes just past the edge
+/// doesn't reallocate the grid every time.
+const GROW_CHUNK_CELLS: usize = 64;
+/// Stands in for a packed entry whose segment was removed since the last pack.
+const REMOVED: Slot = Slot::MAX;
+/// Edits since the last pack, as a share of the packed entries, before packing again.
+const EDITS_BEFORE_PACK_FRACTION: usize = 8;
+/// Small maps repack after this many edits at the latest.
+const EDITS_BEFORE_PACK_MIN: usize = 4096;
+/// Fewest segments worth handing to a thread of their own when binning.
+const MIN_SEGMENTS_PER_TASK: usize = 4096;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Rect {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+/// Uniform grid of square cells, each listing the segments that pass through it. The cells
+/// are f32, which is plenty for bucketing.
+///
+/// Cell lists are packed CSR style: cell `c` holds `packed[offsets[c]..offsets[c + 1]]`, so a
+/// whole map costs two allocations however many cells it has. Edits don't touch the packed
+/// arrays beyond marking removals; added entries wait in `overflow` until enough edits pile
+/// up to pack everything again.
+pub struct UniformGrid {
+    pub bounds: Rect,
+    pub cell_size: f32,
+    pub cols: usize,
+    pub rows: usize,
+    offsets: Vec<u32>,
+    packed: Vec<Slot>,
+    overflow: HashMap<u32, Vec<Slot>>,
+    edits: usize,
+}
+
+impl UniformGrid {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            bounds: Rect {
+                min: Vec2::ZERO,
+                max: Vec2::ZERO,
+            },
+            cell_size,
+            cols: 0,
+            rows: 0,
+            offsets: vec![0],
+            packed: Vec::new(),
+            overflow: HashMap::new(),
+            edits: 0,
+        }
+    }
+
+    /// Sizes the grid to its bounds, empty.
+    pub fn recalc_grid(&mut self) {
+        let width = self.bounds.max.x - self.bounds.min.x;
+        let height = self.bounds.max.y - self.bounds.min.y;
+        self.cols = (width / self.cell_size).ceil() as usize;
+        self.rows = (height / self.cell_size).ceil() as usize;
+        self.offsets = vec![0; self.cols * self.rows + 1];
+        self.packed.clear();
+        self.overflow.clear();
+        self.edits = 0;
+    }
+
+    /// Packed and overflow slots of cell `idx`. Packed ones may be REMOVED.
+    fn cell_slices(&self, idx: usize) -> (&[Slot], &[Slot]) {
+        let packed = &self.packed[self.offsets[idx] as usize..self.offsets[idx + 1] as usize];
+        // Straight after a pack there's no overflow, so skip hashing the cell
+        let overflow = if self.overflow.is_empty() {
+            &[]
+        } else {
+            self.overflow
+                .get(&(idx as u32))
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+        };
+        (packed, overflow)
+    }
+
+    /// Slots filed under cell `idx`.
+    fn cell(&self, idx: usize) -> impl Iterator<Item = Slot> + '_ {
+        let (packed, overflow) = self.cell_slices(idx);
+        packed
+            .iter()
+            .copied()
+            .filter(|&slot| slot != REMOVED)
+            .chain(overflow.iter().copied())
+    }
+
+    /// Replaces the contents with the (cell, slot) entries in `buckets` on a `cols` x `rows`
+    /// grid, with a counting sort by cell.
+    fn pack(&mut self, cols: usize, rows: usize, buckets: &[Vec<(u32, Slot)>]) {
+        let mut offsets = vec![0u32; cols * rows + 1];
+        for &(cell, _) in buckets.iter().flatten() {
+            offsets[cell as usize + 1] += 1;
+        }
+        for i in 1..offsets.len() {
+            offsets[i] += offsets[i - 1];
+        }
+        let mut next = offsets.clone();
+        let mut packed = vec![REMOVED; offsets[offsets.len() - 1] as usize];
+        for &(cell, slot) in buckets.iter().flatten() {
+            packed[next[cell as usize] as usize] = slot;
+            next[cell as usize] += 1;
+        }
+        self.cols = cols;
+        self.rows = rows;
+        self.offsets = offsets;
+        self.packed = packed;
+        self.overflow = HashMap::new();
+        self.edits = 0;
+    }
+
+    /// Every (cell, slot) entry, with cells renumbered by `remap`.
+    fn entries(&self, remap: impl Fn(usize) -> usize) -> Vec<(u32, Slot)> {
+        let mut entries = Vec::with_capacity(self.packed.len() + self.edits);
+        for idx in 0..self.cols * self.rows {
+            let cell = remap(idx) as u32;
+            entries.extend(self.cell(idx).map(|slot| (cell, slot)));
+        }
+        entries
+    }
+
+    fn repack(&mut self) {
+        let entries = self.entries(|idx| idx);
+        self.pack(self.cols, self.rows, &[entries]);
+    }
+
+    fn note_edits(&mut self, count: usize) {
+        self.edits += count;
+        if self.edits > self.packed.len() / EDITS_BEFORE_PACK_FRACTION + EDITS_BEFORE_PACK_MIN {
+            self.repack();
+        }
+    }
+
+    fn cell_index(&self, col: usize, row: usize) -> usize {
+        row * self.cols + col
+    }
+
+    fn point_to_cell(&self, point: Vec2) -> Option<(usize, usize)> {
+        if point.x < self.bounds.min.x
+            || point.x > self.bounds.max.x
+            || point.y < self.bounds.min.y
+            || point.y > self.bounds.max.y
+        {
+            return None;
+        }
+        if self.cols == 0 || self.rows == 0 {
+            return None;
+        }
+        // Points on the max edge belong to the last cell rather than falling off the grid
+        let col =
+            (((point.x - self.bounds.min.x) / self.cell_size).floor() as usize).min(self.cols - 1);
+        let row =
+            (((point.y - self.bounds.min.y) / self.cell_size).floor() as usize).min(self.rows - 1);
+        Some((col, row))
+    }
+
+    /// Every cell the segment passes through, found by walking the grid lines it crosses
+    /// (Amanatides-Woo). Where it goes exactly through a cell corner both side cells are
+    /// included too, so the result is a supercover and touching counts as crossing.
+    fn segment_cells(&self, segment: &PathSegment) -> Vec<usize> {
+        let (start, end) = (to_render(segment.start), to_render(segment.end));
+        let (Some((mut col, mut row)), Some((end_col, end_row))) =
+            (self.point_to_cell(start), self.point_to_cell(end))
+        else {
+            return Vec::new();
+        };
+        let mut out = vec![self.cell_index(col, row)];
+
+        let d = end - start;
+        let step_col: isize = if d.x >= 0.0 { 1 } else { -1 };
+        let step_row: isize = if d.y >= 0.0 { 1 } else { -1 };
+        let boundary = |cell: usize, step: isize, origin: f32| {
+            origin + (cell as f32 + if step > 0 { 1.0 } else { 0.0 }) * self.cell_size
+        };
+        let (mut t_max_x, t_delta_x) = if d.x != 0.0 {
+            (
+                (boundary(col, step_col, self.bounds.min.x) - start.x) / d.x,
+                self.cell_size / d.x.abs(),
+            )
+        } else {
+            (f32::INFINITY, f32::INFINITY)
+        };
+        let (mut t_max_y, t_delta_y) = if d.y != 0.0 {
+            (
+                (boundary(row, step_row, self.bounds.min.y) - start.y) / d.y,
+                self.cell_size / d.y.abs(),
+            )
+        } else {
+            (f32::INFINITY, f32::INFINITY)
+        };
+
+        // Each step moves one cell towards the end cell, so this always terminates
+        let corner_epsilon = 1.0e-6 * t_delta_x.min(t_delta_y).min(1.0);
+        while (col, row) != (end_col, end_row) {
+            let move_col = col != end_col;
+            let move_row = row != end_row;
+            let corner = move_col && move_row && (t_max_x - t_max_y).abs() <= corner_epsilon;
+            let next_col = col.wrapping_add_signed(step_col);
+            let next_row = row.wrapping_add_signed(step_row);
+            if corner {
+                out.push(self.cell_index(next_col, row));
+                out.push(self.cell_index(col, next_row));
+                col = next_col;
+                row = next_row;
+                t_max_x += t_delta_x;
+                t_max_y += t_delta_y;
+            } else if move_col && (!move_row || t_max_x < t_max_y) {
+                col = next_col;
+                t_max_x += t_delta_x;
+            } else {
+                row = next_row;
+                t_max_y += t_delta_y;
+            }
+            out.push(self.cell_index(col, row));
+        }
+        out
+    }
+
+    fn insert_into_cells(&mut self, slot: Slot, segment: &PathSegment) {
+        if segment.start == segment.end {
+            return;
+        }
+        let cells = self.segment_cells(segment);
+        for &idx in &cells {
+            self.overflow.entry(idx as u32).or_default().push(slot);
+        }
+        self.note_edits(cells.len());
+    }
+
+    /// Extends the bounds by whole chunks until they cover `min..max`. Existing cells keep
+    /// their contents and are only shifted in one pack, nothing is re-inserted.
+    fn grow_to_fit(&mut self, min: Vec2, max: Vec2) {
+        let chunk = self.cell_size * GROW_CHUNK_CELLS as f32;
+        if self.cols == 0 || self.rows == 0 {
+            self.bounds.min = (min / chunk).floor() * chunk;
+            self.bounds.max = ((max / chunk).floor() + Vec2::ONE) * chunk;
+            self.recalc_grid();
+            return;
+        }
+        let chunks_below = |need: f32, have: f32| {
+            if need < have {
+                ((have - need) / chunk).ceil() as usize
+            } else {
+                0
+            }
+        };
+        let chunks_above = |need: f32, have: f32| {
+            if need >= have {
+                ((need - have) / chunk).floor() as usize + 1
+            } else {
+                0
+            }
+        };
+        let left = chunks_below(min.x, self.bounds.min.x) * GROW_CHUNK_CELLS;
+        let bottom = chunks_below(min.y, self.bounds.min.y) * GROW_CHUNK_CELLS;
+        let right = chunks_above(max.x, self.bounds.max.x) * GROW_CHUNK_CELLS;
+        let top = chunks_above(max.y, self.bounds.max.y) * GROW_CHUNK_CELLS;
+        if left + bottom + right + top == 0 {
+            return;
+        }
+
+        let cols = self.cols + left + right;
+        let rows = self.rows + bottom + top;
+        let old_cols = self.cols;
+        let entries = self.entries(|idx| (idx / old_cols + bottom) * cols + idx % old_cols + left);
+        self.pack(cols, rows, &[entries]);
+        self.bounds.min -= Vec2::new(left as f32, bottom as f32) * self.cell_size;
+        self.bounds.max += Vec2::new(right as f32, top as f32) * self.cell_size;
+        println!("SpatialGrid grown to cols {} rows {}", cols, rows);
+    }
+
+    pub fn read_cache(reader: &mut CacheReader) -> Option<Self> {
+        let bounds = Rect {
+            min: reader.vec2()?,
+            max: reader.vec2()?,
+        };
+        let cell_size = reader.f32()?;
+        let cols = reader.usize()?;
+        let rows = reader.usize()?;
+        let offset_count = reader.len(4)?;
+        let offsets: Vec<u32> = (0..offset_count)
+            .map(|_| reader.u32())
+            .collect::<Option<_>>()?;
+        let packed_count = reader.len(4)?;
+        let packed: Vec<Slot> = (0..packed_count)
+            .map(|_| reader.u32())
+            .collect::<Option<_>>()?;
+
+        // Cells are sliced straight out of `packed` by these offsets, so they have to add up
+        let fits = cell_size > 0.0
+            && cols.checked_mul(rows)?.checked_add(1)? == offsets.len()
+            && offsets[0] == 0
+            && offsets.windows(2).all(|w| w[0] <= w[1])
+            && offsets[offsets.len() - 1] as usize == packed.len();
+        fits.then_some(Self {
+            bounds,
+            cell_size,
+            cols,
+            rows,
+            offsets,
+            packed,
+            overflow: HashMap::new(),
+            edits: 0,
+        })
+    }
+
+    /// Column and row of `point` counted from the grid origin, which may be off the grid.
+    fn cell_coords(&self, point: DVec2) -> (i64, i64) {
+        let rel = (point - to_world(self.bounds.min)) / self.cell_size as f64;
+        (rel.x.floor() as i64, rel.y.floor() as i64)
+    }
+}
+
+impl SpatialIndex for UniformGrid {
+    fn kind(&self) -> SpatialIndexKind {
+        SpatialIndexKind::Grid
+    }
+
+    fn rebuild(&mut self, segments: &[(Slot, PathSegment)]) {
+        let mut min = Vec2::splat(f32::INFINITY);
+        let mut max = Vec2::splat(f32::NEG_INFINITY);
+        for (_, seg) in segments {
+            if seg.start == seg.end {
+                continue;
+            }
+            min = min.min(to_render(seg.start.min(seg.end)));
+            max = max.max(to_render(seg.start.max(seg.end)));
+        }
+
+        if min.x > max.x || min.y > max.y {
+            println!("SpatialGrid: bounds calculation failed (min > max), using fallback bounds.");
+            self.bounds.min = Vec2::ZERO;
+            self.bounds.max = Vec2::ZERO;
+        } else {
+            self.bounds.min = min;
+            self.bounds.max = max;
+        }
+        let degenerate = segments.iter().filter(|(_, s)| s.start == s.end).count();
+        println!("Degenerate segments: {}", degenerate);
+
+        self.recalc_grid();
+        println!(
+            "Grid recalculated: min {:?} max {:?} cols {} rows {}",
+            self.bounds.min, self.bounds.max, self.cols, self.rows
+        );
+        // Each thread bins its own run of segments, and the buckets are merged by the pack
+        let pool = ComputeTaskPool::get_or_init(TaskPool::default);
+        let chunk_len = segments
+            .len()
+            .div_ceil(pool.thread_num().max(1))
+            .max(MIN_SEGMENTS_PER_TASK);
+        let grid = &*self;
+        let buckets = segments.par_chunk_map(pool, chunk_len, |_, chunk| {
+            let mut bucket = Vec::with_capacity(chunk.len() * 2);
+            for (slot, segment) in chunk {
+                if segment.start != segment.end {
+                    bucket.extend(
+                        grid.segment_cells(segment)
+                            .into_iter()
+                            .map(|idx| (idx as u32, *slot)),
+                    );
+                }
+            }
+            bucket
+        });
+        self.pack(self.cols, self.rows, &buckets);
+    }
+
+    /// Files the segment under the cells it crosses, growing the grid first if it reaches
+    /// outside.
+    fn insert(&mut self, id: Slot, segment: &PathSegment) {
+        if segment.start == segment.end {
+            return;
+        }
+        self.grow_to_fit(
+            to_render(segment.start.min(segment.end)),
+            to_render(segment.start.max(segment.end)),
+        );
+        self.insert_into_cells(id, segment);
+    }
+
+    fn remove(&mut self, id: Slot, segment: &PathSegment) {
+        let cells = self.segment_cells(segment);
+        for &idx in &cells {
+            let range = self.offsets[idx] as usize..self.offsets[idx + 1] as usize;
+            if let Some(entry) = self.packed[range].iter_mut().find(|slot| **slot == id) {
+                *entry = REMOVED;
+            } else if let Some(list) = self.overflow.get_mut(&(idx as u32)) {
+                list.retain(|&slot| slot != id);
+                if list.is_empty() {
+                    self.overflow.remove(&(idx as u32));
+                }
+            }
+        }
+        self.note_edits(cells.len());
+    }
+
+    fn query_rect(&self, min: DVec2, max: DVec2, out: &mut Vec<Slot>) {
+        if self.cols == 0 || self.rows == 0 {
+            return;
+        }
+        let (c0, r0) = self.cell_coords(min);
+        let (c1, r1) = self.cell_coords(max);
+        if c1 < 0 || r1 < 0 || c0 >= self.cols as i64 || r0 >= self.rows as i64 {
+            return;
+        }
+        let clamp_col = |c: i64| c.clamp(0, self.cols as i64 - 1) as usize;
+        let clamp_row = |r: i64| r.clamp(0, self.rows as i64 - 1) as usize;
+        // Copy whole cell slices and only weed out removals if there can be any
+        let start = out.len();
+        for row in clamp_row(r0)..=clamp_row(r1) {
+            for col in clamp_col(c0)..=clamp_col(c1) {
+                let (packed, overflow) = self.cell_slices(self.cell_index(col, row));
+                out.extend_from_slice(packed);
+                out.extend_from_slice(overflow);
+            }
+        }
+        if self.edits > 0 {
+            let mut kept = start;
+            for i in start..out.len() {
+                if out[i] != REMOVED {
+                    out[kept] = out[i];
+                    kept += 1;
+                }
+            }
+            out.truncate(kept);
+        }
+    }
+
+    /// Searches rings of cells outwards from the one under `pos`, stopping once the next
+    /// ring can't hold anything closer. Exact for any radius and cell size, and `pos` may be
+    /// off the grid.
+    fn nearest(
+        &self,
+        pos: DVec2,
+        radius: f64,
+        test: &mut dyn FnMut(Slot, f64) -> Option<f64>,
+    ) -> Option<Slot> {
+        if self.cols == 0 || self.rows == 0 {
+            return None;
+        }
+        let cell = self.cell_size as f64;
+        let (cx, cy) = self.cell_coords(pos);
+        let (cols, rows) = (self.cols as i64, self.rows as i64);
+
+        // First ring that touches the grid at all, and the last one that still does
+        let gap = |c: i64, n: i64| {
+            if c < 0 {
+                -c
+            } else if c >= n {
+                c - n + 1
+            } else {
+                0
+            }
+        };
+        let first_ring = gap(cx, cols).max(gap(cy, rows));
+        let last_ring = cx.max(cols - 1 - cx).max(cy).max(rows - 1 - cy);
+
+        let mut reach = radius;
+        let mut best = None;
+        for ring in first_ring..=last_ring {
+            // Everything in this ring is at least (ring - 1) cells away
+            if ring > 0 && (ring - 1) as f64 * cell > reach {
+                break;
+            }
+            let (x0, x1) = ((cx - ring).max(0), (cx + ring).min(cols - 1));
+            let (y0, y1) = ((cy - ring).max(0), (cy + ring).min(rows - 1));
+            for y in y0..=y1 {
+                let on_edge_row = y == cy - ring || y == cy + ring;
+                let mut x = x0;
+                while x <= x1 {
+                    if on_edge_row || x == cx - ring || x == cx + ring {
+                        for seg_id in self.cell(self.cell_index(x as usize, y as usize)) {
+                            if let Some(dist) = test(seg_id, reach) {
+                                reach = dist;
+                                best = Some(seg_id);
+                            }
+                        }
+                    }
+                    // Inside rows only have the two side cells on the ring
+                    x = if on_edge_row || x >= cx + ring {
+                        x + 1
+                    } else {
+                        (cx + ring).max(x + 1)
+                    };
+                }
+            }
+        }
+        best
+    }
+
+    fn debug_rects(&self, min: Vec2, max: Vec2) -> Vec<(Vec2, Vec2)> {
+        let mut rects = Vec::new();
+        for col in 0..self.cols {
+            let x = self.bounds.min.x + col as f32 * self.cell_size;
+            for row in 0..self.rows {
+                let rect_min = Vec2::new(x, self.bounds.min.y + row as f32 * self.cell_size);
+                let rect_max = rect_min + Vec2::splat(self.cell_size);
+                if rect_max.x < min.x
+                    || rect_min.x > max.x
+                    || rect_max.y < min.y
+                    || rect_min.y > max.y
+                {
+                    continue;
+                }
+                rects.push((rect_min, rect_max));
+            }
+        }
+        rects
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "grid min {:?} max {:?} cols {} rows {} packed {} edits {} bytes {}",
+            self.bounds.min,
+            self.bounds.max,
+            self.cols,
+            self.rows,
+            self.packed.len(),
+            self.edits,
+            self.memory_bytes()
+        )
+    }
+
+    fn memory_bytes(&self) -> usize {
+        let overflow: usize = self
+            .overflow
+            .values()
+            .map(|list| list.capacity() * size_of::<Slot>())
+            .sum();
+        self.offsets.capacity() * size_of::<u32>()
+            + self.packed.capacity() * size_of::<Slot>()
+            + self.overflow.capacity() * (size_of::<u32>() + size_of::<Vec<Slot>>())
+            + overflow
+    }
+
+    fn compact(&mut self) {
+        self.repack();
+    }
+
+    /// Overflow and removals are folded in, so the copy read back starts freshly packed.
+    fn write_cache(&self, out: &mut CacheWriter) {
+        out.vec2(self.bounds.min);
+        out.vec2(self.bounds.max);
+        out.f32(self.cell_size);
+        out.usize(self.cols);
+        out.usize(self.rows);
+        let mut offsets = vec![0u32];
+        let mut packed = Vec::with_capacity(self.packed.len());
+        for idx in 0..self.cols * self.rows {
+            packed.extend(self.cell(idx));
+            offsets.push(packed.len() as u32);
+        }
+        out.usize(offsets.len());
+        offsets.into_iter().for_each(|offset| out.u32(offset));
+        out.usize(packed.len());
+        packed.into_iter().for_each(|slot| out.u32(slot));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(grid: &UniformGrid, start: Vec2, end: Vec2) -> Vec<(usize, usize)> {
+        let segment = PathSegment {
+            start: to_world(start),
+            end: to_world(end),
+        };
+        let mut cells: Vec<(usize, usize)> = grid
+            .segment_cells(&segment)
+            .into_iter()
+            .map(|idx| (idx % grid.cols, idx / grid.cols))
+            .collect();
+        cells.sort_unstable();
+        cells.dedup();
+        cells
+    }
+
+    #[test]
+    fn corner_crossings_cover_both_side_cells() {
+        let mut grid = UniformGrid::new(10.0);
+        grid.bounds = Rect {
+            min: Vec2::ZERO,
+            max: Vec2::splat(30.0),
+        };
+        grid.recalc_grid();
+
+        // Through the corners at (10, 10) and (20, 20)
+        assert_eq!(
+            cells(&grid, Vec2::splat(5.0), Vec2::splat(25.0)),
+            [(0, 0), (0, 1), (1, 0), (1, 1), (1, 2), (2, 1), (2, 2)]
+        );
+        // Down through the corner at (10, 20)
+        assert_eq!(
+            cells(&grid, Vec2::new(5.0, 25.0), Vec2::new(15.0, 15.0)),
+            [(0, 1), (0, 2), (1, 1), (1, 2)]
+        );
+        // Passing just above a corner only enters the cell it really goes through
+        assert_eq!(
+            cells(&grid, Vec2::new(5.0, 5.5), Vec2::new(15.0, 15.5)),
+            [(0, 0), (0, 1), (1, 1)]
+        );
+    }
+}